@@ -0,0 +1,9 @@
+-- Add up migration script here
+-- access tokens are stateless, the ids of the ones revoked before their expiry are kept here
+CREATE TABLE revoked_tokens (
+  jti uuid PRIMARY KEY,
+  expires_at timestamptz NOT NULL,
+  revoked_at timestamptz NOT NULL DEFAULT current_timestamp
+);
+
+CREATE INDEX revoked_tokens_expires_at_idx ON revoked_tokens (expires_at);
@@ -0,0 +1,13 @@
+-- Add up migration script here
+-- single-use tokens sent to the users, e.g. by email
+CREATE TABLE user_tokens (
+  id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+  user_id uuid NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  purpose varchar(32) NOT NULL,
+  token_hash varchar(64) UNIQUE NOT NULL,
+  expires_at timestamptz NOT NULL,
+  created_at timestamptz NOT NULL DEFAULT current_timestamp,
+  used_at timestamptz
+);
+
+CREATE INDEX user_tokens_user_id_idx ON user_tokens (user_id, purpose);
@@ -0,0 +1,3 @@
+-- Add up migration script here
+-- access tokens issued before this time are rejected, e.g. after a password reset
+ALTER TABLE users ADD COLUMN tokens_valid_after timestamptz;
@@ -0,0 +1,44 @@
+-- Add up migration script here
+CREATE TABLE roles (
+  id serial PRIMARY KEY,
+  name varchar(64) UNIQUE NOT NULL,
+  description varchar(255) NOT NULL DEFAULT '',
+  created_at timestamptz NOT NULL DEFAULT current_timestamp
+);
+
+CREATE TABLE permissions (
+  id serial PRIMARY KEY,
+  -- <resource>:<action>, e.g. users:read
+  name varchar(64) UNIQUE NOT NULL
+);
+
+CREATE TABLE role_permissions (
+  role_id integer NOT NULL REFERENCES roles (id) ON DELETE CASCADE,
+  permission_id integer NOT NULL REFERENCES permissions (id) ON DELETE CASCADE,
+  PRIMARY KEY (role_id, permission_id)
+);
+
+CREATE TABLE user_roles (
+  user_id uuid NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  role_id integer NOT NULL REFERENCES roles (id) ON DELETE CASCADE,
+  created_at timestamptz NOT NULL DEFAULT current_timestamp,
+  PRIMARY KEY (user_id, role_id)
+);
+
+INSERT INTO permissions (name)
+VALUES ('users:read'), ('users:write'), ('roles:read'), ('roles:write'), ('catalog:write');
+
+INSERT INTO roles (name, description)
+VALUES ('admin', 'Full access'),
+  ('support', 'Can look up users'),
+  ('editor', 'Can edit the catalog');
+
+INSERT INTO role_permissions (role_id, permission_id)
+SELECT roles.id, permissions.id FROM roles CROSS JOIN permissions
+WHERE roles.name = 'admin'
+  OR (roles.name = 'support' AND permissions.name = 'users:read')
+  OR (roles.name = 'editor' AND permissions.name = 'catalog:write');
+
+INSERT INTO user_roles (user_id, role_id)
+SELECT users.id, roles.id FROM users CROSS JOIN roles
+WHERE users.is_superuser AND roles.name = 'admin';
@@ -0,0 +1,8 @@
+-- Add up migration script here
+-- failed login attempts per account (account:<email>) and per client ip (ip:<address>)
+CREATE TABLE login_throttles (
+  key varchar(320) PRIMARY KEY,
+  failures integer NOT NULL DEFAULT 0,
+  last_failure_at timestamptz,
+  locked_until timestamptz
+);
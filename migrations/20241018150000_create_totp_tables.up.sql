@@ -0,0 +1,21 @@
+-- Add up migration script here
+-- the TOTP secret of a user, the second factor is only required once it is confirmed
+CREATE TABLE user_totp (
+  user_id uuid PRIMARY KEY REFERENCES users (id) ON DELETE CASCADE,
+  secret varchar(64) NOT NULL,
+  confirmed_at timestamptz,
+  -- the time step of the last accepted code, so that a code cannot be replayed
+  last_used_step bigint,
+  created_at timestamptz NOT NULL DEFAULT current_timestamp
+);
+
+-- single-use codes to log in when the authenticator is lost
+CREATE TABLE recovery_codes (
+  id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+  user_id uuid NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  code_hash varchar(64) NOT NULL,
+  created_at timestamptz NOT NULL DEFAULT current_timestamp,
+  used_at timestamptz
+);
+
+CREATE INDEX recovery_codes_user_id_idx ON recovery_codes (user_id);
@@ -0,0 +1,18 @@
+-- Add up migration script here
+-- personal API keys, only the hash of the key is stored
+CREATE TABLE api_keys (
+  id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+  user_id uuid NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  name varchar(64) NOT NULL,
+  -- the start of the key, so that the user can recognize it
+  key_prefix varchar(16) NOT NULL,
+  key_hash varchar(64) UNIQUE NOT NULL,
+  -- the permissions of the user that the key is restricted to
+  scopes varchar(64)[] NOT NULL DEFAULT '{}',
+  expires_at timestamptz,
+  created_at timestamptz NOT NULL DEFAULT current_timestamp,
+  last_used_at timestamptz,
+  revoked_at timestamptz
+);
+
+CREATE INDEX api_keys_user_id_idx ON api_keys (user_id);
@@ -0,0 +1,19 @@
+-- Add up migration script here
+CREATE OR REPLACE FUNCTION set_updated_at() RETURNS trigger AS $$
+BEGIN
+  NEW.updated_at = current_timestamp;
+  RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+-- only the changes made by the user or an admin count as an update,
+-- not the bookkeeping such as the last login or the revocation of tokens
+CREATE TRIGGER users_set_updated_at
+  BEFORE UPDATE ON users
+  FOR EACH ROW
+  WHEN (
+    (OLD.email, OLD.name, OLD.hashed_password, OLD.is_active, OLD.is_verified, OLD.is_superuser)
+    IS DISTINCT FROM
+    (NEW.email, NEW.name, NEW.hashed_password, NEW.is_active, NEW.is_verified, NEW.is_superuser)
+  )
+  EXECUTE FUNCTION set_updated_at();
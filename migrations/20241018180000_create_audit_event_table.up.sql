@@ -0,0 +1,35 @@
+-- Add up migration script here
+-- the users are not referenced, so that the events outlive a deleted account
+CREATE TABLE audit_events (
+  id bigserial PRIMARY KEY,
+  -- NULL if nobody is logged in, e.g. a failed login or a registration
+  actor_id uuid,
+  -- <resource>.<action>, e.g. user.deactivated
+  action varchar(64) NOT NULL,
+  target_id uuid,
+  ip varchar(64),
+  user_agent text,
+  metadata jsonb NOT NULL DEFAULT '{}',
+  created_at timestamptz NOT NULL DEFAULT current_timestamp
+);
+
+CREATE INDEX audit_events_created_at_idx ON audit_events (created_at DESC);
+CREATE INDEX audit_events_actor_id_idx ON audit_events (actor_id);
+CREATE INDEX audit_events_target_id_idx ON audit_events (target_id);
+
+CREATE OR REPLACE FUNCTION reject_audit_event_change() RETURNS trigger AS $$
+BEGIN
+  RAISE EXCEPTION 'audit events are append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER audit_events_append_only
+  BEFORE UPDATE OR DELETE ON audit_events
+  FOR EACH ROW
+  EXECUTE FUNCTION reject_audit_event_change();
+
+INSERT INTO permissions (name) VALUES ('audit:read');
+
+INSERT INTO role_permissions (role_id, permission_id)
+SELECT roles.id, permissions.id FROM roles, permissions
+WHERE roles.name = 'admin' AND permissions.name = 'audit:read';
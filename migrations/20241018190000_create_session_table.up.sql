@@ -0,0 +1,24 @@
+-- Add up migration script here
+-- a session is a login on a device, its refresh tokens are the token family of the login
+CREATE TABLE sessions (
+  id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+  user_id uuid NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  user_agent text,
+  ip varchar(64),
+  created_at timestamptz NOT NULL DEFAULT current_timestamp,
+  last_seen_at timestamptz NOT NULL DEFAULT current_timestamp,
+  revoked_at timestamptz
+);
+
+CREATE INDEX sessions_user_id_idx ON sessions (user_id);
+
+-- the logins before the sessions were tracked
+INSERT INTO sessions (id, user_id, created_at, last_seen_at, revoked_at)
+SELECT family_id, user_id, MIN(created_at), MAX(created_at),
+  CASE WHEN bool_and(revoked_at IS NOT NULL) THEN MAX(revoked_at) END
+FROM refresh_tokens
+GROUP BY family_id, user_id;
+
+ALTER TABLE refresh_tokens
+  ADD CONSTRAINT refresh_tokens_family_id_fkey
+  FOREIGN KEY (family_id) REFERENCES sessions (id) ON DELETE CASCADE;
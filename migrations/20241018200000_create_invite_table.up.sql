@@ -0,0 +1,14 @@
+-- Add up migration script here
+-- invite codes for the registration, only the hash of the code is stored
+CREATE TABLE invites (
+  id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+  code_hash varchar(64) UNIQUE NOT NULL,
+  -- the role assigned to the users who register with the code
+  role_id integer REFERENCES roles (id) ON DELETE SET NULL,
+  max_uses integer NOT NULL DEFAULT 1 CHECK (max_uses > 0),
+  use_count integer NOT NULL DEFAULT 0 CHECK (use_count <= max_uses),
+  expires_at timestamptz,
+  created_by uuid REFERENCES users (id) ON DELETE SET NULL,
+  created_at timestamptz NOT NULL DEFAULT current_timestamp,
+  revoked_at timestamptz
+);
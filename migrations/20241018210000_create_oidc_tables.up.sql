@@ -0,0 +1,25 @@
+-- Add up migration script here
+-- the accounts of an OpenID Connect provider that are linked to our users
+CREATE TABLE oidc_identities (
+  issuer varchar(255) NOT NULL,
+  -- the `sub` claim, which is unique per issuer
+  subject varchar(255) NOT NULL,
+  user_id uuid NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  -- the email the provider had when the identity was linked
+  email varchar(255),
+  created_at timestamptz NOT NULL DEFAULT current_timestamp,
+  last_login_at timestamptz,
+  PRIMARY KEY (issuer, subject)
+);
+
+CREATE INDEX oidc_identities_user_id_idx ON oidc_identities (user_id);
+
+-- the logins that were sent to the provider and have not come back yet,
+-- only the hash of the state is stored
+CREATE TABLE oidc_login_attempts (
+  state_hash varchar(64) PRIMARY KEY,
+  nonce varchar(64) NOT NULL,
+  code_verifier varchar(128) NOT NULL,
+  created_at timestamptz NOT NULL DEFAULT current_timestamp,
+  expires_at timestamptz NOT NULL
+);
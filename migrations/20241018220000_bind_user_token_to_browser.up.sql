@@ -0,0 +1,4 @@
+-- Add up migration script here
+-- the hash of a secret kept in a cookie of the browser that asked for the token,
+-- the token only works in that browser if it is set, e.g. for the sign-in links
+ALTER TABLE user_tokens ADD COLUMN browser_hash varchar(64);
@@ -0,0 +1,3 @@
+-- Add down migration script here
+DROP INDEX IF EXISTS pokemon_name_prefix_idx;
+DROP INDEX IF EXISTS pokemon_name_trgm_idx;
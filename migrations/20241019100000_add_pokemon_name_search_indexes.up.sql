@@ -0,0 +1,7 @@
+-- Add up migration script here
+-- trigram similarity for the fuzzy search of the names, pg_trgm is a trusted extension
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+-- the search is case-insensitive, so the indexes are on the lowercased name
+CREATE INDEX pokemon_name_trgm_idx ON pokemon USING GIN (lower(name) gin_trgm_ops);
+-- text_pattern_ops lets the prefix search (LIKE 'abc%') use a btree index whatever the collation
+CREATE INDEX pokemon_name_prefix_idx ON pokemon (lower(name) text_pattern_ops);
@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP INDEX IF EXISTS pokemon_search_vector_idx;
+ALTER TABLE pokemon DROP COLUMN IF EXISTS search_vector;
+DROP FUNCTION IF EXISTS pokemon_search_vector(text, text[]);
@@ -0,0 +1,12 @@
+-- Add up migration script here
+-- the document of the full-text search, the name weighs more than the types.
+-- a generated column needs an immutable expression, array_to_string alone is only stable
+CREATE FUNCTION pokemon_search_vector(name text, types text[]) RETURNS tsvector
+LANGUAGE sql IMMUTABLE PARALLEL SAFE AS $$
+  SELECT setweight(to_tsvector('english', name), 'A')
+    || setweight(to_tsvector('english', array_to_string(types, ' ')), 'B')
+$$;
+
+ALTER TABLE pokemon ADD COLUMN search_vector tsvector
+  GENERATED ALWAYS AS (pokemon_search_vector(name, types::text[])) STORED;
+CREATE INDEX pokemon_search_vector_idx ON pokemon USING GIN (search_vector);
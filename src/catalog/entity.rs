@@ -1,6 +1,16 @@
 use num::{Bounded, Num};
 use serde::{Deserialize, Serialize};
 
+/// An item matching a full-text search
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SearchHit<T> {
+    #[serde(flatten)]
+    pub item: T,
+    pub rank: f32,
+    // the matching text, HTML escaped, with the matching words in <mark> tags
+    pub snippet: String,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Range<T: num::Num> {
     pub min: T,
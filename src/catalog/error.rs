@@ -1,3 +1,4 @@
+use crate::common::error::CommonError;
 use axum::{
     http::StatusCode,
     response::{IntoResponse, Response},
@@ -13,6 +14,8 @@ pub enum CatalogError {
     #[error("Resource not ready")]
     NotImplemented,
     #[error(transparent)]
+    CommonError(#[from] CommonError),
+    #[error(transparent)]
     UnexpectedError(#[from] anyhow::Error),
 }
 
@@ -20,6 +23,8 @@ impl IntoResponse for CatalogError {
     fn into_response(self) -> Response {
         let status = match self {
             CatalogError::NotFound | CatalogError::NotImplemented => StatusCode::NOT_FOUND,
+            CatalogError::CommonError(CommonError::NotFound) => StatusCode::NOT_FOUND,
+            CatalogError::CommonError(CommonError::ValidationError(_)) => StatusCode::BAD_REQUEST,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         };
         let body = Json(json!({
@@ -1,11 +1,16 @@
+use crate::catalog::entity::SearchHit;
 use crate::catalog::error::CatalogError;
 use crate::catalog::service::{CatalogService, HasCatalogService};
 use crate::common::entity::Pagination;
 use crate::common::entity::Pokemon;
-use crate::common::entity::{AppState, QueryName};
+use crate::common::entity::{
+    AppState, ItemFilter, Keyset, QueryCursor, QueryName, QuerySearch, SortSpec,
+};
+use crate::common::error::CommonError;
 use anyhow::Context;
 use async_trait::async_trait;
 use axum::extract::Query;
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
 use axum::{
     extract::{Json, Path, State},
     routing::{get, put},
@@ -13,6 +18,12 @@ use axum::{
 };
 use sqlx::PgPool;
 
+// the cursors of the pages next to a listing, to be sent back as `after` and `before`
+pub const NEXT_CURSOR_HEADER: &str = "x-next-cursor";
+pub const PREVIOUS_CURSOR_HEADER: &str = "x-previous-cursor";
+// a larger page is clamped, so that a client cannot load the whole catalog at once
+pub const MAX_PAGE_SIZE: usize = 100;
+
 pub struct CatalogHandlers<T> {
     _service: std::marker::PhantomData<T>,
 }
@@ -29,23 +40,107 @@ pub trait HasCatalogHandlers: 'static + Send + Sync {
         Router::new()
             .route("/items", get(Self::show_items))
             .route("/items/:id", put(Self::show_item))
+            .route("/search", get(Self::search_items))
     }
 
+    /// The items are paginated by the page, or by the `after` and `before` cursors.
+    /// The page size is limited to `MAX_PAGE_SIZE`.
+    /// Either way the cursors of the next and the previous pages are in the headers,
+    /// so that a client can go on from a numbered page with the cursors
     async fn show_items(
         State(pool): State<PgPool>,
         q_name: Option<QueryName>,
         pagination: Option<Query<Pagination>>,
-    ) -> Result<Json<Vec<<Self::Service as HasCatalogService>::Item>>, CatalogError> {
-        let items = if let Some(QueryName { name }) = q_name {
-            Self::Service::query_items_by_name(&pool, &name)
-                .await
-                .context("Failed to get items")?
-        } else {
-            Self::Service::query_items(&pool, pagination.unwrap_or_default())
+        filter: ItemFilter,
+        sort: SortSpec,
+        cursor: QueryCursor,
+    ) -> Result<
+        (
+            HeaderMap,
+            Json<Vec<<Self::Service as HasCatalogService>::Item>>,
+        ),
+        CatalogError,
+    > {
+        let pagination = Query(pagination.unwrap_or_default().clamped(MAX_PAGE_SIZE)?);
+        let keyset = cursor.keyset(&sort)?;
+        if let Some(QueryName { name }) = q_name {
+            // the relevance of the names cannot be resumed from an item
+            if keyset.is_some() {
+                return Err(CommonError::ValidationError(
+                    "A name search is paginated by the page, not by a cursor".into(),
+                )
+                .into());
+            }
+            let items =
+                Self::Service::query_items_by_name(&pool, &name, pagination, &filter, &sort)
+                    .await
+                    .context("Failed to get items")?;
+            return Ok((HeaderMap::new(), Json(items)));
+        }
+
+        let limit = pagination.page_size;
+        let (items, has_next, has_previous) = match &keyset {
+            None => {
+                let has_previous = pagination.page > 0;
+                let items = Self::Service::query_items(&pool, pagination, &filter, &sort)
+                    .await
+                    .context("Failed to get items")?;
+                let has_next = items.len() == limit;
+                (items, has_next, has_previous)
+            }
+            // one more item tells whether there is another page past this one
+            Some(keyset) => {
+                let mut items = Self::Service::query_items_by_keyset(
+                    &pool,
+                    keyset,
+                    limit.saturating_add(1),
+                    &filter,
+                    &sort,
+                )
                 .await
-                .context("Failed to get items")?
+                .context("Failed to get items")?;
+                let has_more = items.len() > limit;
+                match keyset {
+                    Keyset::After(_) => {
+                        items.truncate(limit);
+                        (items, has_more, true)
+                    }
+                    Keyset::Before(_) => {
+                        if has_more {
+                            items.remove(0);
+                        }
+                        (items, true, has_more)
+                    }
+                }
+            }
         };
-        Ok(Json(items))
+
+        let mut headers = HeaderMap::new();
+        let cursors = [
+            (NEXT_CURSOR_HEADER, has_next, items.last()),
+            (PREVIOUS_CURSOR_HEADER, has_previous, items.first()),
+        ];
+        for (header, has_page, item) in cursors {
+            if let (true, Some(item)) = (has_page, item) {
+                let cursor = Self::Service::cursor(item, &sort).encode();
+                let value = HeaderValue::from_str(&cursor).context("Invalid cursor header")?;
+                headers.insert(HeaderName::from_static(header), value);
+            }
+        }
+        Ok((headers, Json(items)))
+    }
+
+    async fn search_items(
+        State(pool): State<PgPool>,
+        QuerySearch { q }: QuerySearch,
+        pagination: Option<Query<Pagination>>,
+    ) -> Result<Json<Vec<SearchHit<<Self::Service as HasCatalogService>::Item>>>, CatalogError>
+    {
+        let pagination = Query(pagination.unwrap_or_default().clamped(MAX_PAGE_SIZE)?);
+        let hits = Self::Service::search_items(&pool, &q, pagination)
+            .await
+            .context("Failed to search items")?;
+        Ok(Json(hits))
     }
 
     async fn show_item(
@@ -58,3 +153,157 @@ pub trait HasCatalogHandlers: 'static + Send + Sync {
         Ok(Json(item))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::catalog::pokemon::entity::Monster;
+    use crate::common::db::postgres::{get_test_postgres_pool, unique_word};
+
+    async fn page(
+        pool: &PgPool,
+        filter: &ItemFilter,
+        sort: &SortSpec,
+        cursor: QueryCursor,
+    ) -> (Vec<i32>, Option<String>, Option<String>) {
+        let pagination = Pagination {
+            page: 0,
+            page_size: 2,
+        };
+        let (headers, Json(items)) = CatalogHandlers::<Pokemon>::show_items(
+            State(pool.clone()),
+            None,
+            Some(Query(pagination)),
+            filter.clone(),
+            sort.clone(),
+            cursor,
+        )
+        .await
+        .unwrap();
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .map(|value| value.to_str().unwrap().to_string())
+        };
+        let ids = items.iter().map(|item: &Monster| item.id).collect();
+        (
+            ids,
+            header(NEXT_CURSOR_HEADER),
+            header(PREVIOUS_CURSOR_HEADER),
+        )
+    }
+
+    #[tokio::test]
+    async fn walk_pages_with_cursors() {
+        let pool = &get_test_postgres_pool();
+        let type_ = unique_word();
+        let mut ids = vec![];
+        for weight in [30, 10, 30, 20, 10] {
+            let id = sqlx::query_scalar!(
+                "INSERT INTO pokemon (name, height, weight, types) VALUES ($1, 1, $2, $3) RETURNING id",
+                type_,
+                weight,
+                &[type_.clone()]
+            )
+            .fetch_one(pool)
+            .await
+            .unwrap();
+            ids.push(id);
+        }
+        let filter = ItemFilter {
+            types: vec![type_.clone()],
+            ..Default::default()
+        };
+        let pairs = vec![("sort".to_string(), "weight:desc".to_string())];
+        let sort = SortSpec::from_pairs(pairs).unwrap();
+        // by the weight, then by the id
+        let expected = [ids[0], ids[2], ids[3], ids[1], ids[4]];
+
+        let (first, next, previous) = page(pool, &filter, &sort, QueryCursor::default()).await;
+        assert_eq!(first, expected[..2]);
+        let past_first = next.clone();
+        assert!(previous.is_none());
+        let after = |cursor: Option<String>| QueryCursor {
+            after: cursor,
+            before: None,
+        };
+        let (second, next, _) = page(pool, &filter, &sort, after(next)).await;
+        assert_eq!(second, expected[2..4]);
+        let (third, next, previous) = page(pool, &filter, &sort, after(next)).await;
+        assert_eq!(third, expected[4..]);
+        assert!(next.is_none());
+
+        // an item added to the first page does not shift the next ones
+        sqlx::query!(
+            "INSERT INTO pokemon (name, height, weight, types) VALUES ($1, 1, 40, $2)",
+            type_,
+            &[type_.clone()]
+        )
+        .execute(pool)
+        .await
+        .unwrap();
+        let before = QueryCursor {
+            after: None,
+            before: previous,
+        };
+        let (back, _, previous) = page(pool, &filter, &sort, before).await;
+        assert_eq!(back, expected[2..4]);
+        assert!(previous.is_some());
+
+        // an oversized page is clamped, the row that probes the next page does not overflow
+        let oversized = Pagination {
+            page: 0,
+            page_size: usize::MAX,
+        };
+        let (_, Json(items)) = CatalogHandlers::<Pokemon>::show_items(
+            State(pool.clone()),
+            None,
+            Some(Query(oversized)),
+            filter.clone(),
+            sort.clone(),
+            after(past_first),
+        )
+        .await
+        .unwrap();
+        assert_eq!(items.len(), 3);
+
+        // a page past the largest offset is rejected instead of failing the query
+        let far = Pagination {
+            page: usize::MAX,
+            page_size: 16,
+        };
+        let result = CatalogHandlers::<Pokemon>::show_items(
+            State(pool.clone()),
+            None,
+            Some(Query(far)),
+            filter.clone(),
+            sort.clone(),
+            QueryCursor::default(),
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(CatalogError::CommonError(CommonError::ValidationError(_)))
+        ));
+
+        // a cursor is only valid for its sort
+        let result = CatalogHandlers::<Pokemon>::show_items(
+            State(pool.clone()),
+            None,
+            None,
+            filter,
+            SortSpec::default(),
+            after(previous),
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(CatalogError::CommonError(CommonError::ValidationError(_)))
+        ));
+
+        sqlx::query!("DELETE FROM pokemon WHERE $1 = ANY(types)", type_)
+            .execute(pool)
+            .await
+            .unwrap();
+    }
+}
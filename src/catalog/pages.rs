@@ -1,6 +1,7 @@
 use crate::catalog::error::CatalogError;
 use crate::catalog::service::HasCatalogService;
-use crate::common::entity::{AppState, Pagination, QueryName};
+use crate::common::entity::{AppState, ItemFilter, Pagination, QueryName, SortSpec};
+use crate::user_mgmt::csrf::CsrfToken;
 use askama_axum::Template;
 use async_trait::async_trait;
 use axum::extract::Query;
@@ -28,25 +29,31 @@ pub trait HasCatalogPages: 'static + Send + Sync {
 
     async fn show_items(
         State(pool): State<PgPool>,
+        csrf_token: CsrfToken,
         q_name: Option<QueryName>,
         pagination: Option<Query<Pagination>>,
+        filter: ItemFilter,
+        sort: SortSpec,
     ) -> Result<Self::ItemsPage, CatalogError>;
 
     async fn show_item(
         State(pool): State<PgPool>,
+        csrf_token: CsrfToken,
         Path(id): Path<u32>,
     ) -> Result<Self::ItemPage, CatalogError>;
 }
 
 pub mod pokemon {
     use crate::catalog::error::CatalogError;
+    use crate::catalog::handler::MAX_PAGE_SIZE;
     use crate::catalog::pages::CatalogPages;
     use crate::catalog::pages::HasCatalogPages;
     use crate::catalog::pokemon::entity::Monster;
     use crate::catalog::service::{CatalogService, HasCatalogService};
     use crate::common::entity::PaginationNavigation;
-    use crate::common::entity::{Pagination, Pokemon, QueryName};
+    use crate::common::entity::{ItemFilter, Pagination, Pokemon, QueryName, SortSpec};
     use crate::common::filters;
+    use crate::user_mgmt::csrf::CsrfToken;
     use anyhow::Context;
     use askama_axum::Template;
     use async_trait::async_trait;
@@ -61,12 +68,45 @@ pub mod pokemon {
         pub page_size: usize,
         pub current_page: usize,
         pub pagination: PaginationNavigation,
+        // the search and the filter are kept in the links of the pages
+        pub name: Option<String>,
+        pub filter: ItemFilter,
+        pub sort: SortSpec,
+        pub types: &'static [&'static str],
+        pub sorts: &'static [(&'static str, &'static str)],
+        pub csrf_token: String,
     }
 
+    impl PokemonItemsTemplate {
+        /// A sort from the url that is not offered, e.g. with multiple keys
+        pub fn is_custom_sort(&self) -> bool {
+            !self.sorts.iter().any(|(param, _)| self.sort.is(param))
+        }
+    }
+
+    /// The sorts offered by the list page, by their param and label
+    const SORTS: [(&str, &str); 8] = [
+        ("", "Number"),
+        ("id:desc", "Number, descending"),
+        ("name", "Name"),
+        ("name:desc", "Name, descending"),
+        ("height", "Height"),
+        ("height:desc", "Height, descending"),
+        ("weight", "Weight"),
+        ("weight:desc", "Weight, descending"),
+    ];
+
+    /// The types offered by the filter panel
+    const TYPES: [&str; 18] = [
+        "normal", "fire", "water", "grass", "electric", "ice", "fighting", "poison", "ground",
+        "flying", "psychic", "bug", "rock", "ghost", "dragon", "dark", "steel", "fairy",
+    ];
+
     #[derive(Template)]
     #[template(path = "pokemon/item.html")]
     pub struct PokemonItemTemplate {
         pub pokemon: Monster,
+        pub csrf_token: String,
     }
 
     #[async_trait]
@@ -77,23 +117,37 @@ pub mod pokemon {
 
         async fn show_items(
             State(pool): State<PgPool>,
+            CsrfToken(csrf_token): CsrfToken,
             q_name: Option<QueryName>,
             pagination: Option<Query<Pagination>>,
+            filter: ItemFilter,
+            sort: SortSpec,
         ) -> Result<Self::ItemsPage, CatalogError> {
-            let count = Self::Service::query_items_count(&pool)
-                .await
-                .context("Failed to get items count")?;
-
-            let pagination = pagination.unwrap_or_default();
+            let pagination = Query(pagination.unwrap_or_default().clamped(MAX_PAGE_SIZE)?);
+            let name = q_name.map(|QueryName { name }| name);
 
-            let items = if let Some(QueryName { name }) = q_name {
-                Self::Service::query_items_by_name(&pool, &name)
+            let (count, items) = if let Some(name) = &name {
+                let count = Self::Service::query_items_by_name_count(&pool, name, &filter)
                     .await
-                    .context("Failed to get items")?
+                    .context("Failed to get items count")?;
+                let items = Self::Service::query_items_by_name(
+                    &pool,
+                    name,
+                    pagination.clone(),
+                    &filter,
+                    &sort,
+                )
+                .await
+                .context("Failed to get items")?;
+                (count, items)
             } else {
-                Self::Service::query_items(&pool, pagination.clone())
+                let count = Self::Service::query_items_count(&pool, &filter)
                     .await
-                    .context("Failed to get items")?
+                    .context("Failed to get items count")?;
+                let items = Self::Service::query_items(&pool, pagination.clone(), &filter, &sort)
+                    .await
+                    .context("Failed to get items")?;
+                (count, items)
             };
 
             let total_pages = pagination.get_total_pages(count);
@@ -103,17 +157,27 @@ pub mod pokemon {
                 page_size: pagination.page_size,
                 total_pages,
                 pagination: pagination.get_navigation(total_pages, 5),
+                name,
+                filter,
+                sort,
+                types: &TYPES,
+                sorts: &SORTS,
+                csrf_token,
             })
         }
 
         async fn show_item(
             State(pool): State<PgPool>,
+            CsrfToken(csrf_token): CsrfToken,
             Path(id): Path<u32>,
         ) -> Result<Self::ItemPage, CatalogError> {
             let item = Self::Service::query_item(&pool, id)
                 .await
                 .context("Failed to get item")?;
-            Ok(PokemonItemTemplate { pokemon: item })
+            Ok(PokemonItemTemplate {
+                pokemon: item,
+                csrf_token,
+            })
         }
     }
 }
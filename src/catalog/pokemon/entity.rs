@@ -58,7 +58,7 @@ pub struct Type {
     pub url: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
 pub struct Monster {
     pub id: i32,
     pub name: String,
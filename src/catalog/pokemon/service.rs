@@ -1,85 +1,559 @@
 use super::entity::Monster;
+use crate::catalog::entity::SearchHit;
 use crate::catalog::service::CatalogService;
-use crate::catalog::service::HasCatalogService;
-use crate::common::entity::{Pagination, Pokemon};
+use crate::catalog::service::{escape_like, escape_snippet, HasCatalogService, HEADLINE_OPTIONS};
+use crate::common::entity::{
+    Cursor, ItemFilter, Keyset, Pagination, Pokemon, SortField, SortSpec, SortValue,
+};
 use async_trait::async_trait;
 use axum::extract::Query;
-use sqlx::PgPool;
+use sqlx::{PgPool, Postgres, QueryBuilder};
 
 #[async_trait]
 impl HasCatalogService for CatalogService<Pokemon> {
     type Item = Monster;
 
-    async fn query_items_count(pool: &PgPool) -> Result<usize, sqlx::Error> {
-        sqlx::query!("SELECT COUNT(*) FROM pokemon")
+    fn sort_value(item: &Self::Item, field: SortField) -> SortValue {
+        match field {
+            SortField::Id => SortValue::Int(item.id.into()),
+            SortField::Name => SortValue::Text(item.name.clone()),
+            SortField::Height => SortValue::Int(item.height.into()),
+            SortField::Weight => SortValue::Int(item.weight.into()),
+        }
+    }
+
+    async fn query_items_count(pool: &PgPool, filter: &ItemFilter) -> Result<usize, sqlx::Error> {
+        let mut builder = QueryBuilder::new("SELECT COUNT(*) FROM pokemon WHERE true");
+        push_filter(&mut builder, filter);
+        builder
+            .build_query_scalar::<i64>()
             .fetch_one(pool)
             .await
-            .map(|row| row.count)
             .inspect_err(|e| tracing::error!("Failed to fetch count: {}", e))
-            .map(|count| count.unwrap_or(0) as usize)
+            .map(|count| count as usize)
     }
 
     async fn query_items(
         pool: &PgPool,
         pagination: Query<Pagination>,
+        filter: &ItemFilter,
+        sort: &SortSpec,
+    ) -> Result<Vec<Self::Item>, sqlx::Error> {
+        let mut builder = QueryBuilder::new(format!("SELECT {COLUMNS} FROM pokemon WHERE true"));
+        push_filter(&mut builder, filter);
+        builder.push(" ORDER BY ").push(sort.order_by());
+        push_pagination(&mut builder, &pagination);
+        builder
+            .build_query_as::<Monster>()
+            .fetch_all(pool)
+            .await
+            .inspect_err(|e| tracing::error!("Failed to fetch monsters: {}", e))
+    }
+
+    async fn query_items_by_keyset(
+        pool: &PgPool,
+        keyset: &Keyset,
+        limit: usize,
+        filter: &ItemFilter,
+        sort: &SortSpec,
     ) -> Result<Vec<Self::Item>, sqlx::Error> {
+        let mut builder = QueryBuilder::new(format!("SELECT {COLUMNS} FROM pokemon WHERE true"));
+        push_filter(&mut builder, filter);
+        // the rows before the cursor are read backwards from it, then put back in order
+        let (cursor, order) = match keyset {
+            Keyset::After(cursor) => (cursor, sort.clone()),
+            Keyset::Before(cursor) => (cursor, sort.reversed()),
+        };
+        push_keyset(&mut builder, &order, cursor);
+        builder.push(" ORDER BY ").push(order.order_by());
+        builder.push(" LIMIT ").push_bind(limit as i64);
+        let mut items = builder
+            .build_query_as::<Monster>()
+            .fetch_all(pool)
+            .await
+            .inspect_err(|e| tracing::error!("Failed to fetch monsters: {}", e))?;
+        if let Keyset::Before(_) = keyset {
+            items.reverse();
+        }
+        Ok(items)
+    }
+
+    #[allow(unused_variables)]
+    async fn query_item(pool: &PgPool, id: u32) -> Result<Self::Item, sqlx::Error> {
         sqlx::query_as!(
             Monster,
             r#"
             SELECT id, name, height, weight, types, image_url, image_url_game_front, image_url_game_back, image_url_game_front_shiny, image_url_game_back_shiny
             FROM pokemon
-            ORDER BY id
-            LIMIT $1
-            OFFSET $2
+            WHERE id = $1
+            "#,
+            id as i64
+        ).fetch_one(pool)
+            .await
+            .inspect_err(|e| tracing::error!("Failed to fetch monsters: {}", e))
+    }
+
+    async fn search_items(
+        pool: &PgPool,
+        query: &str,
+        pagination: Query<Pagination>,
+    ) -> Result<Vec<SearchHit<Self::Item>>, sqlx::Error> {
+        // the snippet is made from the same text as the search_vector column
+        let rows = sqlx::query!(
+            r#"
+            SELECT id, name, height, weight, types, image_url, image_url_game_front, image_url_game_back, image_url_game_front_shiny, image_url_game_back_shiny,
+                ts_rank_cd(search_vector, query) AS "rank!",
+                ts_headline('english', name || ' ' || array_to_string(types, ' '), query, $2) AS "snippet!"
+            FROM pokemon, websearch_to_tsquery('english', $1) AS query
+            WHERE search_vector @@ query
+            ORDER BY ts_rank_cd(search_vector, query) DESC, id
+            LIMIT $3
+            OFFSET $4
             "#,
+            query,
+            HEADLINE_OPTIONS,
             pagination.page_size as i64,
-            pagination.offset() as i64
+            pagination.sql_offset()
         ).fetch_all(pool)
             .await
-            .inspect_err(|e| tracing::error!("Failed to fetch monsters: {}", e))
+            .inspect_err(|e| tracing::error!("Failed to search monsters: {}", e))?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| SearchHit {
+                item: Monster {
+                    id: row.id,
+                    name: row.name,
+                    height: row.height,
+                    weight: row.weight,
+                    types: row.types,
+                    image_url: row.image_url,
+                    image_url_game_front: row.image_url_game_front,
+                    image_url_game_back: row.image_url_game_back,
+                    image_url_game_front_shiny: row.image_url_game_front_shiny,
+                    image_url_game_back_shiny: row.image_url_game_back_shiny,
+                },
+                rank: row.rank,
+                snippet: escape_snippet(&row.snippet),
+            })
+            .collect())
     }
 
-    #[allow(unused_variables)]
-    async fn query_item(pool: &PgPool, id: u32) -> Result<Self::Item, sqlx::Error> {
+    async fn query_item_by_name(pool: &PgPool, name: String) -> Result<Self::Item, sqlx::Error> {
         sqlx::query_as!(
             Monster,
             r#"
             SELECT id, name, height, weight, types, image_url, image_url_game_front, image_url_game_back, image_url_game_front_shiny, image_url_game_back_shiny
             FROM pokemon
-            WHERE id = $1
+            WHERE lower(name) = lower($1)
+            ORDER BY id
+            LIMIT 1
             "#,
-            id as i64
+            name
         ).fetch_one(pool)
             .await
-            .inspect_err(|e| tracing::error!("Failed to fetch monsters: {}", e))
+            .inspect_err(|e| tracing::error!("Failed to fetch monster: {}", e))
     }
 
-    #[allow(unused_variables)]
-    async fn query_item_by_name(pool: &PgPool, name: String) -> Result<Self::Item, sqlx::Error> {
-        unimplemented!()
+    async fn query_items_by_name_count(
+        pool: &PgPool,
+        name: &str,
+        filter: &ItemFilter,
+    ) -> Result<usize, sqlx::Error> {
+        let mut builder = QueryBuilder::new("SELECT COUNT(*) FROM pokemon WHERE true");
+        push_name_match(&mut builder, name);
+        push_filter(&mut builder, filter);
+        builder
+            .build_query_scalar::<i64>()
+            .fetch_one(pool)
+            .await
+            .inspect_err(|e| tracing::error!("Failed to fetch count: {}", e))
+            .map(|count| count as usize)
     }
 
-    #[allow(unused_variables)]
     async fn query_items_by_name(
         pool: &PgPool,
         name: &str,
+        pagination: Query<Pagination>,
+        filter: &ItemFilter,
+        sort: &SortSpec,
     ) -> Result<Vec<Self::Item>, sqlx::Error> {
-        unimplemented!()
+        let name = name.to_lowercase();
+        let mut builder = QueryBuilder::new(format!("SELECT {COLUMNS} FROM pokemon WHERE true"));
+        push_name_match(&mut builder, &name);
+        push_filter(&mut builder, filter);
+        if sort.is_empty() {
+            // the exact match comes first, then the prefix matches, then the most similar names
+            builder
+                .push(" ORDER BY lower(name) = ")
+                .push_bind(name.clone())
+                .push(" DESC, lower(name) LIKE ")
+                .push_bind(format!("{}%", escape_like(&name)))
+                .push(" DESC, similarity(lower(name), ")
+                .push_bind(name)
+                .push(") DESC, id");
+        } else {
+            builder.push(" ORDER BY ").push(sort.order_by());
+        }
+        push_pagination(&mut builder, &pagination);
+        builder
+            .build_query_as::<Monster>()
+            .fetch_all(pool)
+            .await
+            .inspect_err(|e| tracing::error!("Failed to fetch monsters: {}", e))
     }
 }
 
+const COLUMNS: &str = "id, name, height, weight, types, image_url, image_url_game_front, image_url_game_back, image_url_game_front_shiny, image_url_game_back_shiny";
+
+/// Case-insensitive match of a prefix or a similar name, after a WHERE clause.
+/// `%` is the similarity operator of pg_trgm, both conditions can use the indexes on lower(name)
+fn push_name_match(builder: &mut QueryBuilder<'_, Postgres>, name: &str) {
+    let name = name.to_lowercase();
+    builder
+        .push(" AND (lower(name) LIKE ")
+        .push_bind(format!("{}%", escape_like(&name)))
+        .push(" OR lower(name) % ")
+        .push_bind(name)
+        .push(")");
+}
+
+/// The conditions of the filter after a WHERE clause, the values are always bound
+fn push_filter(builder: &mut QueryBuilder<'_, Postgres>, filter: &ItemFilter) {
+    if !filter.types.is_empty() {
+        builder
+            .push(" AND types @> ")
+            .push_bind(filter.types.clone())
+            .push("::varchar[]");
+    }
+    let bounds = [
+        ("id >= ", filter.min_id.map(i64::from)),
+        ("id <= ", filter.max_id.map(i64::from)),
+        ("height >= ", filter.min_height.map(i64::from)),
+        ("height <= ", filter.max_height.map(i64::from)),
+        ("weight >= ", filter.min_weight.map(i64::from)),
+        ("weight <= ", filter.max_weight.map(i64::from)),
+    ];
+    for (condition, value) in bounds {
+        if let Some(value) = value {
+            builder.push(" AND ").push(condition).push_bind(value);
+        }
+    }
+}
+
+/// The rows that come after the cursor in the order, after a WHERE clause.
+/// The keys can go in different directions, so instead of a row comparison it is
+/// `a > x OR (a = x AND b < y) OR (a = x AND b = y AND id > z)`
+fn push_keyset(builder: &mut QueryBuilder<'_, Postgres>, order: &SortSpec, cursor: &Cursor) {
+    let keys: Vec<_> = order
+        .keys_with_tiebreak()
+        .into_iter()
+        .zip(cursor.values())
+        .collect();
+    builder.push(" AND (false");
+    for (i, (key, value)) in keys.iter().enumerate() {
+        builder.push(" OR (true");
+        for (equal_key, equal_value) in &keys[..i] {
+            builder
+                .push(" AND ")
+                .push(equal_key.field.column())
+                .push(" = ");
+            push_sort_value(builder, equal_value);
+        }
+        let operator = if key.descending { " < " } else { " > " };
+        builder
+            .push(" AND ")
+            .push(key.field.column())
+            .push(operator);
+        push_sort_value(builder, value);
+        builder.push(")");
+    }
+    builder.push(")");
+}
+
+fn push_sort_value(builder: &mut QueryBuilder<'_, Postgres>, value: &SortValue) {
+    match value {
+        SortValue::Int(value) => builder.push_bind(*value),
+        SortValue::Text(value) => builder.push_bind(value.clone()),
+    };
+}
+
+fn push_pagination(builder: &mut QueryBuilder<'_, Postgres>, pagination: &Pagination) {
+    builder
+        .push(" LIMIT ")
+        .push_bind(pagination.page_size as i64)
+        .push(" OFFSET ")
+        .push_bind(pagination.sql_offset());
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::common::db::postgres::get_postgres_pool;
+    use crate::common::db::postgres::{get_postgres_pool, get_test_postgres_pool, unique_word};
+    use crate::common::entity::{SortField, SortKey};
 
     #[tokio::test]
     async fn get_pokemon_count() {
         let pool = get_postgres_pool().await;
-        let has_count = CatalogService::<Pokemon>::query_items_count(pool)
+        let has_count = CatalogService::<Pokemon>::query_items_count(pool, &ItemFilter::default())
             .await
             .is_ok();
 
         assert!(has_count);
     }
+
+    #[tokio::test]
+    async fn search_pokemon_by_name() {
+        let pool = &get_test_postgres_pool();
+        let stem = unique_word();
+        let names = [
+            format!("{stem}achu"),
+            stem.clone(),
+            format!("{stem}ta"),
+            // a typo in the stem, only similar
+            format!("x{}", &stem[1..]),
+        ];
+        for name in &names {
+            sqlx::query!(
+                "INSERT INTO pokemon (name, height, weight, types) VALUES ($1, 1, 1, '{}')",
+                name
+            )
+            .execute(pool)
+            .await
+            .unwrap();
+        }
+
+        let search = stem.to_uppercase();
+        let count = CatalogService::<Pokemon>::query_items_by_name_count(
+            pool,
+            &search,
+            &ItemFilter::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(count, 4);
+        let pagination = Pagination {
+            page: 0,
+            page_size: 3,
+        };
+        let items = CatalogService::<Pokemon>::query_items_by_name(
+            pool,
+            &search,
+            Query(pagination),
+            &ItemFilter::default(),
+            &SortSpec::default(),
+        )
+        .await
+        .unwrap();
+        let found: Vec<&str> = items.iter().map(|item| item.name.as_str()).collect();
+        // the exact match, then the prefix matches in the order of the similarity
+        assert_eq!(
+            found,
+            vec![names[1].as_str(), names[2].as_str(), names[0].as_str()]
+        );
+        let pagination = Pagination {
+            page: 1,
+            page_size: 3,
+        };
+        let items = CatalogService::<Pokemon>::query_items_by_name(
+            pool,
+            &search,
+            Query(pagination),
+            &ItemFilter::default(),
+            &SortSpec::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].name, names[3]);
+
+        // the wildcards are matched literally
+        let count = CatalogService::<Pokemon>::query_items_by_name_count(
+            pool,
+            "zq%",
+            &ItemFilter::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(count, 0);
+        let item = CatalogService::<Pokemon>::query_item_by_name(pool, search)
+            .await
+            .unwrap();
+        assert_eq!(item.name, stem);
+
+        sqlx::query!("DELETE FROM pokemon WHERE name = ANY($1)", &names[..])
+            .execute(pool)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn full_text_search_ranks_and_highlights() {
+        let pool = &get_test_postgres_pool();
+        let word = unique_word();
+        let other = unique_word();
+        let rows = [
+            (format!("{word}-{other}"), vec![word.clone()]),
+            (format!("{word}-saur"), vec!["grass".to_string()]),
+            (format!("{other}-saur"), vec![word.clone()]),
+        ];
+        for (name, types) in &rows {
+            sqlx::query!(
+                "INSERT INTO pokemon (name, height, weight, types) VALUES ($1, 1, 1, $2)",
+                name,
+                types as &[String]
+            )
+            .execute(pool)
+            .await
+            .unwrap();
+        }
+
+        let search = |query: String| async move {
+            CatalogService::<Pokemon>::search_items(pool, &query, Query(Pagination::default()))
+                .await
+                .unwrap()
+        };
+        let hits = search(word.clone()).await;
+        let found: Vec<&str> = hits.iter().map(|hit| hit.item.name.as_str()).collect();
+        // the word in both the name and the types ranks first, the name weighs more than the types
+        assert_eq!(
+            found,
+            vec![rows[0].0.as_str(), rows[1].0.as_str(), rows[2].0.as_str()]
+        );
+        assert!(hits[1].snippet.contains(&format!("<mark>{word}</mark>")));
+
+        let hits = search(format!("{word} -grass")).await;
+        assert_eq!(hits.len(), 2);
+        assert!(hits.iter().all(|hit| hit.item.name != rows[1].0));
+        let hits = search(format!("\"{other} saur\"")).await;
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].item.name, rows[2].0);
+
+        let names: Vec<String> = rows.into_iter().map(|(name, _)| name).collect();
+        sqlx::query!("DELETE FROM pokemon WHERE name = ANY($1)", &names[..])
+            .execute(pool)
+            .await
+            .unwrap();
+    }
+
+    /// Three rows named after their unique type, the first and the last are of fire as well
+    async fn insert_typed_pokemon(pool: &PgPool, type_: &str) -> Vec<i32> {
+        let rows = [
+            (10, 100, vec![type_.to_string(), "fire".to_string()]),
+            (20, 200, vec![type_.to_string()]),
+            (30, 300, vec![type_.to_string(), "fire".to_string()]),
+        ];
+        let mut ids = vec![];
+        for (height, weight, types) in &rows {
+            let id = sqlx::query_scalar!(
+                "INSERT INTO pokemon (name, height, weight, types) VALUES ($1, $2, $3, $4) RETURNING id",
+                type_,
+                height,
+                weight,
+                types as &[String]
+            )
+            .fetch_one(pool)
+            .await
+            .unwrap();
+            ids.push(id);
+        }
+        ids
+    }
+
+    #[tokio::test]
+    async fn filter_pokemon() {
+        let pool = &get_test_postgres_pool();
+        let type_ = unique_word();
+        let ids = insert_typed_pokemon(pool, &type_).await;
+
+        let filter = ItemFilter {
+            types: vec![type_.clone(), "fire".to_string()],
+            min_weight: Some(150),
+            ..Default::default()
+        };
+        let count = CatalogService::<Pokemon>::query_items_count(pool, &filter)
+            .await
+            .unwrap();
+        assert_eq!(count, 1);
+        let filter = ItemFilter {
+            types: vec![type_.clone()],
+            max_height: Some(20),
+            min_id: Some(ids[1]),
+            ..Default::default()
+        };
+        let items = CatalogService::<Pokemon>::query_items(
+            pool,
+            Query(Pagination::default()),
+            &filter,
+            &SortSpec::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].id, ids[1]);
+        let count = CatalogService::<Pokemon>::query_items_by_name_count(pool, &type_, &filter)
+            .await
+            .unwrap();
+        assert_eq!(count, 1);
+
+        sqlx::query!("DELETE FROM pokemon WHERE id = ANY($1)", &ids[..])
+            .execute(pool)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn sort_pokemon() {
+        let pool = &get_test_postgres_pool();
+        let type_ = unique_word();
+        let ids = insert_typed_pokemon(pool, &type_).await;
+
+        // the same name everywhere, so the weight breaks the tie, the heaviest first
+        let filter = ItemFilter {
+            types: vec![type_.clone()],
+            ..Default::default()
+        };
+        let sort = SortSpec {
+            keys: vec![
+                SortKey {
+                    field: SortField::Name,
+                    descending: false,
+                },
+                SortKey {
+                    field: SortField::Weight,
+                    descending: true,
+                },
+            ],
+        };
+        let pagination = Pagination {
+            page: 1,
+            page_size: 2,
+        };
+        let items = CatalogService::<Pokemon>::query_items(pool, Query(pagination), &filter, &sort)
+            .await
+            .unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].id, ids[0]);
+        let sort = SortSpec {
+            keys: vec![SortKey {
+                field: SortField::Name,
+                descending: true,
+            }],
+        };
+        let items = CatalogService::<Pokemon>::query_items(
+            pool,
+            Query(Pagination::default()),
+            &filter,
+            &sort,
+        )
+        .await
+        .unwrap();
+        let found: Vec<i32> = items.iter().map(|item| item.id).collect();
+        assert_eq!(found, ids);
+
+        sqlx::query!("DELETE FROM pokemon WHERE id = ANY($1)", &ids[..])
+            .execute(pool)
+            .await
+            .unwrap();
+    }
 }
@@ -1,4 +1,7 @@
-use crate::common::entity::Pagination;
+use crate::catalog::entity::SearchHit;
+use crate::common::entity::{
+    Cursor, ItemFilter, Keyset, Pagination, SortField, SortSpec, SortValue,
+};
 use async_trait::async_trait;
 use axum::extract::Query;
 use serde::de::DeserializeOwned;
@@ -14,17 +17,112 @@ pub trait HasCatalogService: 'static {
     // Send is required for async future to be pass around
     type Item: Send + DeserializeOwned + Serialize;
 
-    async fn query_items_count(pool: &PgPool) -> Result<usize, sqlx::Error>;
+    fn sort_value(item: &Self::Item, field: SortField) -> SortValue;
+
+    /// The position of the item in a listing with the sort
+    fn cursor(item: &Self::Item, sort: &SortSpec) -> Cursor {
+        let values = sort
+            .keys_with_tiebreak()
+            .iter()
+            .map(|key| Self::sort_value(item, key.field))
+            .collect();
+        Cursor::new(sort, values)
+    }
+
+    async fn query_items_count(pool: &PgPool, filter: &ItemFilter) -> Result<usize, sqlx::Error>;
 
     async fn query_items(
         pool: &PgPool,
         pagination: Query<Pagination>,
+        filter: &ItemFilter,
+        sort: &SortSpec,
+    ) -> Result<Vec<Self::Item>, sqlx::Error>;
+
+    /// The `limit` items right after or before the cursor, in the order of the sort.
+    /// Unlike an offset, the page does not shift when items are added or removed
+    async fn query_items_by_keyset(
+        pool: &PgPool,
+        keyset: &Keyset,
+        limit: usize,
+        filter: &ItemFilter,
+        sort: &SortSpec,
+    ) -> Result<Vec<Self::Item>, sqlx::Error>;
+
+    async fn query_items_by_name_count(
+        pool: &PgPool,
+        name: &str,
+        filter: &ItemFilter,
+    ) -> Result<usize, sqlx::Error>;
+
+    /// Case-insensitive search of the names, matching a prefix or a similar name,
+    /// the most relevant items come first unless they are sorted
+    async fn query_items_by_name(
+        pool: &PgPool,
+        name: &str,
+        pagination: Query<Pagination>,
+        filter: &ItemFilter,
+        sort: &SortSpec,
     ) -> Result<Vec<Self::Item>, sqlx::Error>;
 
-    async fn query_items_by_name(pool: &PgPool, name: &str)
-        -> Result<Vec<Self::Item>, sqlx::Error>;
+    /// Full-text search in the websearch syntax of Postgres, i.e. quoted phrases,
+    /// `or` and `-` for negation, the best ranked items come first
+    async fn search_items(
+        pool: &PgPool,
+        query: &str,
+        pagination: Query<Pagination>,
+    ) -> Result<Vec<SearchHit<Self::Item>>, sqlx::Error>;
 
     async fn query_item(pool: &PgPool, id: u32) -> Result<Self::Item, sqlx::Error>;
 
     async fn query_item_by_name(pool: &PgPool, name: String) -> Result<Self::Item, sqlx::Error>;
 }
+
+pub const HIGHLIGHT_START: &str = "<mark>";
+pub const HIGHLIGHT_STOP: &str = "</mark>";
+
+/// The options of `ts_headline`, the delimiters are kept by `escape_snippet`
+pub const HEADLINE_OPTIONS: &str = "StartSel=<mark>, StopSel=</mark>, HighlightAll=true";
+
+/// Escape the text of a `ts_headline` snippet but its delimiters,
+/// so that it can be rendered as HTML whatever the items contain
+pub fn escape_snippet(snippet: &str) -> String {
+    let escape = |text: &str| {
+        text.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+            .replace('"', "&quot;")
+            .replace('\'', "&#x27;")
+    };
+    snippet
+        .split(HIGHLIGHT_START)
+        .map(|part| {
+            part.split(HIGHLIGHT_STOP)
+                .map(escape)
+                .collect::<Vec<_>>()
+                .join(HIGHLIGHT_STOP)
+        })
+        .collect::<Vec<_>>()
+        .join(HIGHLIGHT_START)
+}
+
+/// Escape the wildcards of LIKE, so that the input only matches itself
+pub fn escape_like(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snippet_is_escaped_but_the_highlights() {
+        let snippet = escape_snippet("<mark>fire</mark> <b>&</b> <mark>dragon</mark>");
+        assert_eq!(
+            snippet,
+            "<mark>fire</mark> &lt;b&gt;&amp;&lt;/b&gt; <mark>dragon</mark>"
+        );
+    }
+}
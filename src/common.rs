@@ -3,3 +3,4 @@ pub mod db;
 pub mod entity;
 pub mod error;
 pub mod filters;
+pub mod mail;
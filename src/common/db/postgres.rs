@@ -14,6 +14,21 @@ pub async fn get_postgres_pool() -> &'static PgPool {
     .await
 }
 
+/// The static pool cannot be shared between tests,
+/// every `#[tokio::test]` runs its own runtime and the pooled connections are bound to it
+#[cfg(test)]
+pub fn get_test_postgres_pool() -> PgPool {
+    let configuration = get_configuration().expect("Failed to read configuration.");
+    PgPoolOptions::new().connect_lazy_with(configuration.database.with_db())
+}
+
+/// A word that no other row has, so that a test only matches its own rows,
+/// not the ones of the other tests running at the same time or of the etl
+#[cfg(test)]
+pub fn unique_word() -> String {
+    format!("zq{}", &uuid::Uuid::new_v4().simple().to_string()[..8])
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1,10 +1,17 @@
 use async_trait::async_trait;
-use axum::extract::{FromRef, FromRequestParts, Query};
+use axum::extract::{ConnectInfo, FromRef, FromRequestParts, Query};
+use axum::http::header::USER_AGENT;
 use axum::http::request::Parts;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
+use std::net::{IpAddr, SocketAddr};
 
 use super::error::CommonError;
+use super::mail::SharedMailer;
+use crate::configuration::get_configuration;
 
 pub struct Pokemon {}
 
@@ -12,7 +19,9 @@ pub trait HasService {
     const SERVICE: Service;
 }
 
+// a missing param takes its default, e.g. only the page_size is given along with a cursor
 #[derive(Deserialize, Clone)]
+#[serde(default)]
 pub struct Pagination {
     pub page: usize,
     pub page_size: usize,
@@ -40,8 +49,30 @@ pub struct PaginationNavigationItem {
 }
 
 impl Pagination {
+    /// The page size limited to `max`, the offset and the total pages must be derived
+    /// from the clamped page instead of the requested one to stay consistent.
+    /// A page past the largest offset of the database is rejected instead of failing the query
+    pub fn clamped(&self, max: usize) -> Result<Self, CommonError> {
+        let pagination = Self {
+            page: self.page,
+            page_size: self.page_size.clamp(1, max),
+        };
+        pagination
+            .page
+            .checked_mul(pagination.page_size)
+            .and_then(|offset| i64::try_from(offset).ok())
+            .ok_or_else(|| {
+                CommonError::ValidationError(format!("The page {} is out of range", self.page))
+            })?;
+        Ok(pagination)
+    }
     pub fn offset(&self) -> usize {
-        self.page * self.page_size
+        self.page.saturating_mul(self.page_size)
+    }
+    /// The offset bound to a query, the one of a clamped page always fits,
+    /// any other is capped instead of wrapping around to a negative offset
+    pub fn sql_offset(&self) -> i64 {
+        i64::try_from(self.offset()).unwrap_or(i64::MAX)
     }
     pub fn limit(&self) -> usize {
         self.page_size
@@ -133,6 +164,7 @@ impl Pagination {
 #[derive(Clone, FromRef)]
 pub struct AppState {
     pub pool: PgPool,
+    pub mailer: SharedMailer,
 }
 
 #[derive(Debug, Serialize, Deserialize, sqlx::Type, PartialEq, Eq)]
@@ -190,10 +222,621 @@ where
     }
 }
 
+/// The filters of a listing, from repeated `type` and optional bounds,
+/// e.g. `type=fire&type=flying&min_weight=100&max_id=151`.
+/// An item must have all of the types, the bounds are inclusive and empty values are ignored
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ItemFilter {
+    pub types: Vec<String>,
+    pub min_id: Option<i32>,
+    pub max_id: Option<i32>,
+    pub min_height: Option<i16>,
+    pub max_height: Option<i16>,
+    pub min_weight: Option<i16>,
+    pub max_weight: Option<i16>,
+}
+
+const MAX_FILTER_TYPES: usize = 5;
+
+fn parse_bound<T: std::str::FromStr>(key: &str, value: &str) -> Result<Option<T>, CommonError> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Ok(None);
+    }
+    value
+        .parse()
+        .map(Some)
+        .map_err(|_| CommonError::ValidationError(format!("{key} must be a whole number in range")))
+}
+
+fn check_range<T: PartialOrd>(
+    name: &str,
+    min: &Option<T>,
+    max: &Option<T>,
+) -> Result<(), CommonError> {
+    match (min, max) {
+        (Some(min), Some(max)) if min > max => Err(CommonError::ValidationError(format!(
+            "min_{name} must not be greater than max_{name}"
+        ))),
+        _ => Ok(()),
+    }
+}
+
+impl ItemFilter {
+    pub fn from_pairs(pairs: Vec<(String, String)>) -> Result<Self, CommonError> {
+        let mut filter = Self::default();
+        for (key, value) in pairs {
+            match key.as_str() {
+                "type" => {
+                    let value = value.trim().to_lowercase();
+                    if value.is_empty() || filter.types.contains(&value) {
+                        continue;
+                    }
+                    if value.len() > 20
+                        || !value.chars().all(|c| c.is_ascii_lowercase() || c == '-')
+                    {
+                        return Err(CommonError::ValidationError(format!(
+                            "Unknown type {value}"
+                        )));
+                    }
+                    filter.types.push(value);
+                }
+                "min_id" => filter.min_id = parse_bound(&key, &value)?,
+                "max_id" => filter.max_id = parse_bound(&key, &value)?,
+                "min_height" => filter.min_height = parse_bound(&key, &value)?,
+                "max_height" => filter.max_height = parse_bound(&key, &value)?,
+                "min_weight" => filter.min_weight = parse_bound(&key, &value)?,
+                "max_weight" => filter.max_weight = parse_bound(&key, &value)?,
+                // the other params, e.g. the pagination, are extracted on their own
+                _ => {}
+            }
+        }
+        if filter.types.len() > MAX_FILTER_TYPES {
+            return Err(CommonError::ValidationError(format!(
+                "At most {MAX_FILTER_TYPES} types can be filtered"
+            )));
+        }
+        check_range("id", &filter.min_id, &filter.max_id)?;
+        check_range("height", &filter.min_height, &filter.max_height)?;
+        check_range("weight", &filter.min_weight, &filter.max_weight)?;
+        Ok(filter)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn has_type(&self, type_: &str) -> bool {
+        self.types.iter().any(|t| t == type_)
+    }
+
+    /// The filter as query params, each one prefixed by `&`, to be appended to the links of the pages.
+    /// The values do not need to be encoded, the types are validated and the bounds are numbers
+    pub fn query_string(&self) -> String {
+        let bounds = [
+            ("min_id", self.min_id.map(i64::from)),
+            ("max_id", self.max_id.map(i64::from)),
+            ("min_height", self.min_height.map(i64::from)),
+            ("max_height", self.max_height.map(i64::from)),
+            ("min_weight", self.min_weight.map(i64::from)),
+            ("max_weight", self.max_weight.map(i64::from)),
+        ];
+        let types = self.types.iter().map(|t| format!("&type={t}"));
+        let bounds = bounds
+            .into_iter()
+            .filter_map(|(key, value)| value.map(|value| format!("&{key}={value}")));
+        types.chain(bounds).collect()
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ItemFilter
+where
+    S: Send + Sync,
+{
+    type Rejection = CommonError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        // a list of pairs keeps the repeated keys, which a struct would reject
+        let Query(pairs) = Query::<Vec<(String, String)>>::from_request_parts(parts, state)
+            .await
+            .map_err(|_| CommonError::ValidationError("Cannot get query params".into()))?;
+        Self::from_pairs(pairs)
+    }
+}
+
+/// The fields a listing can be sorted by, only these can end up in an ORDER BY
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortField {
+    Id,
+    Name,
+    Height,
+    Weight,
+}
+
+impl SortField {
+    pub fn is_text(&self) -> bool {
+        matches!(self, SortField::Name)
+    }
+
+    pub fn column(&self) -> &'static str {
+        match self {
+            SortField::Id => "id",
+            SortField::Name => "name",
+            SortField::Height => "height",
+            SortField::Weight => "weight",
+        }
+    }
+}
+
+impl std::str::FromStr for SortField {
+    type Err = CommonError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "id" => Ok(SortField::Id),
+            "name" => Ok(SortField::Name),
+            "height" => Ok(SortField::Height),
+            "weight" => Ok(SortField::Weight),
+            _ => Err(CommonError::ValidationError(format!(
+                "Cannot sort by {s}, sort by id, name, height or weight"
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SortKey {
+    pub field: SortField,
+    pub descending: bool,
+}
+
+/// The order of a listing, from comma separated or repeated `sort` params,
+/// e.g. `sort=weight:desc,name`. A key is ascending unless it ends with `:desc`.
+/// The id always ends the order, so that the rows with equal keys
+/// keep the same order from a page to the next
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SortSpec {
+    pub keys: Vec<SortKey>,
+}
+
+impl SortSpec {
+    pub fn from_pairs(pairs: Vec<(String, String)>) -> Result<Self, CommonError> {
+        let mut keys: Vec<SortKey> = vec![];
+        let values = pairs
+            .iter()
+            .filter(|(key, _)| key == "sort")
+            .flat_map(|(_, value)| value.split(','))
+            .map(str::trim)
+            .filter(|value| !value.is_empty());
+        for value in values {
+            let (field, direction) = value.split_once(':').unwrap_or((value, "asc"));
+            let field: SortField = field.trim().to_lowercase().parse()?;
+            let descending = match direction.trim().to_lowercase().as_str() {
+                "asc" => false,
+                "desc" => true,
+                _ => {
+                    return Err(CommonError::ValidationError(format!(
+                        "Cannot sort by {value}, the direction is asc or desc"
+                    )))
+                }
+            };
+            if keys.iter().any(|key| key.field == field) {
+                return Err(CommonError::ValidationError(format!(
+                    "Cannot sort by {} twice",
+                    field.column()
+                )));
+            }
+            keys.push(SortKey { field, descending });
+        }
+        Ok(Self { keys })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// The keys with the id as the final tie-break, unless the id is already sorted by
+    pub fn keys_with_tiebreak(&self) -> Vec<SortKey> {
+        let mut keys = self.keys.clone();
+        if !keys.iter().any(|key| key.field == SortField::Id) {
+            keys.push(SortKey {
+                field: SortField::Id,
+                descending: false,
+            });
+        }
+        keys
+    }
+
+    /// The opposite order, with the tie-break, to read the rows before a cursor
+    pub fn reversed(&self) -> Self {
+        let keys = self
+            .keys_with_tiebreak()
+            .into_iter()
+            .map(|key| SortKey {
+                field: key.field,
+                descending: !key.descending,
+            })
+            .collect();
+        Self { keys }
+    }
+
+    /// The ORDER BY expressions, safe to be pushed into the SQL as the columns are whitelisted
+    pub fn order_by(&self) -> String {
+        self.keys_with_tiebreak()
+            .iter()
+            .map(|key| {
+                let direction = if key.descending { "DESC" } else { "ASC" };
+                format!("{} {direction}", key.field.column())
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// The value of the `sort` param, e.g. `weight:desc,name`
+    pub fn param(&self) -> String {
+        self.keys
+            .iter()
+            .map(|key| match key.descending {
+                true => format!("{}:desc", key.field.column()),
+                false => key.field.column().to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    pub fn is(&self, param: &str) -> bool {
+        self.param() == param
+    }
+
+    /// The sort as a query param prefixed by `&`, to be appended to the links of the pages
+    pub fn query_string(&self) -> String {
+        match self.is_empty() {
+            true => String::new(),
+            false => format!("&sort={}", self.param()),
+        }
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for SortSpec
+where
+    S: Send + Sync,
+{
+    type Rejection = CommonError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Query(pairs) = Query::<Vec<(String, String)>>::from_request_parts(parts, state)
+            .await
+            .map_err(|_| CommonError::ValidationError("Cannot get query params".into()))?;
+        Self::from_pairs(pairs)
+    }
+}
+
+/// The value of a sort key of an item
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum SortValue {
+    Int(i64),
+    Text(String),
+}
+
+/// A position in a sorted listing, the values of the sort keys of an item
+/// along with the sort they belong to.
+/// It is opaque to the clients, they get it from a response and send it back as it is
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Cursor {
+    sort: String,
+    values: Vec<SortValue>,
+}
+
+impl Cursor {
+    /// The values follow the keys of the sort, including the tie-break
+    pub fn new(sort: &SortSpec, values: Vec<SortValue>) -> Self {
+        Self {
+            sort: sort.param(),
+            values,
+        }
+    }
+
+    pub fn values(&self) -> &[SortValue] {
+        &self.values
+    }
+
+    pub fn encode(&self) -> String {
+        let json = serde_json::to_vec(self).expect("A cursor is always serializable");
+        URL_SAFE_NO_PAD.encode(json)
+    }
+
+    /// A cursor is only valid for the sort it was made with,
+    /// the values are checked as they end up in the query
+    pub fn decode(value: &str, sort: &SortSpec) -> Result<Self, CommonError> {
+        let invalid = || CommonError::ValidationError("Invalid cursor".into());
+        let json = URL_SAFE_NO_PAD.decode(value).map_err(|_| invalid())?;
+        let cursor: Self = serde_json::from_slice(&json).map_err(|_| invalid())?;
+        if cursor.sort != sort.param() {
+            return Err(CommonError::ValidationError(
+                "The cursor was made with another sort".into(),
+            ));
+        }
+        let keys = sort.keys_with_tiebreak();
+        let matches_keys = cursor.values.len() == keys.len()
+            && keys
+                .iter()
+                .zip(&cursor.values)
+                .all(|(key, value)| matches!(value, SortValue::Text(_)) == key.field.is_text());
+        if !matches_keys {
+            return Err(invalid());
+        }
+        Ok(cursor)
+    }
+}
+
+/// Where a page of a listing starts, from the `after` or the `before` param
+#[derive(Debug, Clone, PartialEq)]
+pub enum Keyset {
+    After(Cursor),
+    Before(Cursor),
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct QueryCursor {
+    pub after: Option<String>,
+    pub before: Option<String>,
+}
+
+impl QueryCursor {
+    pub fn keyset(&self, sort: &SortSpec) -> Result<Option<Keyset>, CommonError> {
+        match (&self.after, &self.before) {
+            (Some(_), Some(_)) => Err(CommonError::ValidationError(
+                "Use either after or before".into(),
+            )),
+            (Some(after), None) => Ok(Some(Keyset::After(Cursor::decode(after, sort)?))),
+            (None, Some(before)) => Ok(Some(Keyset::Before(Cursor::decode(before, sort)?))),
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for QueryCursor
+where
+    S: Send + Sync,
+{
+    type Rejection = CommonError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Query(query) = Query::<QueryCursor>::from_request_parts(parts, state)
+            .await
+            .map_err(|_| CommonError::ValidationError("Cannot get query params".into()))?;
+        Ok(query)
+    }
+}
+
+/// A full-text search, e.g. `"fire dragon" -water` or `grass or poison`
+#[derive(Debug, Deserialize)]
+pub struct QuerySearch {
+    pub q: String,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for QuerySearch
+where
+    S: Send + Sync,
+{
+    type Rejection = CommonError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let query = Query::<QuerySearch>::from_request_parts(parts, state)
+            .await
+            .map_err(|_| CommonError::ValidationError("Cannot get query param".into()))?;
+
+        let q = query.q.trim().to_string();
+        if q.is_empty() || q.len() > 200 {
+            return Err(CommonError::ValidationError(
+                "Search must be 1 to 200 characters long".into(),
+            ));
+        }
+        Ok(Self { q })
+    }
+}
+
+static TRUSTED_PROXIES: Lazy<Vec<IpAddr>> = Lazy::new(|| {
+    let configuration = get_configuration().expect("Failed to load configuration.");
+    configuration.security.trusted_proxies
+});
+
+/// The address of the client, the forwarding headers are only read from a trusted proxy,
+/// X-Forwarded-For is walked from the right, past the trusted proxies, to the first other hop.
+/// It is unknown without the peer address, i.e. if the app is not served with connect info
+fn client_ip(
+    peer: Option<IpAddr>,
+    forwarded_for: Option<&str>,
+    real_ip: Option<&str>,
+    trusted: &[IpAddr],
+) -> Option<IpAddr> {
+    let is_trusted = |ip: &IpAddr| trusted.contains(&ip.to_canonical());
+    let mut ip = peer?.to_canonical();
+    if !is_trusted(&ip) {
+        return Some(ip);
+    }
+    match forwarded_for {
+        Some(forwarded_for) => {
+            for hop in forwarded_for.rsplit(',') {
+                // a hop that is not an address was not written by a trusted proxy
+                let Ok(hop) = hop.trim().parse::<IpAddr>() else {
+                    break;
+                };
+                ip = hop.to_canonical();
+                if !is_trusted(&ip) {
+                    break;
+                }
+            }
+        }
+        None => {
+            if let Some(real_ip) = real_ip.and_then(|value| value.parse::<IpAddr>().ok()) {
+                ip = real_ip.to_canonical();
+            }
+        }
+    }
+    Some(ip)
+}
+
+/// Who is sending the request, both fields are best effort
+#[derive(Debug, Clone, Default)]
+pub struct ClientInfo {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ClientInfo
+where
+    S: Send + Sync,
+{
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let header = |name: &str| {
+            parts
+                .headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.trim().to_string())
+        };
+
+        let peer = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip());
+        let ip = client_ip(
+            peer,
+            header("x-forwarded-for").as_deref(),
+            header("x-real-ip").as_deref(),
+            &TRUSTED_PROXIES,
+        )
+        .map(|ip| ip.to_string());
+        let user_agent = header(USER_AGENT.as_str());
+
+        Ok(Self { ip, user_agent })
+    }
+}
+
+#[cfg(test)]
+mod test_item_filter {
+    use super::*;
+
+    fn pairs(query: &[(&str, &str)]) -> Vec<(String, String)> {
+        query
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn parse_filter() {
+        let filter = ItemFilter::from_pairs(pairs(&[
+            ("type", "Fire"),
+            ("type", "flying"),
+            ("type", "fire"),
+            ("min_weight", "100"),
+            ("max_height", ""),
+            ("max_id", "151"),
+            ("page", "2"),
+        ]))
+        .unwrap();
+        assert_eq!(filter.types, vec!["fire", "flying"]);
+        assert_eq!(filter.min_weight, Some(100));
+        assert_eq!(filter.max_height, None);
+        assert_eq!(filter.max_id, Some(151));
+        assert_eq!(
+            filter.query_string(),
+            "&type=fire&type=flying&max_id=151&min_weight=100"
+        );
+    }
+
+    #[test]
+    fn reject_invalid_filter() {
+        for query in [
+            [("min_weight", "heavy")],
+            [("max_height", "99999")],
+            [("type", "fire&x=1")],
+        ] {
+            assert!(ItemFilter::from_pairs(pairs(&query)).is_err());
+        }
+        let result = ItemFilter::from_pairs(pairs(&[("min_id", "10"), ("max_id", "1")]));
+        assert!(matches!(result, Err(CommonError::ValidationError(_))));
+    }
+}
+
+#[cfg(test)]
+mod test_sort_spec {
+    use super::*;
+
+    fn sort(values: &[&str]) -> Result<SortSpec, CommonError> {
+        let pairs = values
+            .iter()
+            .map(|value| ("sort".to_string(), value.to_string()))
+            .collect();
+        SortSpec::from_pairs(pairs)
+    }
+
+    #[test]
+    fn parse_sort() {
+        let spec = sort(&["weight:desc, name", "height:ASC"]).unwrap();
+        assert_eq!(spec.order_by(), "weight DESC, name ASC, height ASC, id ASC");
+        assert_eq!(spec.query_string(), "&sort=weight:desc,name,height");
+        let spec = sort(&["id:desc,name"]).unwrap();
+        assert_eq!(spec.order_by(), "id DESC, name ASC");
+        assert_eq!(sort(&[]).unwrap().order_by(), "id ASC");
+    }
+
+    #[test]
+    fn reject_invalid_sort() {
+        for values in [
+            &["created_at"][..],
+            &["name;drop table pokemon"][..],
+            &["name:up"][..],
+            &["name", "name:desc"][..],
+        ] {
+            assert!(matches!(sort(values), Err(CommonError::ValidationError(_))));
+        }
+    }
+}
+
 #[cfg(test)]
 mod test_pagination {
     use super::*;
 
+    #[test]
+    fn check_clamped() {
+        let pagination = Pagination {
+            page: 3,
+            page_size: 1000,
+        }
+        .clamped(100)
+        .unwrap();
+        assert_eq!(pagination.limit(), 100);
+        assert_eq!(pagination.sql_offset(), 300);
+        assert_eq!(pagination.get_total_pages(250), 3);
+        let empty = Pagination {
+            page: 0,
+            page_size: 0,
+        };
+        assert_eq!(empty.clamped(100).unwrap().limit(), 1);
+
+        // the offset would not fit a bigint
+        let far = Pagination {
+            page: usize::MAX / 2,
+            page_size: 16,
+        };
+        assert!(matches!(
+            far.clamped(100),
+            Err(CommonError::ValidationError(_))
+        ));
+        assert_eq!(far.sql_offset(), i64::MAX);
+    }
+
     #[test]
     fn check_one_page() {
         let pagination = Pagination {
@@ -274,3 +917,39 @@ mod test_pagination {
         assert_eq!(pages, vec![11, 12, 13, 14, 15, 16, 17, 18, 19]);
     }
 }
+
+#[cfg(test)]
+mod test_client_ip {
+    use super::*;
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn forwarding_headers_are_only_read_from_trusted_proxies() {
+        let proxy = ip("10.0.0.1");
+        let trusted = [proxy];
+        let forwarded = Some("1.2.3.4, 5.6.7.8");
+
+        // a client connecting directly cannot pick its own ip
+        let direct = client_ip(Some(ip("9.9.9.9")), forwarded, Some("1.1.1.1"), &trusted);
+        assert_eq!(direct, Some(ip("9.9.9.9")));
+
+        // the hop appended by the trusted proxy is the client, the spoofed ones are ignored
+        let proxied = client_ip(Some(proxy), forwarded, None, &trusted);
+        assert_eq!(proxied, Some(ip("5.6.7.8")));
+        let chained = client_ip(Some(proxy), Some("5.6.7.8, 10.0.0.1"), None, &trusted);
+        assert_eq!(chained, Some(ip("5.6.7.8")));
+        let real_ip = client_ip(Some(proxy), None, Some("1.1.1.1"), &trusted);
+        assert_eq!(real_ip, Some(ip("1.1.1.1")));
+
+        // garbage cannot end up in the sessions and audit events
+        let garbage = "x".repeat(100);
+        let invalid = client_ip(Some(proxy), Some(&garbage), None, &trusted);
+        assert_eq!(invalid, Some(proxy));
+        let mapped = client_ip(Some(ip("::ffff:10.0.0.1")), forwarded, None, &trusted);
+        assert_eq!(mapped, Some(ip("5.6.7.8")));
+        assert_eq!(client_ip(None, forwarded, None, &trusted), None);
+    }
+}
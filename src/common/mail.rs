@@ -0,0 +1,105 @@
+use crate::configuration::{MailBackend, MailSettings};
+use anyhow::Context;
+use async_trait::async_trait;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+#[derive(Debug)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Mail delivery is behind a trait so that a real provider can be plugged in
+/// without touching the code sending the emails
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: Email) -> anyhow::Result<()>;
+}
+
+pub type SharedMailer = Arc<dyn Mailer>;
+
+pub fn get_mailer(settings: &MailSettings) -> SharedMailer {
+    match settings.backend {
+        MailBackend::Log => Arc::new(LogMailer),
+        MailBackend::File => Arc::new(FileMailer {
+            sender: settings.sender.clone(),
+            directory: PathBuf::from(&settings.directory),
+        }),
+    }
+}
+
+/// Print the emails to the log, for local development
+pub struct LogMailer;
+
+#[async_trait]
+impl Mailer for LogMailer {
+    async fn send(&self, email: Email) -> anyhow::Result<()> {
+        tracing::info!(
+            "Sending email to {}\nSubject: {}\n\n{}",
+            email.to,
+            email.subject,
+            email.body
+        );
+        Ok(())
+    }
+}
+
+/// Write every email as a .eml file into a directory, so that tests and developers can read them
+pub struct FileMailer {
+    pub sender: String,
+    pub directory: PathBuf,
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, email: Email) -> anyhow::Result<()> {
+        tokio::fs::create_dir_all(&self.directory)
+            .await
+            .context("Failed to create mail directory")?;
+
+        let filename = format!(
+            "{}-{}.eml",
+            chrono::Utc::now().format("%Y%m%d%H%M%S"),
+            uuid::Uuid::new_v4()
+        );
+        let content = format!(
+            "From: {}\r\nTo: {}\r\nSubject: {}\r\n\r\n{}\r\n",
+            self.sender, email.to, email.subject, email.body
+        );
+        tokio::fs::write(self.directory.join(filename), content)
+            .await
+            .context("Failed to write email")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn file_mailer_writes_email() {
+        let directory = std::env::temp_dir().join(format!("mail-{}", uuid::Uuid::new_v4()));
+        let mailer = FileMailer {
+            sender: "noreply@example.com".to_string(),
+            directory: directory.clone(),
+        };
+        mailer
+            .send(Email {
+                to: "test@example.com".to_string(),
+                subject: "Hello".to_string(),
+                body: "World".to_string(),
+            })
+            .await
+            .unwrap();
+
+        let mut entries = std::fs::read_dir(&directory).unwrap();
+        let path = entries.next().unwrap().unwrap().path();
+        let content = std::fs::read_to_string(path).unwrap();
+        assert!(content.contains("To: test@example.com"));
+        assert!(content.contains("World"));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
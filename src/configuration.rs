@@ -4,12 +4,18 @@ use serde::Deserialize;
 use serde_aux::field_attributes::deserialize_number_from_string;
 use sqlx::postgres::{PgConnectOptions, PgSslMode};
 use sqlx::ConnectOptions;
+use std::net::IpAddr;
 
 #[derive(Deserialize)]
 pub struct Settings {
     pub application: ApplicationSettings,
     pub database: DatabaseSettings,
     pub security: SecuritySettings,
+    pub mail: MailSettings,
+    pub password: PasswordSettings,
+    // the login with an OpenID Connect provider is disabled if it is not set
+    #[serde(default)]
+    pub oidc: Option<OidcSettings>,
 }
 
 #[derive(Deserialize)]
@@ -18,6 +24,8 @@ pub struct ApplicationSettings {
     #[serde(deserialize_with = "deserialize_number_from_string")]
     pub port: u16,
     pub rust_log: String,
+    // used to build the links sent by email
+    pub base_url: String,
 }
 
 #[derive(Deserialize)]
@@ -34,6 +42,110 @@ pub struct DatabaseSettings {
 #[derive(Deserialize)]
 pub struct SecuritySettings {
     pub secret_key: String,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub access_token_ttl_minutes: i64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub refresh_token_ttl_days: i64,
+    pub require_verified_email: bool,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_login_failures: i32,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_login_failures_per_ip: i32,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub lockout_minutes: i64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub failure_window_minutes: i64,
+    pub jwt_issuer: String,
+    pub jwt_audience: String,
+    // the first key signs the new tokens, the others only verify, the secret_key signs if it is empty
+    #[serde(default)]
+    pub jwt_keys: Vec<JwtKeySettings>,
+    #[serde(default)]
+    pub registration: RegistrationPolicy,
+    // the X-Forwarded-For and X-Real-IP headers are only read from these addresses
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpAddr>,
+}
+
+/// Who can create an account at /register
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RegistrationPolicy {
+    #[default]
+    Open,
+    // only with an invite code generated by an admin
+    InviteOnly,
+    Closed,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct JwtKeySettings {
+    pub kid: String,
+    pub algorithm: JwtAlgorithm,
+    // a PEM file with the private key, or only the public key for a key that only verifies
+    pub file: String,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum JwtAlgorithm {
+    RS256,
+    EdDSA,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct OidcSettings {
+    // shown on the login button, e.g. "Sign in with Acme"
+    pub name: String,
+    // the provider is discovered at `<issuer_url>/.well-known/openid-configuration`
+    pub issuer_url: String,
+    pub client_id: String,
+    // a public client only relies on PKCE
+    #[serde(default)]
+    pub client_secret: Option<Secret<String>>,
+    #[serde(default = "default_oidc_scopes")]
+    pub scopes: String,
+    // create a user on the first login of an identity that is not linked yet
+    #[serde(default)]
+    pub auto_provision: bool,
+}
+
+fn default_oidc_scopes() -> String {
+    "openid email profile".to_string()
+}
+
+#[derive(Deserialize)]
+pub struct MailSettings {
+    pub backend: MailBackend,
+    pub sender: String,
+    // only used by the file backend
+    pub directory: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MailBackend {
+    Log,
+    File,
+}
+
+#[derive(Deserialize)]
+pub struct PasswordSettings {
+    pub algorithm: PasswordAlgorithm,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub bcrypt_cost: u32,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub argon2_memory_kib: u32,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub argon2_iterations: u32,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub argon2_parallelism: u32,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PasswordAlgorithm {
+    Argon2id,
+    Bcrypt,
 }
 
 pub fn get_environment() -> Environment {
@@ -1,10 +1,33 @@
 use crate::catalog::pages::{CatalogPages, HasCatalogPages};
-use crate::common::entity::{AppState, Pokemon};
-use crate::user_mgmt::auth::CurrentUser;
+use crate::common::entity::{AppState, Pagination, PaginationNavigation, Pokemon};
+use crate::common::filters;
+use crate::configuration::RegistrationPolicy;
+use crate::user_mgmt::admin::ADMIN_ROLE;
+use crate::user_mgmt::audit::{
+    query_audit_events, query_audit_events_count, AuditAction, AuditFilter, AuditRecord,
+    MAX_AUDIT_PAGE_SIZE,
+};
+use crate::user_mgmt::auth::{renew_expired_session, CurrentUser, Role};
+use crate::user_mgmt::csrf::CsrfToken;
+use crate::user_mgmt::entity::{User, UserFilter, UserStatus};
 use crate::user_mgmt::error::AuthError;
+use crate::user_mgmt::guard::{
+    AuditRead, RequirePermission, RequiredPermission, RolesWrite, UsersRead, UsersWrite,
+};
+use crate::user_mgmt::handler::{query_users, query_users_count, MAX_USERS_PAGE_SIZE};
+use crate::user_mgmt::invite::{query_invites, Invite, REGISTRATION_POLICY};
+use crate::user_mgmt::oidc::oidc_provider_name;
+use crate::user_mgmt::permission::query_roles;
+use crate::user_mgmt::session::{query_sessions, Session};
+use crate::user_mgmt::totp::is_totp_enabled;
+use crate::user_mgmt::verification::verify_email;
 use askama_axum::Template;
+use axum::extract::{Query, State};
+use axum::middleware::from_fn;
 use axum::routing::get;
 use axum::Router;
+use serde::Deserialize;
+use sqlx::PgPool;
 
 #[derive(Template)]
 #[template(path = "hello.html")]
@@ -18,11 +41,96 @@ async fn hello_world() -> HelloTemplate<'static> {
 
 #[derive(Template)]
 #[template(path = "login.html")]
-struct LoginTemplate;
+struct LoginTemplate {
+    // the name of the OpenID Connect provider, if the login with it is enabled
+    pub oidc_provider: Option<String>,
+    pub csrf_token: String,
+}
+
+async fn login_page(CsrfToken(csrf_token): CsrfToken) -> LoginTemplate {
+    LoginTemplate {
+        oidc_provider: oidc_provider_name(),
+        csrf_token,
+    }
+}
 
 #[derive(Template)]
 #[template(path = "register.html")]
-struct RegisterTemplate;
+struct RegisterTemplate {
+    pub registration_closed: bool,
+    pub invite_required: bool,
+    // prefilled from the link of the invite
+    pub invite_code: String,
+    pub csrf_token: String,
+}
+
+#[derive(Deserialize)]
+struct RegisterQuery {
+    invite: Option<String>,
+}
+
+async fn register_page(
+    CsrfToken(csrf_token): CsrfToken,
+    Query(query): Query<RegisterQuery>,
+) -> RegisterTemplate {
+    RegisterTemplate {
+        registration_closed: *REGISTRATION_POLICY == RegistrationPolicy::Closed,
+        invite_required: *REGISTRATION_POLICY == RegistrationPolicy::InviteOnly,
+        invite_code: query.invite.unwrap_or_default(),
+        csrf_token,
+    }
+}
+
+#[derive(Template)]
+#[template(path = "forgot_password.html")]
+struct ForgotPasswordTemplate {
+    pub csrf_token: String,
+}
+
+#[derive(Template)]
+#[template(path = "reset_password.html")]
+struct ResetPasswordTemplate {
+    pub token: String,
+    pub csrf_token: String,
+}
+
+#[derive(Template)]
+#[template(path = "magic_link.html")]
+struct MagicLinkTemplate {
+    // set when the page is opened from the link, the email is asked for otherwise
+    pub token: Option<String>,
+    pub csrf_token: String,
+}
+
+#[derive(Deserialize)]
+struct MagicLinkQuery {
+    token: Option<String>,
+}
+
+async fn magic_link_page(
+    CsrfToken(csrf_token): CsrfToken,
+    Query(query): Query<MagicLinkQuery>,
+) -> MagicLinkTemplate {
+    MagicLinkTemplate {
+        token: query.token,
+        csrf_token,
+    }
+}
+
+#[derive(Deserialize)]
+struct ResetPasswordQuery {
+    token: String,
+}
+
+async fn reset_password_page(
+    CsrfToken(csrf_token): CsrfToken,
+    Query(query): Query<ResetPasswordQuery>,
+) -> ResetPasswordTemplate {
+    ResetPasswordTemplate {
+        token: query.token,
+        csrf_token,
+    }
+}
 
 #[derive(Template)]
 #[template(path = "me.html")]
@@ -30,24 +138,227 @@ struct MeTemplate {
     pub name: String,
     pub email: String,
     pub role: String,
+    pub totp_enabled: bool,
+    pub can_manage_users: bool,
+    pub can_read_audit: bool,
+    pub can_invite_users: bool,
+    pub sessions: Vec<Session>,
+    // the OpenID Connect provider the account can be linked to, if it is enabled
+    pub oidc_provider: Option<String>,
+    pub csrf_token: String,
 }
 
-async fn me_page(user: CurrentUser) -> Result<MeTemplate, AuthError> {
+async fn me_page(
+    State(pool): State<PgPool>,
+    CsrfToken(csrf_token): CsrfToken,
+    user: CurrentUser,
+) -> Result<MeTemplate, AuthError> {
     Ok(MeTemplate {
         name: user.name.clone(),
         email: user.email.clone(),
         role: user.role.to_string(),
+        totp_enabled: is_totp_enabled(&pool, user.id).await?,
+        can_manage_users: user.has_permission(UsersRead::PERMISSION),
+        can_read_audit: user.has_permission(AuditRead::PERMISSION),
+        can_invite_users: user.has_permission(UsersWrite::PERMISSION),
+        sessions: query_sessions(&pool, user.id, user.session_id).await?,
+        oidc_provider: oidc_provider_name(),
+        csrf_token,
+    })
+}
+
+#[derive(Template)]
+#[template(path = "admin/users.html")]
+struct AdminUsersTemplate {
+    pub users: Vec<User>,
+    pub current_user_id: uuid::Uuid,
+    pub can_write_users: bool,
+    pub can_write_roles: bool,
+    // only a logged-in admin can impersonate, not an admin who is impersonating
+    pub can_impersonate: bool,
+    pub q: String,
+    pub status: String,
+    pub total_pages: usize,
+    pub page_size: usize,
+    pub current_page: usize,
+    pub pagination: PaginationNavigation,
+    pub csrf_token: String,
+}
+
+async fn admin_users_page(
+    State(pool): State<PgPool>,
+    CsrfToken(csrf_token): CsrfToken,
+    RequirePermission { user, .. }: RequirePermission<UsersRead>,
+    Query(filter): Query<UserFilter>,
+    pagination: Option<Query<Pagination>>,
+) -> Result<AdminUsersTemplate, AuthError> {
+    let pagination = pagination
+        .unwrap_or_default()
+        .clamped(MAX_USERS_PAGE_SIZE)?;
+    let users = query_users(&pool, &filter, &pagination).await?;
+    let count = query_users_count(&pool, &filter).await?;
+    let total_pages = pagination.get_total_pages(count);
+
+    Ok(AdminUsersTemplate {
+        users,
+        current_user_id: user.id,
+        can_write_users: user.has_permission(UsersWrite::PERMISSION),
+        can_write_roles: user.has_permission(RolesWrite::PERMISSION),
+        can_impersonate: user.role.satisfies(&Role::Admin) && user.impersonator_id.is_none(),
+        q: filter.q.unwrap_or_default(),
+        status: match filter.status {
+            Some(UserStatus::Active) => "active",
+            Some(UserStatus::Inactive) => "inactive",
+            None => "",
+        }
+        .to_string(),
+        total_pages,
+        page_size: pagination.page_size,
+        current_page: pagination.page,
+        pagination: pagination.get_navigation(total_pages, 5),
+        csrf_token,
+    })
+}
+
+#[derive(Template)]
+#[template(path = "admin/invites.html")]
+struct AdminInvitesTemplate {
+    pub invites: Vec<Invite>,
+    // the roles an invite can grant
+    pub roles: Vec<String>,
+    pub csrf_token: String,
+}
+
+async fn admin_invites_page(
+    State(pool): State<PgPool>,
+    CsrfToken(csrf_token): CsrfToken,
+    _: RequirePermission<UsersWrite>,
+) -> Result<AdminInvitesTemplate, AuthError> {
+    let roles = query_roles(&pool)
+        .await?
+        .into_iter()
+        .map(|role| role.name)
+        .filter(|name| name != ADMIN_ROLE)
+        .collect();
+    Ok(AdminInvitesTemplate {
+        invites: query_invites(&pool).await?,
+        roles,
+        csrf_token,
+    })
+}
+
+#[derive(Template)]
+#[template(path = "admin/audit.html")]
+struct AdminAuditTemplate {
+    pub events: Vec<AuditRecord>,
+    // the options of the filter and whether they are selected
+    pub actions: Vec<(&'static str, bool)>,
+    pub action: String,
+    pub email: String,
+    pub ip: String,
+    pub total_pages: usize,
+    pub page_size: usize,
+    pub current_page: usize,
+    pub pagination: PaginationNavigation,
+    pub csrf_token: String,
+}
+
+async fn admin_audit_page(
+    State(pool): State<PgPool>,
+    CsrfToken(csrf_token): CsrfToken,
+    _: RequirePermission<AuditRead>,
+    Query(filter): Query<AuditFilter>,
+    pagination: Option<Query<Pagination>>,
+) -> Result<AdminAuditTemplate, AuthError> {
+    let pagination = pagination
+        .unwrap_or_default()
+        .clamped(MAX_AUDIT_PAGE_SIZE)?;
+    let events = query_audit_events(&pool, &filter, &pagination).await?;
+    let count = query_audit_events_count(&pool, &filter).await?;
+    let total_pages = pagination.get_total_pages(count);
+
+    Ok(AdminAuditTemplate {
+        events,
+        actions: AuditAction::ALL
+            .iter()
+            .map(|action| (action.as_str(), filter.action == Some(*action)))
+            .collect(),
+        action: filter
+            .action
+            .map(|action| action.as_str().to_string())
+            .unwrap_or_default(),
+        email: filter.email.unwrap_or_default(),
+        ip: filter.ip.unwrap_or_default(),
+        total_pages,
+        page_size: pagination.page_size,
+        current_page: pagination.page,
+        pagination: pagination.get_navigation(total_pages, 5),
+        csrf_token,
     })
 }
 
+#[derive(Template)]
+#[template(path = "two_factor.html")]
+struct TwoFactorTemplate {
+    pub csrf_token: String,
+}
+
+#[derive(Template)]
+#[template(path = "verify_email.html")]
+struct VerifyEmailTemplate {
+    // None if the page is only opened to resend the link
+    pub verified: Option<bool>,
+    pub csrf_token: String,
+}
+
+#[derive(Deserialize)]
+struct VerifyEmailQuery {
+    token: Option<String>,
+}
+
+async fn verify_email_page(
+    State(pool): State<PgPool>,
+    CsrfToken(csrf_token): CsrfToken,
+    Query(query): Query<VerifyEmailQuery>,
+) -> VerifyEmailTemplate {
+    let verified = match query.token {
+        Some(token) => Some(
+            verify_email(&pool, &token)
+                .await
+                .inspect_err(|e| tracing::debug!("Failed to verify email: {e}"))
+                .is_ok(),
+        ),
+        None => None,
+    };
+    VerifyEmailTemplate {
+        verified,
+        csrf_token,
+    }
+}
+
 pub fn create_frontend_router() -> Router<AppState> {
     let pokemon_router = CatalogPages::<Pokemon>::create_router();
     Router::new()
         // Cannot think of a good home page, use the pokemon list for now
         .nest("/", pokemon_router.clone())
         .route("/hello", get(hello_world))
-        .route("/login", get(|| async { LoginTemplate }))
-        .route("/register", get(|| async { RegisterTemplate }))
+        .route("/login", get(login_page))
+        .route(
+            "/login/2fa",
+            get(|CsrfToken(csrf_token): CsrfToken| async { TwoFactorTemplate { csrf_token } }),
+        )
+        .route("/login/magic-link", get(magic_link_page))
+        .route("/register", get(register_page))
         .route("/me", get(me_page))
+        .route("/admin/users", get(admin_users_page))
+        .route("/admin/audit", get(admin_audit_page))
+        .route("/admin/invites", get(admin_invites_page))
+        .route("/verify-email", get(verify_email_page))
+        .route(
+            "/forgot-password",
+            get(|CsrfToken(csrf_token): CsrfToken| async { ForgotPasswordTemplate { csrf_token } }),
+        )
+        .route("/reset-password", get(reset_password_page))
         .nest("/pokemon", pokemon_router)
+        .layer(from_fn(renew_expired_session))
 }
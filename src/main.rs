@@ -1,21 +1,52 @@
 use axum::http::header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE, USER_AGENT};
-use axum::http::{Method, StatusCode, Uri};
+use axum::http::{HeaderName, Method, StatusCode, Uri};
+use axum::middleware::from_fn;
 use axum::response::IntoResponse;
-use axum::routing::{get, post};
+use axum::routing::{delete, get, post, put};
 use axum::Router;
 use myapp::frontend::create_frontend_router;
 use myapp::{
-    catalog::handler::{CatalogHandlers, HasCatalogHandlers},
+    catalog::handler::{
+        CatalogHandlers, HasCatalogHandlers, NEXT_CURSOR_HEADER, PREVIOUS_CURSOR_HEADER,
+    },
     common::{
         db::postgres::get_postgres_pool,
         entity::{AppState, Pokemon, Service},
+        mail::get_mailer,
     },
     configuration::get_configuration,
     user_mgmt::{
-        auth::{login, logout, me_handler},
+        admin::{
+            activate_user, deactivate_user, demote_user, logout_user, promote_user,
+            reset_user_password,
+        },
+        api_key::{create_api_key, revoke_api_key, show_api_keys},
+        audit::show_audit_events,
+        auth::{jwks, login, logout, me_handler, refresh, refresh_and_redirect, refresh_page},
+        csrf::{csrf_protection, CSRF_HEADER},
+        denylist::spawn_purge_task,
+        guard::{
+            require_permission, require_role, Admin, AuditRead, RolesRead, RolesWrite, UsersRead,
+            UsersWrite,
+        },
         handler::{create_user, show_users},
+        impersonation::{impersonate_user, stop_impersonating},
+        invite::{create_invite, revoke_invite, show_invites},
+        magic_link::{request_magic_link, verify_magic_link},
+        oidc::{oidc_callback, oidc_login},
+        password_reset::{forgot_password, reset_password},
+        permission::{add_user_role, remove_user_role, show_roles, show_user_roles},
+        profile::{change_password, delete_account, update_profile},
+        session::{logout_everywhere, revoke_session, show_sessions},
+        throttle::unlock_user,
+        totp::{
+            confirm_totp, disable_totp, enroll_totp, reset_user_totp, show_user_totp,
+            verify_second_factor,
+        },
+        verification::resend_verification,
     },
 };
+use std::net::SocketAddr;
 use std::time::Duration;
 use tokio::net::TcpListener;
 use tower_http::cors::CorsLayer;
@@ -26,9 +57,35 @@ use tower_livereload::LiveReloadLayer;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 #[shuttle_runtime::main]
-async fn shuttle_main() -> shuttle_axum::ShuttleAxum {
+async fn shuttle_main() -> Result<ConnectInfoService, shuttle_runtime::Error> {
     let app = create_main_router().await;
-    Ok(app.into())
+    Ok(ConnectInfoService(app))
+}
+
+/// shuttle_axum serves the router without the address of the peer,
+/// which the client ip of the throttles, the sessions and the audit events is taken from
+struct ConnectInfoService(Router);
+
+#[shuttle_runtime::async_trait]
+impl shuttle_runtime::Service for ConnectInfoService {
+    async fn bind(mut self, addr: SocketAddr) -> Result<(), shuttle_runtime::Error> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .map_err(shuttle_runtime::CustomError::new)?;
+        serve(listener, self.0)
+            .await
+            .map_err(shuttle_runtime::CustomError::new)?;
+        Ok(())
+    }
+}
+
+// the peer address is the client ip, unless it is a trusted proxy
+async fn serve(listener: TcpListener, app: Router) -> std::io::Result<()> {
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
 }
 
 /// This is the original main function before the shuttle runtime and deployment was added.
@@ -44,13 +101,11 @@ async fn local_axum_without_shuttle() {
         "{}:{}",
         configuration.application.host, configuration.application.port
     );
-    let addr = addr_str.parse::<std::net::SocketAddr>().unwrap();
+    let addr = addr_str.parse::<SocketAddr>().unwrap();
     let listener = TcpListener::bind(&addr).await.unwrap();
 
     tracing::debug!("Listening on {}", listener.local_addr().unwrap());
-    axum::serve(listener, app)
-        .await
-        .expect("Cannot start the server");
+    serve(listener, app).await.expect("Cannot start the server");
 }
 
 async fn create_main_router() -> Router {
@@ -85,8 +140,14 @@ async fn create_main_router() -> Router {
         .await
         .expect("Failed to run migrations");
 
+    // Forget the revoked access tokens once they have expired by themselves
+    spawn_purge_task(pool.clone(), Duration::from_secs(60 * 60));
+
     // Setup app state for the entire app
-    let state = AppState { pool };
+    let state = AppState {
+        pool,
+        mailer: get_mailer(&configuration.mail),
+    };
 
     // states the origins that are allowed to make requests to the server
     // not for the app itself but for any frontend that wants to make requests to the server
@@ -103,13 +164,60 @@ async fn create_main_router() -> Router {
             Method::PATCH,
             Method::DELETE,
         ])
-        .allow_headers([ACCEPT, AUTHORIZATION, CONTENT_TYPE, USER_AGENT])
+        .allow_headers([
+            ACCEPT,
+            AUTHORIZATION,
+            CONTENT_TYPE,
+            USER_AGENT,
+            HeaderName::from_static(CSRF_HEADER),
+        ])
+        .expose_headers([
+            HeaderName::from_static(NEXT_CURSOR_HEADER),
+            HeaderName::from_static(PREVIOUS_CURSOR_HEADER),
+        ])
         .allow_origin(origins)
         .allow_credentials(true);
 
-    let user_routes = Router::new()
-        .route("/", post(create_user))
-        .route("/", get(show_users));
+    // The permission guards only apply to the routes added before them,
+    // so the routes are grouped by the permission they require and merged afterwards
+    let user_read_routes = Router::new()
+        .route("/users", get(show_users))
+        .route("/users/:id/2fa", get(show_user_totp))
+        .route("/invites", get(show_invites))
+        .route_layer(require_permission::<UsersRead>(state.clone()));
+
+    let user_write_routes = Router::new()
+        .route("/users/:id/unlock", post(unlock_user))
+        .route("/users/:id/deactivate", post(deactivate_user))
+        .route("/users/:id/activate", post(activate_user))
+        .route("/users/:id/logout", post(logout_user))
+        .route("/users/:id/reset-password", post(reset_user_password))
+        .route("/users/:id/2fa", delete(reset_user_totp))
+        .route("/invites", post(create_invite))
+        .route("/invites/:id", delete(revoke_invite))
+        .route_layer(require_permission::<UsersWrite>(state.clone()));
+
+    let role_read_routes = Router::new()
+        .route("/roles", get(show_roles))
+        .route("/users/:id/roles", get(show_user_roles))
+        .route_layer(require_permission::<RolesRead>(state.clone()));
+
+    let role_write_routes = Router::new()
+        .route(
+            "/users/:id/roles/:role",
+            put(add_user_role).delete(remove_user_role),
+        )
+        .route("/users/:id/promote", post(promote_user))
+        .route("/users/:id/demote", post(demote_user))
+        .route_layer(require_permission::<RolesWrite>(state.clone()));
+
+    let admin_routes = Router::new()
+        .route("/users/:id/impersonate", post(impersonate_user))
+        .route_layer(require_role::<Admin>(state.clone()));
+
+    let audit_read_routes = Router::new()
+        .route("/audit-events", get(show_audit_events))
+        .route_layer(require_permission::<AuditRead>(state.clone()));
 
     // Note that the middleware is only applied to existing routes.
     // So you have to first add your routes (and / or fallback)
@@ -122,8 +230,36 @@ async fn create_main_router() -> Router {
         .route("/", get(root))
         .route("/auth/login", post(login))
         .route("/auth/logout", post(logout))
-        .route("/me", get(me_handler))
-        .nest("/users", user_routes)
+        .route("/auth/refresh", post(refresh).get(refresh_page))
+        .route("/auth/refresh/redirect", post(refresh_and_redirect))
+        // under /auth so that the refresh token of the admin is sent along
+        .route("/auth/impersonate/stop", post(stop_impersonating))
+        .route("/auth/magic-link", post(request_magic_link))
+        .route("/auth/magic-link/verify", post(verify_magic_link))
+        .route("/auth/oidc/login", get(oidc_login))
+        .route("/auth/oidc/callback", get(oidc_callback))
+        .route("/auth/2fa", post(verify_second_factor))
+        .route("/auth/2fa/enroll", post(enroll_totp))
+        .route("/auth/2fa/confirm", post(confirm_totp))
+        .route("/auth/2fa/disable", post(disable_totp))
+        .route("/auth/verify-email/resend", post(resend_verification))
+        .route("/auth/forgot-password", post(forgot_password))
+        .route("/auth/reset-password", post(reset_password))
+        .route("/me", get(me_handler).patch(update_profile))
+        .route("/me/password", put(change_password))
+        // not DELETE because htmx sends the parameters of a DELETE in the query string
+        .route("/me/delete", post(delete_account))
+        .route("/me/sessions", get(show_sessions).delete(logout_everywhere))
+        .route("/me/sessions/:id", delete(revoke_session))
+        .route("/api-keys", get(show_api_keys).post(create_api_key))
+        .route("/api-keys/:id", delete(revoke_api_key))
+        .route("/users", post(create_user))
+        .merge(user_read_routes)
+        .merge(user_write_routes)
+        .merge(role_read_routes)
+        .merge(role_write_routes)
+        .merge(audit_read_routes)
+        .merge(admin_routes)
         .nest(format!("/{}", Service::Pokemon).as_str(), pokemon_handlers)
         // timeout requests after 10 secs, returning 408 status code
         .layer(TimeoutLayer::new(Duration::from_secs(20)))
@@ -135,7 +271,11 @@ async fn create_main_router() -> Router {
         // serve the file in the "assets" directory under `/assets`
         .nest("/", base_frontend_app)
         .nest("/api/v1", base_api_app)
+        // the public keys for other services to verify our access tokens
+        .route("/.well-known/jwks.json", get(jwks))
         .with_state(state)
+        // every page gets a token, every state-changing request is checked
+        .layer(from_fn(csrf_protection))
         .layer(TraceLayer::new_for_http())
         .layer(cors)
         .nest_service("/assets", ServeDir::new("assets"))
@@ -161,3 +301,53 @@ async fn fallback(uri: Uri) -> impl IntoResponse {
     tracing::debug!(message);
     (StatusCode::NOT_FOUND, message)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::header::{CONTENT_TYPE, COOKIE, SET_COOKIE};
+    use myapp::user_mgmt::csrf::{CSRF_COOKIE, CSRF_HEADER};
+
+    // the router initializes the global tracing subscriber, so it is built only once
+    #[tokio::test]
+    async fn client_ip_is_recorded_through_the_served_router() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}/api/v1", listener.local_addr().unwrap());
+        let app = create_main_router().await;
+        tokio::spawn(serve(listener, app));
+
+        let client = reqwest::Client::new();
+        let response = client.get(&base).send().await.unwrap();
+        let token = response
+            .headers()
+            .get_all(SET_COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .find_map(|value| value.strip_prefix(&format!("{CSRF_COOKIE}=")))
+            .and_then(|value| value.split(';').next())
+            .unwrap()
+            .to_string();
+
+        let email = format!("{}@example.com", uuid::Uuid::new_v4().simple());
+        let response = client
+            .post(format!("{base}/auth/login"))
+            .header(COOKIE, format!("{CSRF_COOKIE}={token}"))
+            .header(CSRF_HEADER, &token)
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(format!("email={email}&password=wrong"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let pool = get_postgres_pool().await;
+        let ip: Option<String> = sqlx::query_scalar(
+            "SELECT ip FROM audit_events WHERE action = 'login.failed' AND metadata->>'email' = $1",
+        )
+        .bind(&email)
+        .fetch_one(pool)
+        .await
+        .unwrap();
+        assert_eq!(ip.as_deref(), Some("127.0.0.1"));
+    }
+}
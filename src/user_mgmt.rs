@@ -1,6 +1,26 @@
+pub mod admin;
+pub mod api_key;
+pub mod audit;
 pub mod auth;
+pub mod csrf;
+pub mod denylist;
+mod email;
 mod encryption;
 pub mod entity;
 pub mod error;
+pub mod guard;
 pub mod handler;
+pub mod impersonation;
+pub mod invite;
 mod jwt;
+pub mod magic_link;
+pub mod oidc;
+pub mod password_reset;
+pub mod permission;
+pub mod profile;
+mod refresh;
+pub mod session;
+pub mod throttle;
+mod token;
+pub mod totp;
+pub mod verification;
@@ -0,0 +1,306 @@
+use super::api_key::revoke_user_api_keys;
+use super::audit::{emit, AuditAction, AuditEvent};
+use super::auth::CurrentUser;
+use super::denylist::revoke_user_tokens;
+use super::error::AuthError;
+use super::handler::{query_user, update_password};
+use super::jwt::Role;
+use super::password_reset::{issue_password_reset_token, send_password_reset_email};
+use super::token::generate_token;
+use crate::common::entity::ClientInfo;
+use crate::common::error::CommonError;
+use crate::common::mail::SharedMailer;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use sqlx::{PgConnection, PgPool};
+
+// the named role that goes with is_superuser
+pub const ADMIN_ROLE: &str = "admin";
+
+async fn emit_admin_action(
+    pool: &PgPool,
+    client: &ClientInfo,
+    admin: &CurrentUser,
+    action: AuditAction,
+    user_id: uuid::Uuid,
+) {
+    emit(
+        pool,
+        client,
+        AuditEvent::new(action).actor(admin.id).target(user_id),
+    )
+    .await;
+}
+
+/// An admin cannot lock themselves out, another admin has to do it
+fn forbid_self(user: &CurrentUser, user_id: uuid::Uuid, action: &str) -> Result<(), AuthError> {
+    if user.id == user_id {
+        return Err(CommonError::ValidationError(format!("You cannot {action} yourself")).into());
+    }
+    Ok(())
+}
+
+async fn set_active(
+    conn: &mut PgConnection,
+    user_id: uuid::Uuid,
+    is_active: bool,
+) -> Result<(), AuthError> {
+    let result = sqlx::query!(
+        "UPDATE users SET is_active = $2 WHERE id = $1",
+        user_id,
+        is_active
+    )
+    .execute(conn)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Err(CommonError::NotFound.into());
+    }
+    Ok(())
+}
+
+/// The user is logged out as well, a deactivated user cannot log in until reactivated
+#[tracing::instrument(name = "Deactivating user", skip(pool, user, client), fields(admin = %user.email))]
+pub async fn deactivate_user(
+    State(pool): State<PgPool>,
+    user: CurrentUser,
+    client: ClientInfo,
+    Path(user_id): Path<uuid::Uuid>,
+) -> Result<StatusCode, AuthError> {
+    forbid_self(&user, user_id, "deactivate")?;
+    let mut tx = pool.begin().await?;
+    set_active(&mut tx, user_id, false).await?;
+    revoke_user_tokens(&mut tx, user_id).await?;
+    tx.commit().await?;
+    emit_admin_action(&pool, &client, &user, AuditAction::UserDeactivated, user_id).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[tracing::instrument(name = "Activating user", skip(pool, user, client), fields(admin = %user.email))]
+pub async fn activate_user(
+    State(pool): State<PgPool>,
+    user: CurrentUser,
+    client: ClientInfo,
+    Path(user_id): Path<uuid::Uuid>,
+) -> Result<StatusCode, AuthError> {
+    let mut conn = pool.acquire().await?;
+    set_active(&mut conn, user_id, true).await?;
+    emit_admin_action(&pool, &client, &user, AuditAction::UserActivated, user_id).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn set_superuser(
+    conn: &mut PgConnection,
+    user_id: uuid::Uuid,
+    is_superuser: bool,
+) -> Result<(), AuthError> {
+    let result = sqlx::query!(
+        "UPDATE users SET is_superuser = $2 WHERE id = $1",
+        user_id,
+        is_superuser
+    )
+    .execute(&mut *conn)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Err(CommonError::NotFound.into());
+    }
+
+    // the admin role grants the permissions, is_superuser the role of the token
+    if is_superuser {
+        sqlx::query!(
+            "INSERT INTO user_roles (user_id, role_id)
+            SELECT $1, roles.id FROM roles WHERE roles.name = $2
+            ON CONFLICT DO NOTHING",
+            user_id,
+            ADMIN_ROLE
+        )
+        .execute(conn)
+        .await?;
+    } else {
+        sqlx::query!(
+            "DELETE FROM user_roles
+            USING roles
+            WHERE user_roles.role_id = roles.id AND user_roles.user_id = $1 AND roles.name = $2",
+            user_id,
+            ADMIN_ROLE
+        )
+        .execute(conn)
+        .await?;
+    }
+    Ok(())
+}
+
+/// The new admin gets the admin role with the next token, e.g. after a refresh.
+/// Only an admin can promote, roles:write alone would let a user make themselves superuser
+#[tracing::instrument(name = "Promoting user", skip(pool, user, client), fields(admin = %user.email))]
+pub async fn promote_user(
+    State(pool): State<PgPool>,
+    user: CurrentUser,
+    client: ClientInfo,
+    Path(user_id): Path<uuid::Uuid>,
+) -> Result<StatusCode, AuthError> {
+    if user.role != Role::Admin {
+        return Err(AuthError::Forbidden);
+    }
+    forbid_self(&user, user_id, "promote")?;
+    let mut tx = pool.begin().await?;
+    set_superuser(&mut tx, user_id, true).await?;
+    tx.commit().await?;
+    emit_admin_action(&pool, &client, &user, AuditAction::UserPromoted, user_id).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The demotion takes effect immediately, the current user is resolved on every request
+#[tracing::instrument(name = "Demoting user", skip(pool, user, client), fields(admin = %user.email))]
+pub async fn demote_user(
+    State(pool): State<PgPool>,
+    user: CurrentUser,
+    client: ClientInfo,
+    Path(user_id): Path<uuid::Uuid>,
+) -> Result<StatusCode, AuthError> {
+    forbid_self(&user, user_id, "demote")?;
+    let mut tx = pool.begin().await?;
+    set_superuser(&mut tx, user_id, false).await?;
+    tx.commit().await?;
+    emit_admin_action(&pool, &client, &user, AuditAction::UserDemoted, user_id).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Log the user out everywhere, e.g. when a device is lost
+#[tracing::instrument(name = "Logging user out", skip(pool, admin, client), fields(admin = %admin.email))]
+pub async fn logout_user(
+    State(pool): State<PgPool>,
+    admin: CurrentUser,
+    client: ClientInfo,
+    Path(user_id): Path<uuid::Uuid>,
+) -> Result<StatusCode, AuthError> {
+    let user = query_user(&pool, user_id).await?;
+    let mut conn = pool.acquire().await?;
+    revoke_user_tokens(&mut conn, user.id).await?;
+    emit_admin_action(&pool, &client, &admin, AuditAction::UserLoggedOut, user.id).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The current password is replaced by a random one that nobody knows,
+/// so the user has to choose a new password with the link sent to them
+#[tracing::instrument(name = "Forcing password reset", skip(pool, mailer, admin, client), fields(admin = %admin.email))]
+pub async fn reset_user_password(
+    State(pool): State<PgPool>,
+    State(mailer): State<SharedMailer>,
+    admin: CurrentUser,
+    client: ClientInfo,
+    Path(user_id): Path<uuid::Uuid>,
+) -> Result<StatusCode, AuthError> {
+    let user = query_user(&pool, user_id).await?;
+
+    let mut tx = pool.begin().await?;
+    update_password(&mut tx, user.id, generate_token()).await?;
+    revoke_user_tokens(&mut tx, user.id).await?;
+    revoke_user_api_keys(&mut tx, user.id).await?;
+    let token = issue_password_reset_token(&mut tx, user.id).await?;
+    tx.commit().await?;
+    emit_admin_action(
+        &pool,
+        &client,
+        &admin,
+        AuditAction::UserPasswordReset,
+        user.id,
+    )
+    .await;
+    send_password_reset_email(&mailer, user.email, &token).await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::db::postgres::get_test_postgres_pool;
+    use crate::user_mgmt::auth::get_current_user_from_id;
+    use crate::user_mgmt::entity::CreateUser;
+    use crate::user_mgmt::handler::insert_user;
+
+    #[tokio::test]
+    async fn admin_cannot_deactivate_themselves() {
+        let pool = get_test_postgres_pool();
+        let mut conn = pool.acquire().await.unwrap();
+        let payload = CreateUser {
+            email: format!("{}@example.com", uuid::Uuid::new_v4()),
+            password: "password".to_string(),
+            name: "Inactive".to_string(),
+        };
+        let user_id = insert_user(&mut conn, payload).await.unwrap();
+
+        let admin_id = uuid::Uuid::parse_str("93cb6fd1-0bef-473a-87f7-655386777578").unwrap();
+        let admin = get_current_user_from_id(&pool, &admin_id).await;
+        let result = deactivate_user(
+            State(pool.clone()),
+            admin,
+            ClientInfo::default(),
+            Path(admin_id),
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(AuthError::CommonError(CommonError::ValidationError(_)))
+        ));
+
+        let admin = get_current_user_from_id(&pool, &admin_id).await;
+        deactivate_user(
+            State(pool.clone()),
+            admin,
+            ClientInfo::default(),
+            Path(user_id),
+        )
+        .await
+        .unwrap();
+        assert!(!query_user(&pool, user_id).await.unwrap().is_active);
+
+        let admin = get_current_user_from_id(&pool, &admin_id).await;
+        activate_user(
+            State(pool.clone()),
+            admin,
+            ClientInfo::default(),
+            Path(user_id),
+        )
+        .await
+        .unwrap();
+        assert!(query_user(&pool, user_id).await.unwrap().is_active);
+
+        // the events are recorded with the admin as the actor
+        let actions = sqlx::query_scalar!(
+            "SELECT action FROM audit_events WHERE actor_id = $1 AND target_id = $2
+            ORDER BY id",
+            admin_id,
+            user_id
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        assert_eq!(actions, vec!["user.deactivated", "user.activated"]);
+    }
+
+    #[tokio::test]
+    async fn user_cannot_promote_themselves() {
+        let pool = get_test_postgres_pool();
+        let mut conn = pool.acquire().await.unwrap();
+        let payload = CreateUser {
+            email: format!("{}@example.com", uuid::Uuid::new_v4()),
+            password: "password".to_string(),
+            name: "Roles writer".to_string(),
+        };
+        let user_id = insert_user(&mut conn, payload).await.unwrap();
+
+        // roles:write is not enough to become an admin
+        let mut user = get_current_user_from_id(&pool, &user_id).await;
+        user.permissions.push("roles:write".to_string());
+        let result = promote_user(
+            State(pool.clone()),
+            user,
+            ClientInfo::default(),
+            Path(user_id),
+        )
+        .await;
+        assert!(matches!(result, Err(AuthError::Forbidden)));
+        assert!(!query_user(&pool, user_id).await.unwrap().is_superuser);
+    }
+}
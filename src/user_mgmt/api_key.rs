@@ -0,0 +1,242 @@
+use super::auth::CurrentUser;
+use super::error::AuthError;
+use super::jwt::{ApiKeyScopes, Claims};
+use super::token::{generate_token, hash_token};
+use crate::common::error::CommonError;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::Json;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgConnection, PgPool};
+
+// tells an API key apart from a jwt in the authorization header
+const API_KEY_PREFIX: &str = "pak_";
+// the number of characters shown to the user to recognize a key
+const KEY_PREFIX_LEN: usize = 12;
+const MAX_NAME_LEN: usize = 64;
+const MAX_EXPIRES_IN_DAYS: i64 = 365;
+
+#[derive(Debug, Serialize)]
+pub struct ApiKey {
+    pub id: uuid::Uuid,
+    pub name: String,
+    pub key_prefix: String,
+    pub scopes: Vec<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+pub struct CreateApiKey {
+    pub name: String,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    // the key never expires if it is not set
+    pub expires_in_days: Option<i64>,
+}
+
+/// The key itself is only returned once, when it is created
+#[derive(Debug, Serialize)]
+pub struct CreatedApiKey {
+    pub key: String,
+    #[serde(flatten)]
+    pub api_key: ApiKey,
+}
+
+pub fn is_api_key(token: &str) -> bool {
+    token.starts_with(API_KEY_PREFIX)
+}
+
+/// Resolve the claims of an API key and record its use,
+/// it fails if the key is unknown, expired or revoked
+pub async fn authenticate_api_key(pool: &PgPool, key: &str) -> Result<Claims, AuthError> {
+    let row = sqlx::query!(
+        "UPDATE api_keys SET last_used_at = current_timestamp
+        WHERE key_hash = $1 AND revoked_at IS NULL
+            AND (expires_at IS NULL OR expires_at > current_timestamp)
+        RETURNING id, user_id, scopes, created_at, expires_at",
+        hash_token(key),
+    )
+    .fetch_optional(pool)
+    .await?
+    .ok_or(AuthError::InvalidToken)?;
+
+    let scopes = ApiKeyScopes {
+        id: row.id,
+        scopes: row.scopes,
+    };
+    Ok(Claims::for_api_key(
+        row.user_id,
+        scopes,
+        row.created_at,
+        row.expires_at,
+    ))
+}
+
+#[tracing::instrument(name = "Creating API key", skip(pool, user, payload), fields(email = %user.email))]
+pub async fn create_api_key(
+    State(pool): State<PgPool>,
+    user: CurrentUser,
+    Json(payload): Json<CreateApiKey>,
+) -> Result<(StatusCode, Json<CreatedApiKey>), AuthError> {
+    // otherwise a leaked key could be used to mint keys that outlive it
+    if user.api_key_id.is_some() {
+        return Err(AuthError::Forbidden);
+    }
+    user.ensure_not_impersonated()?;
+    let name = payload.name.trim();
+    if name.is_empty() || name.len() > MAX_NAME_LEN {
+        return Err(CommonError::ValidationError(format!(
+            "The name must have between 1 and {MAX_NAME_LEN} characters"
+        ))
+        .into());
+    }
+    // a key can only be restricted, it never has more permissions than its user
+    if let Some(scope) = payload.scopes.iter().find(|s| !user.has_permission(s)) {
+        return Err(CommonError::ValidationError(format!(
+            "You do not have the permission {scope}"
+        ))
+        .into());
+    }
+    let expires_at = match payload.expires_in_days {
+        Some(days) if (1..=MAX_EXPIRES_IN_DAYS).contains(&days) => {
+            Some(Utc::now() + Duration::days(days))
+        }
+        Some(_) => {
+            return Err(CommonError::ValidationError(format!(
+                "A key expires in 1 to {MAX_EXPIRES_IN_DAYS} days"
+            ))
+            .into())
+        }
+        None => None,
+    };
+
+    let key = format!("{API_KEY_PREFIX}{}", generate_token());
+    let api_key = sqlx::query_as!(
+        ApiKey,
+        "INSERT INTO api_keys (user_id, name, key_prefix, key_hash, scopes, expires_at)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        RETURNING id, name, key_prefix, scopes, expires_at, created_at, last_used_at",
+        user.id,
+        name,
+        &key[..KEY_PREFIX_LEN],
+        hash_token(&key),
+        &payload.scopes,
+        expires_at,
+    )
+    .fetch_one(&pool)
+    .await
+    .inspect_err(|e| tracing::error!("Failed to insert API key: {e}"))?;
+
+    Ok((StatusCode::CREATED, Json(CreatedApiKey { key, api_key })))
+}
+
+/// The keys that can still be used, the expired ones are listed so that they can be replaced
+pub async fn show_api_keys(
+    State(pool): State<PgPool>,
+    user: CurrentUser,
+) -> Result<Json<Vec<ApiKey>>, AuthError> {
+    let api_keys = sqlx::query_as!(
+        ApiKey,
+        "SELECT id, name, key_prefix, scopes, expires_at, created_at, last_used_at
+        FROM api_keys
+        WHERE user_id = $1 AND revoked_at IS NULL
+        ORDER BY created_at DESC",
+        user.id,
+    )
+    .fetch_all(&pool)
+    .await?;
+    Ok(Json(api_keys))
+}
+
+#[tracing::instrument(name = "Revoking API key", skip(pool, user), fields(email = %user.email))]
+pub async fn revoke_api_key(
+    State(pool): State<PgPool>,
+    user: CurrentUser,
+    Path(key_id): Path<uuid::Uuid>,
+) -> Result<StatusCode, AuthError> {
+    user.ensure_not_impersonated()?;
+    let result = sqlx::query!(
+        "UPDATE api_keys SET revoked_at = current_timestamp
+        WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL",
+        key_id,
+        user.id,
+    )
+    .execute(&pool)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Err(CommonError::NotFound.into());
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Only when the password is reset, the account may be in the wrong hands.
+/// The keys are checked against the database instead of the denylist,
+/// they are revoked for good so that the user sees it in the list of keys
+pub async fn revoke_user_api_keys(
+    conn: &mut PgConnection,
+    user_id: uuid::Uuid,
+) -> Result<(), AuthError> {
+    sqlx::query!(
+        "UPDATE api_keys SET revoked_at = current_timestamp
+        WHERE user_id = $1 AND revoked_at IS NULL",
+        user_id
+    )
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::db::postgres::get_test_postgres_pool;
+    use crate::user_mgmt::auth::get_current_user_from_id;
+
+    #[tokio::test]
+    async fn api_key_is_scoped_and_revocable() {
+        let pool = get_test_postgres_pool();
+        let admin_id = uuid::Uuid::parse_str("93cb6fd1-0bef-473a-87f7-655386777578").unwrap();
+        let admin = get_current_user_from_id(&pool, &admin_id).await;
+        let payload = CreateApiKey {
+            name: "script".to_string(),
+            scopes: vec!["users:read".to_string()],
+            expires_in_days: Some(1),
+        };
+        let (_, Json(created)) = create_api_key(State(pool.clone()), admin, Json(payload))
+            .await
+            .unwrap();
+        assert!(is_api_key(&created.key));
+        assert!(created.key.starts_with(&created.api_key.key_prefix));
+
+        let claims = authenticate_api_key(&pool, &created.key).await.unwrap();
+        assert_eq!(claims.sub, admin_id);
+        let api_key = claims.api_key.unwrap();
+        assert_eq!(api_key.scopes, vec!["users:read".to_string()]);
+
+        let admin = get_current_user_from_id(&pool, &admin_id).await;
+        revoke_api_key(State(pool.clone()), admin, Path(api_key.id))
+            .await
+            .unwrap();
+        assert!(authenticate_api_key(&pool, &created.key).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn api_key_scopes_are_limited_to_user_permissions() {
+        let pool = get_test_postgres_pool();
+        let user_id = uuid::Uuid::parse_str("00571729-af3e-4cb7-a693-ca0c82efed77").unwrap();
+        let user = get_current_user_from_id(&pool, &user_id).await;
+        let payload = CreateApiKey {
+            name: "script".to_string(),
+            scopes: vec!["roles:write".to_string()],
+            expires_in_days: None,
+        };
+        let result = create_api_key(State(pool.clone()), user, Json(payload)).await;
+        assert!(matches!(
+            result,
+            Err(AuthError::CommonError(CommonError::ValidationError(_)))
+        ));
+    }
+}
@@ -0,0 +1,300 @@
+use super::entity::empty_as_none;
+use super::error::AuthError;
+use crate::common::entity::{ClientInfo, Pagination};
+use axum::extract::{Json, Query, State};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+// a page of the audit log cannot be used to dump it at once
+pub const MAX_AUDIT_PAGE_SIZE: usize = 100;
+
+/// What happened, stored as `<resource>.<action>`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AuditAction {
+    #[serde(rename = "login.succeeded")]
+    LoginSucceeded,
+    #[serde(rename = "login.failed")]
+    LoginFailed,
+    #[serde(rename = "logout")]
+    Logout,
+    #[serde(rename = "user.created")]
+    UserCreated,
+    #[serde(rename = "user.deactivated")]
+    UserDeactivated,
+    #[serde(rename = "user.activated")]
+    UserActivated,
+    #[serde(rename = "user.promoted")]
+    UserPromoted,
+    #[serde(rename = "user.demoted")]
+    UserDemoted,
+    #[serde(rename = "user.logged_out")]
+    UserLoggedOut,
+    #[serde(rename = "user.password_reset")]
+    UserPasswordReset,
+    #[serde(rename = "user.unlocked")]
+    UserUnlocked,
+    #[serde(rename = "user.totp_reset")]
+    UserTotpReset,
+    #[serde(rename = "user.impersonated")]
+    UserImpersonated,
+    #[serde(rename = "user.impersonation_stopped")]
+    UserImpersonationStopped,
+    #[serde(rename = "invite.created")]
+    InviteCreated,
+    #[serde(rename = "invite.revoked")]
+    InviteRevoked,
+    #[serde(rename = "role.assigned")]
+    RoleAssigned,
+    #[serde(rename = "role.unassigned")]
+    RoleUnassigned,
+}
+
+impl AuditAction {
+    pub const ALL: [AuditAction; 18] = [
+        AuditAction::LoginSucceeded,
+        AuditAction::LoginFailed,
+        AuditAction::Logout,
+        AuditAction::UserCreated,
+        AuditAction::UserDeactivated,
+        AuditAction::UserActivated,
+        AuditAction::UserPromoted,
+        AuditAction::UserDemoted,
+        AuditAction::UserLoggedOut,
+        AuditAction::UserPasswordReset,
+        AuditAction::UserUnlocked,
+        AuditAction::UserTotpReset,
+        AuditAction::UserImpersonated,
+        AuditAction::UserImpersonationStopped,
+        AuditAction::InviteCreated,
+        AuditAction::InviteRevoked,
+        AuditAction::RoleAssigned,
+        AuditAction::RoleUnassigned,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditAction::LoginSucceeded => "login.succeeded",
+            AuditAction::LoginFailed => "login.failed",
+            AuditAction::Logout => "logout",
+            AuditAction::UserCreated => "user.created",
+            AuditAction::UserDeactivated => "user.deactivated",
+            AuditAction::UserActivated => "user.activated",
+            AuditAction::UserPromoted => "user.promoted",
+            AuditAction::UserDemoted => "user.demoted",
+            AuditAction::UserLoggedOut => "user.logged_out",
+            AuditAction::UserPasswordReset => "user.password_reset",
+            AuditAction::UserUnlocked => "user.unlocked",
+            AuditAction::UserTotpReset => "user.totp_reset",
+            AuditAction::UserImpersonated => "user.impersonated",
+            AuditAction::UserImpersonationStopped => "user.impersonation_stopped",
+            AuditAction::InviteCreated => "invite.created",
+            AuditAction::InviteRevoked => "invite.revoked",
+            AuditAction::RoleAssigned => "role.assigned",
+            AuditAction::RoleUnassigned => "role.unassigned",
+        }
+    }
+}
+
+/// An event to record, the client is added when it is emitted
+pub struct AuditEvent {
+    pub action: AuditAction,
+    pub actor_id: Option<uuid::Uuid>,
+    pub target_id: Option<uuid::Uuid>,
+    pub metadata: serde_json::Value,
+}
+
+impl AuditEvent {
+    pub fn new(action: AuditAction) -> Self {
+        Self {
+            action,
+            actor_id: None,
+            target_id: None,
+            metadata: serde_json::json!({}),
+        }
+    }
+
+    pub fn actor(mut self, actor_id: uuid::Uuid) -> Self {
+        self.actor_id = Some(actor_id);
+        self
+    }
+
+    pub fn target(mut self, target_id: uuid::Uuid) -> Self {
+        self.target_id = Some(target_id);
+        self
+    }
+
+    pub fn metadata(mut self, metadata: serde_json::Value) -> Self {
+        self.metadata = metadata;
+        self
+    }
+}
+
+/// Record an event, a failure is logged instead so that it never fails the action it records
+pub async fn emit(pool: &PgPool, client: &ClientInfo, event: AuditEvent) {
+    let _ = sqlx::query!(
+        "INSERT INTO audit_events (actor_id, action, target_id, ip, user_agent, metadata)
+        VALUES ($1, $2, $3, $4, $5, $6)",
+        event.actor_id,
+        event.action.as_str(),
+        event.target_id,
+        client.ip,
+        client.user_agent,
+        event.metadata,
+    )
+    .execute(pool)
+    .await
+    .inspect_err(|e| {
+        tracing::error!(
+            "Failed to record audit event {}: {e}",
+            event.action.as_str()
+        )
+    });
+}
+
+/// A recorded event, the emails are None if the users were deleted since
+#[derive(Debug, Serialize)]
+pub struct AuditRecord {
+    pub id: i64,
+    pub action: String,
+    pub actor_id: Option<uuid::Uuid>,
+    pub actor_email: Option<String>,
+    pub target_id: Option<uuid::Uuid>,
+    pub target_email: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub metadata: serde_json::Value,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct AuditFilter {
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub action: Option<AuditAction>,
+    // the email of the actor or the target, or the one of a failed login
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub email: Option<String>,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub ip: Option<String>,
+}
+
+pub async fn query_audit_events(
+    pool: &PgPool,
+    filter: &AuditFilter,
+    pagination: &Pagination,
+) -> Result<Vec<AuditRecord>, AuthError> {
+    let pagination = pagination.clamped(MAX_AUDIT_PAGE_SIZE)?;
+    let events = sqlx::query_as!(
+        AuditRecord,
+        r#"SELECT audit_events.id, audit_events.action,
+            audit_events.actor_id, actors.email AS "actor_email?",
+            audit_events.target_id, targets.email AS "target_email?",
+            audit_events.ip, audit_events.user_agent, audit_events.metadata, audit_events.created_at
+        FROM audit_events
+        LEFT JOIN users AS actors ON actors.id = audit_events.actor_id
+        LEFT JOIN users AS targets ON targets.id = audit_events.target_id
+        WHERE ($1::text IS NULL OR audit_events.action = $1)
+            AND ($2::text IS NULL OR lower(actors.email) = lower($2)
+                OR lower(targets.email) = lower($2)
+                OR lower(audit_events.metadata->>'email') = lower($2))
+            AND ($3::text IS NULL OR audit_events.ip = $3)
+        ORDER BY audit_events.created_at DESC, audit_events.id DESC
+        LIMIT $4 OFFSET $5"#,
+        filter.action.map(|action| action.as_str()),
+        filter.email,
+        filter.ip,
+        pagination.limit() as i64,
+        pagination.sql_offset(),
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(events)
+}
+
+pub async fn query_audit_events_count(
+    pool: &PgPool,
+    filter: &AuditFilter,
+) -> Result<usize, sqlx::Error> {
+    let count = sqlx::query_scalar!(
+        r#"SELECT COUNT(*) AS "count!"
+        FROM audit_events
+        LEFT JOIN users AS actors ON actors.id = audit_events.actor_id
+        LEFT JOIN users AS targets ON targets.id = audit_events.target_id
+        WHERE ($1::text IS NULL OR audit_events.action = $1)
+            AND ($2::text IS NULL OR lower(actors.email) = lower($2)
+                OR lower(targets.email) = lower($2)
+                OR lower(audit_events.metadata->>'email') = lower($2))
+            AND ($3::text IS NULL OR audit_events.ip = $3)"#,
+        filter.action.map(|action| action.as_str()),
+        filter.email,
+        filter.ip,
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(count as usize)
+}
+
+/// The newest events first, the total number of matching events is sent in the X-Total-Count header
+pub async fn show_audit_events(
+    State(pool): State<PgPool>,
+    Query(filter): Query<AuditFilter>,
+    pagination: Option<Query<Pagination>>,
+) -> Result<([(&'static str, String); 1], Json<Vec<AuditRecord>>), AuthError> {
+    let pagination = pagination
+        .unwrap_or_default()
+        .clamped(MAX_AUDIT_PAGE_SIZE)?;
+    let events = query_audit_events(&pool, &filter, &pagination).await?;
+    let count = query_audit_events_count(&pool, &filter).await?;
+
+    Ok(([("x-total-count", count.to_string())], Json(events)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::db::postgres::get_test_postgres_pool;
+
+    #[tokio::test]
+    async fn audit_events_are_filtered_and_append_only() {
+        let pool = get_test_postgres_pool();
+        let email = format!("{}@example.com", uuid::Uuid::new_v4());
+        let client = ClientInfo {
+            ip: Some("203.0.113.7".to_string()),
+            user_agent: Some("test".to_string()),
+        };
+        for _ in 0..2 {
+            let event = AuditEvent::new(AuditAction::LoginFailed)
+                .metadata(serde_json::json!({ "email": email, "reason": "wrong_credentials" }));
+            emit(&pool, &client, event).await;
+        }
+
+        let filter = AuditFilter {
+            action: Some(AuditAction::LoginFailed),
+            email: Some(email.to_uppercase()),
+            ip: None,
+        };
+        assert_eq!(query_audit_events_count(&pool, &filter).await.unwrap(), 2);
+        let pagination = Pagination {
+            page: 0,
+            page_size: 1,
+        };
+        let events = query_audit_events(&pool, &filter, &pagination)
+            .await
+            .unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].action, "login.failed");
+        assert_eq!(events[0].ip.as_deref(), Some("203.0.113.7"));
+
+        let filter = AuditFilter {
+            action: Some(AuditAction::LoginSucceeded),
+            email: Some(email),
+            ip: None,
+        };
+        assert_eq!(query_audit_events_count(&pool, &filter).await.unwrap(), 0);
+
+        let result = sqlx::query!("DELETE FROM audit_events WHERE id = $1", events[0].id)
+            .execute(&pool)
+            .await;
+        assert!(result.is_err());
+    }
+}
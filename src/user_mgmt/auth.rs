@@ -1,15 +1,28 @@
-use super::encryption::verify;
+use super::api_key::{authenticate_api_key, is_api_key};
+use super::audit::{emit, AuditAction, AuditEvent};
+use super::csrf::CsrfToken;
+use super::denylist::{is_revoked, revoke_access_token};
+use super::encryption::{needs_rehash, verify};
 use super::error::AuthError;
-use super::handler::query_user;
-use super::jwt::{decode, encode};
-pub use super::jwt::{Claims, Role};
-use crate::configuration::{get_environment, Environment};
-use axum::Form;
+use super::handler::{query_user, update_password};
+use super::jwt::{decode, encode, ACCESS_TOKEN_TTL};
+pub use super::jwt::{jwks, Claims, Role};
+use super::permission::query_user_permissions;
+use super::refresh::{
+    issue_refresh_token, revoke_refresh_token, rotate_refresh_token, REFRESH_TOKEN_TTL,
+};
+use super::throttle::{clear_throttle, reserve_attempt, ThrottleKey};
+use super::totp::{add_mfa_challenge_cookie, is_totp_enabled};
+use crate::common::entity::ClientInfo;
+use crate::configuration::{get_configuration, get_environment, Environment};
+use askama_axum::Template;
 use axum::{
     async_trait,
-    extract::{FromRef, FromRequestParts, State},
-    http::request::Parts,
-    RequestPartsExt,
+    extract::{FromRef, FromRequestParts, Query, Request, State},
+    http::{request::Parts, Method},
+    middleware::Next,
+    response::{IntoResponse, Redirect, Response},
+    Form, Json, RequestPartsExt,
 };
 use axum_extra::{
     extract::cookie::{Cookie, CookieJar, SameSite},
@@ -18,6 +31,7 @@ use axum_extra::{
 };
 use axum_htmx::HxRedirect;
 use cookie::time::Duration;
+use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
 
@@ -28,10 +42,11 @@ pub async fn me_handler(user: CurrentUser) -> Result<String, AuthError> {
 // TODO: should it be in auth.rs? or handler.rs? messy code separation
 // tracing::instrument is a wrapper
 // it shows only if there are logs inside.
-#[tracing::instrument(name="Logging in", skip(jar, pool, payload), fields(username = %payload.email))]
+#[tracing::instrument(name="Logging in", skip(jar, pool, client, payload), fields(username = %payload.email))]
 pub async fn login(
     jar: CookieJar,
     State(pool): State<PgPool>,
+    client: ClientInfo,
     // Json must be placed at the end of the parameters
     Form(payload): Form<AuthPayload>,
     // Json must be placed at the end of the Result tuple
@@ -40,72 +55,363 @@ pub async fn login(
     if payload.email.is_empty() || payload.password.is_empty() {
         return Err(AuthError::MissingCredentials);
     }
+    let email = payload.email.clone();
+    let account = ThrottleKey::Account(payload.email.clone());
+    let mut throttle_keys = vec![account.clone()];
+    if let Some(ip) = client.ip.clone() {
+        throttle_keys.push(ThrottleKey::Ip(ip));
+    }
+    let attempt = reserve_attempt(&pool, &throttle_keys).await?;
+
     // Here you can check the user credentials from a database
     // debug payload
-    let (user_id, role) = validate_user(&pool, payload).await?;
+    let (user_id, role) = match validate_user(&pool, payload).await {
+        Err(AuthError::WrongCredentials) => {
+            // the reserved attempt stays counted as a failure
+            emit(&pool, &client, login_failed(&email, "wrong_credentials")).await;
+            return Err(AuthError::WrongCredentials);
+        }
+        Err(e @ (AuthError::InactiveUser | AuthError::UnverifiedUser)) => {
+            let reason = match e {
+                AuthError::InactiveUser => "inactive",
+                _ => "unverified",
+            };
+            attempt.release(&pool).await?;
+            emit(&pool, &client, login_failed(&email, reason)).await;
+            return Err(e);
+        }
+        result => result?,
+    };
+    attempt.release(&pool).await?;
+    // the earlier failures are kept until the second factor is verified as well,
+    // otherwise the password alone would reset the throttle of the codes
+    if is_totp_enabled(&pool, user_id).await? {
+        return Ok((
+            add_mfa_challenge_cookie(jar, &pool, user_id).await?,
+            HxRedirect("/login/2fa".parse().unwrap()),
+            (),
+        ));
+    }
+    // only the account is cleared, a valid login must not reset the counter of a whole ip
+    clear_throttle(&pool, &account).await?;
+    record_login(&pool, &client, user_id, false).await?;
+
+    // Store and Send the authorized token
+    Ok((
+        add_login_cookies(jar, &pool, user_id, role, &client).await?,
+        HxRedirect("/me".parse().unwrap()),
+        (),
+    ))
+}
+
+/// The failed attempt has no actor, the email tells which account was tried
+pub fn login_failed(email: &str, reason: &str) -> AuditEvent {
+    AuditEvent::new(AuditAction::LoginFailed)
+        .metadata(serde_json::json!({ "email": email, "reason": reason }))
+}
+
+/// The bookkeeping of a completed login, once the second factor is verified if any
+pub async fn record_login(
+    pool: &PgPool,
+    client: &ClientInfo,
+    user_id: uuid::Uuid,
+    mfa: bool,
+) -> Result<(), AuthError> {
+    sqlx::query!(
+        "UPDATE users SET last_login = current_timestamp WHERE id = $1",
+        user_id
+    )
+    .execute(pool)
+    .await?;
+    let event = AuditEvent::new(AuditAction::LoginSucceeded)
+        .actor(user_id)
+        .target(user_id)
+        .metadata(serde_json::json!({ "mfa": mfa }));
+    emit(pool, client, event).await;
+    Ok(())
+}
+
+/// Issue the tokens of a fresh login once the user is fully authenticated
+pub async fn add_login_cookies(
+    jar: CookieJar,
+    pool: &PgPool,
+    user_id: uuid::Uuid,
+    role: Role,
+    client: &ClientInfo,
+) -> Result<CookieJar, AuthError> {
+    // A fresh login starts a new session with a new refresh token family
+    let session = issue_refresh_token(pool, user_id, client).await?;
     // Validated, now create jwt claims
-    let claims = Claims::new(user_id, role);
+    let claims = Claims::new(user_id, role).with_session(session.session_id);
     // Create the authorization token
     let token = encode(&claims).map_err(|_| AuthError::TokenCreation)?;
+    Ok(add_auth_cookies(jar, token, session.token))
+}
 
-    // check env for local client to bypass the secure flag, cuz we don't need https on localhost
+/// Exchange the refresh token cookie for a new access token and a rotated refresh token.
+/// The cookies are removed if the session cannot be renewed, so that the pages stop
+/// showing the user as logged in
+#[tracing::instrument(name = "Refreshing access token", skip(jar, pool, client))]
+pub async fn refresh(
+    jar: CookieJar,
+    State(pool): State<PgPool>,
+    client: ClientInfo,
+) -> Result<(CookieJar, Json<AuthBody>), (CookieJar, AuthError)> {
+    match renew_tokens(jar.clone(), &pool, &client).await {
+        Ok((jar, token)) => Ok((jar, Json(AuthBody::new(token)))),
+        Err(e) => Err((remove_auth_cookies(jar), e)),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RefreshQuery {
+    next: Option<String>,
+}
+
+/// Only a path of this site, so that the link cannot send the user elsewhere
+fn is_local_path(path: &str) -> bool {
+    path.starts_with('/') && !path.starts_with("//") && !path.contains('\\')
+}
+
+#[derive(Template)]
+#[template(path = "refresh.html")]
+pub struct RefreshTemplate {
+    pub next: String,
+    pub csrf_token: String,
+}
+
+fn next_path(query: RefreshQuery) -> String {
+    query
+        .next
+        .filter(|next| is_local_path(next))
+        .unwrap_or_else(|| "/".to_string())
+}
+
+/// The pages are redirected here by `renew_expired_session`. A GET must not rotate the tokens,
+/// any other site could link to it, so the page posts itself to `refresh_and_redirect`
+pub async fn refresh_page(
+    CsrfToken(csrf_token): CsrfToken,
+    Query(query): Query<RefreshQuery>,
+) -> RefreshTemplate {
+    RefreshTemplate {
+        next: next_path(query),
+        csrf_token,
+    }
+}
+
+/// The form version of `refresh` for the pages, which are sent back to the page
+/// once the tokens are renewed
+#[tracing::instrument(name = "Refreshing access token of a page", skip_all)]
+pub async fn refresh_and_redirect(
+    jar: CookieJar,
+    State(pool): State<PgPool>,
+    client: ClientInfo,
+    Form(query): Form<RefreshQuery>,
+) -> (CookieJar, Redirect) {
+    let next = next_path(query);
+    match renew_tokens(jar.clone(), &pool, &client).await {
+        Ok((jar, _)) => (jar, Redirect::to(&next)),
+        Err(e) => {
+            tracing::info!("Failed to renew the session of a page: {e}");
+            (remove_auth_cookies(jar), Redirect::to("/login"))
+        }
+    }
+}
+
+/// The refresh token is only sent to the auth endpoints, so a page cannot renew an expired
+/// access token on its own. A logged in user whose access token is gone is sent through
+/// `refresh_page` first, instead of getting the page as a logged out user
+pub async fn renew_expired_session(jar: CookieJar, request: Request, next: Next) -> Response {
+    let is_logged_in = jar
+        .get(IS_LOGGED_IN_COOKIE)
+        .is_some_and(|cookie| cookie.value() == "1");
+    let has_access_token = jar
+        .get(ACCESS_TOKEN_COOKIE)
+        .is_some_and(|cookie| decode(cookie.value()).is_ok());
+    if request.method() != Method::GET || !is_logged_in || has_access_token {
+        return next.run(request).await;
+    }
+
+    let path = request
+        .uri()
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/");
+    let path = askama::filters::urlencode_strict(path).unwrap_or_default();
+    Redirect::to(&format!("{REFRESH_TOKEN_PATH}/refresh?next={path}")).into_response()
+}
+
+/// Rotate the refresh token of the cookie and issue a new access token for its user
+pub async fn renew_tokens(
+    jar: CookieJar,
+    pool: &PgPool,
+    client: &ClientInfo,
+) -> Result<(CookieJar, String), AuthError> {
+    let refresh_token = jar
+        .get(REFRESH_TOKEN_COOKIE)
+        .ok_or(AuthError::MissingCredentials)?
+        .value()
+        .to_string();
+
+    let rotated = rotate_refresh_token(pool, &refresh_token, client).await?;
+    // the role is read again in case it was changed since the last login
+    let user = query_user(pool, rotated.user_id).await?;
+    if !user.is_active {
+        return Err(AuthError::InactiveUser);
+    }
+    let claims = Claims::new(user.id, user.role()).with_session(rotated.session_id);
+    let token = encode(&claims).map_err(|_| AuthError::TokenCreation)?;
+
+    Ok((add_auth_cookies(jar, token.clone(), rotated.token), token))
+}
+
+/// Revoke the tokens server-side and remove the cookies by setting the max_age to 0
+/// somehow jar.remove(Cookie::from("access_token")) is not working anymore
+pub async fn logout(
+    jar: CookieJar,
+    State(pool): State<PgPool>,
+    client: ClientInfo,
+    claims: Option<Claims>,
+) -> Result<(CookieJar, HxRedirect, ()), AuthError> {
+    // any copy of the access token, e.g. sent as a bearer token, is denied from now on,
+    // an API key is only revoked explicitly
+    if let Some(claims) = claims.filter(|claims| claims.api_key.is_none()) {
+        revoke_access_token(&pool, &claims).await?;
+        let event = AuditEvent::new(AuditAction::Logout)
+            .actor(claims.actor_id())
+            .target(claims.sub);
+        emit(&pool, &client, event).await;
+    }
+    if let Some(refresh_token) = jar.get(REFRESH_TOKEN_COOKIE) {
+        revoke_refresh_token(&pool, refresh_token.value()).await?;
+    }
+
+    Ok((
+        remove_auth_cookies(jar),
+        HxRedirect("/".parse().unwrap()),
+        (),
+    ))
+}
+
+pub fn remove_auth_cookies(jar: CookieJar) -> CookieJar {
     let env = get_environment();
-    // Create a http_only cookie to store the token
-    let cookie = Cookie::build(("access_token", token.clone()))
+    let cookie = Cookie::build((ACCESS_TOKEN_COOKIE, ""))
         .http_only(true)
         .secure(env != Environment::Local)
         .same_site(SameSite::Lax)
-        .max_age(Duration::hours(1))
+        .max_age(Duration::hours(0))
         .path("/")
         .build();
 
-    let is_logged_in = Cookie::build(("is_logged_in", "1"))
+    let refresh_cookie = Cookie::build((REFRESH_TOKEN_COOKIE, ""))
+        .http_only(true)
+        .secure(env != Environment::Local)
+        .same_site(SameSite::Lax)
+        .max_age(Duration::hours(0))
+        .path(REFRESH_TOKEN_PATH)
+        .build();
+
+    let is_logged_in = Cookie::build((IS_LOGGED_IN_COOKIE, "0"))
         .secure(env != Environment::Local)
         .same_site(SameSite::Lax)
-        .max_age(Duration::hours(1))
+        .max_age(Duration::hours(0))
         .path("/")
         .build();
-    // Store and Send the authorized token
-    Ok((
-        jar.add(cookie).add(is_logged_in),
-        HxRedirect("/me".parse().unwrap()),
-        (),
-    ))
+
+    remove_impersonating_cookie(jar)
+        .add(cookie)
+        .add(refresh_cookie)
+        .add(is_logged_in)
 }
 
-/// Remove the cookie by setting the max_age to 0
-/// somehow jar.remove(Cookie::from("access_token")) is not working anymore
-pub async fn logout(jar: CookieJar) -> (CookieJar, HxRedirect, ()) {
+// the email of the impersonated user, the pages show a banner while it is set
+const IMPERSONATING_COOKIE: &str = "impersonating";
+
+/// Replace the access token only, the refresh token of the admin is kept
+/// so that the admin gets their own token back when the impersonation stops
+pub fn add_impersonation_cookies(jar: CookieJar, token: String, email: &str) -> CookieJar {
     let env = get_environment();
-    let cookie = Cookie::build(("access_token", ""))
+    let cookie = Cookie::build((ACCESS_TOKEN_COOKIE, token))
         .http_only(true)
         .secure(env != Environment::Local)
         .same_site(SameSite::Lax)
-        .max_age(Duration::hours(0))
+        .max_age(Duration::seconds(ACCESS_TOKEN_TTL.num_seconds()))
         .path("/")
         .build();
 
-    let is_logged_in = Cookie::build(("is_logged_in", "0"))
+    let impersonating = Cookie::build((IMPERSONATING_COOKIE, email.to_string()))
         .secure(env != Environment::Local)
         .same_site(SameSite::Lax)
+        .max_age(Duration::seconds(ACCESS_TOKEN_TTL.num_seconds()))
+        .path("/")
+        .build();
+
+    jar.add(cookie).add(impersonating)
+}
+
+fn remove_impersonating_cookie(jar: CookieJar) -> CookieJar {
+    let cookie = Cookie::build((IMPERSONATING_COOKIE, ""))
+        .secure(get_environment() != Environment::Local)
+        .same_site(SameSite::Lax)
         .max_age(Duration::hours(0))
         .path("/")
         .build();
+    jar.add(cookie)
+}
 
-    (
-        jar.add(cookie).add(is_logged_in),
-        HxRedirect("/".parse().unwrap()),
-        (),
-    )
+const ACCESS_TOKEN_COOKIE: &str = "access_token";
+const REFRESH_TOKEN_COOKIE: &str = "refresh_token";
+// the refresh token is only sent to the auth endpoints that consume it
+const REFRESH_TOKEN_PATH: &str = "/api/v1/auth";
+// readable by the pages, it is set as long as the session can be renewed
+const IS_LOGGED_IN_COOKIE: &str = "is_logged_in";
+
+pub fn add_auth_cookies(jar: CookieJar, token: String, refresh_token: String) -> CookieJar {
+    // check env for local client to bypass the secure flag, cuz we don't need https on localhost
+    let env = get_environment();
+    // Create a http_only cookie to store the token
+    let cookie = Cookie::build((ACCESS_TOKEN_COOKIE, token))
+        .http_only(true)
+        .secure(env != Environment::Local)
+        .same_site(SameSite::Lax)
+        .max_age(Duration::seconds(ACCESS_TOKEN_TTL.num_seconds()))
+        .path("/")
+        .build();
+
+    let refresh_cookie = Cookie::build((REFRESH_TOKEN_COOKIE, refresh_token))
+        .http_only(true)
+        .secure(env != Environment::Local)
+        .same_site(SameSite::Lax)
+        .max_age(Duration::seconds(REFRESH_TOKEN_TTL.num_seconds()))
+        .path(REFRESH_TOKEN_PATH)
+        .build();
+
+    // the user stays logged in as long as the refresh token is valid
+    let is_logged_in = Cookie::build((IS_LOGGED_IN_COOKIE, "1"))
+        .secure(env != Environment::Local)
+        .same_site(SameSite::Lax)
+        .max_age(Duration::seconds(REFRESH_TOKEN_TTL.num_seconds()))
+        .path("/")
+        .build();
+
+    remove_impersonating_cookie(jar)
+        .add(cookie)
+        .add(refresh_cookie)
+        .add(is_logged_in)
 }
 
+static REQUIRE_VERIFIED_EMAIL: Lazy<bool> = Lazy::new(|| {
+    let configuration = get_configuration().expect("Failed to load configuration.");
+    configuration.security.require_verified_email
+});
+
 async fn validate_user(
     pool: &PgPool,
     credentials: AuthPayload,
 ) -> Result<(uuid::Uuid, Role), AuthError> {
     let user = sqlx::query_as!(
         UserToClaim,
-        "SELECT id, hashed_password, is_superuser, is_verified from users
+        "SELECT id, hashed_password, is_active, is_superuser, is_verified from users
         WHERE email = $1
         ",
         credentials.email
@@ -120,14 +426,28 @@ async fn validate_user(
         Role::User
     };
 
-    if !verify(credentials.password, user.hashed_password).await? {
+    let rehash = needs_rehash(&user.hashed_password);
+    if !verify(credentials.password.clone(), user.hashed_password).await? {
         return Err(AuthError::WrongCredentials);
     }
 
-    // TODO: implement email verification
-    // if !user.is_verified {
-    //     return Err(AuthError::UnverifiedUser);
-    // }
+    // the password is only known now, so this is the time to upgrade an outdated hash,
+    // a failed upgrade is retried on the next login
+    if rehash {
+        let mut conn = pool.acquire().await?;
+        let _ = update_password(&mut conn, user.id, credentials.password)
+            .await
+            .inspect_err(|e| tracing::error!("Failed to rehash password: {e}"));
+    }
+
+    // checked after the password so that it does not tell which accounts exist
+    if !user.is_active {
+        return Err(AuthError::InactiveUser);
+    }
+
+    if *REQUIRE_VERIFIED_EMAIL && !user.is_verified {
+        return Err(AuthError::UnverifiedUser);
+    }
 
     Ok((user.id, role))
 }
@@ -137,29 +457,38 @@ async fn validate_user(
 impl<S> FromRequestParts<S> for Claims
 where
     S: Send + Sync,
+    PgPool: FromRef<S>,
 {
     type Rejection = AuthError;
 
-    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
         // The token might come from a cookie or from the authorization header
         // Note: It is invalid if the token from cookie is correct
         // but the token from the header is not
+        let pool = PgPool::from_ref(state);
         let token = if let Ok(TypedHeader(Authorization(bearer))) =
             parts.extract::<TypedHeader<Authorization<Bearer>>>().await
         {
+            // API keys are only accepted in the header, they are checked against the database
+            if is_api_key(bearer.token()) {
+                return authenticate_api_key(&pool, bearer.token()).await;
+            }
             // Extract the token from the authorization header
             bearer.token().to_string()
-        } else if let Ok(jar) = parts.extract::<CookieJar>().await {
-            // Extract the token from the cookie
-            jar.get("access_token")
+        } else {
+            // Extract the token from the cookie, extracting the jar itself is infallible
+            let jar = CookieJar::from_headers(&parts.headers);
+            jar.get(ACCESS_TOKEN_COOKIE)
                 .ok_or(AuthError::MissingCredentials)?
                 .value()
                 .to_string()
-        } else {
-            return Err(AuthError::MissingCredentials);
         };
 
         let claims = decode(&token)?.claims;
+
+        if is_revoked(&pool, &claims).await? {
+            return Err(AuthError::InvalidToken);
+        }
         Ok(claims)
     }
 }
@@ -181,12 +510,39 @@ where
         let user = query_user(&pool, claims.sub)
             .await
             .inspect_err(|e| tracing::error!("Failed to query current user from jwt: {e}"))?;
+        if !user.is_active {
+            return Err(AuthError::InactiveUser);
+        }
+        // the impersonation ends as soon as the admin is not an admin anymore
+        if let Some(actor) = &claims.act {
+            let admin = query_user(&pool, actor.sub).await?;
+            if !admin.is_active || !admin.is_superuser {
+                return Err(AuthError::InvalidToken);
+            }
+        }
+        // resolved per request so that role changes take effect immediately
+        let mut permissions = query_user_permissions(&pool, user.id)
+            .await
+            .inspect_err(|e| tracing::error!("Failed to query permissions of current user: {e}"))?;
+        if let Some(api_key) = &claims.api_key {
+            permissions.retain(|p| api_key.scopes.contains(p));
+        }
 
+        // a token cannot have more than the current role, so that a demotion takes effect
+        // immediately, while a promotion takes effect with the next token
+        let role = match claims.role {
+            Role::Admin => user.role(),
+            Role::User => Role::User,
+        };
         let current_user = Self {
             id: user.id,
             name: user.name,
             email: user.email,
-            role: claims.role,
+            role,
+            permissions,
+            session_id: claims.sid,
+            impersonator_id: claims.act.map(|actor| actor.sub),
+            api_key_id: claims.api_key.map(|api_key| api_key.id),
         };
 
         Ok(current_user)
@@ -199,6 +555,33 @@ pub struct CurrentUser {
     pub name: String,
     pub email: String,
     pub role: Role,
+    pub permissions: Vec<String>,
+    // the session of the login, None for an API key
+    pub session_id: Option<uuid::Uuid>,
+    // the admin who is impersonating the user
+    pub impersonator_id: Option<uuid::Uuid>,
+    // set if the request is authenticated with an API key instead of a login
+    pub api_key_id: Option<uuid::Uuid>,
+}
+
+impl CurrentUser {
+    /// Who is really acting, the admin while impersonating, the user otherwise
+    pub fn actor_id(&self) -> uuid::Uuid {
+        self.impersonator_id.unwrap_or(self.id)
+    }
+
+    /// The credentials and the sessions of a user are only changed by the user themselves
+    pub fn ensure_not_impersonated(&self) -> Result<(), AuthError> {
+        if self.impersonator_id.is_some() {
+            return Err(AuthError::Impersonating);
+        }
+        Ok(())
+    }
+
+    /// Superusers have every permission, the others get them from their named roles
+    pub fn has_permission(&self, permission: &str) -> bool {
+        self.role == Role::Admin || self.permissions.iter().any(|p| p == permission)
+    }
 }
 
 impl std::fmt::Display for CurrentUser {
@@ -214,7 +597,6 @@ pub struct AuthBody {
 }
 
 impl AuthBody {
-    #[allow(dead_code)]
     fn new(access_token: String) -> Self {
         Self {
             access_token,
@@ -233,8 +615,8 @@ pub struct AuthPayload {
 struct UserToClaim {
     id: uuid::Uuid,
     hashed_password: String,
+    is_active: bool,
     is_superuser: bool,
-    #[allow(dead_code)]
     is_verified: bool,
 }
 
@@ -243,29 +625,31 @@ pub async fn get_current_user_from_id(pool: &PgPool, user_id: &uuid::Uuid) -> Cu
         .await
         .inspect_err(|e| tracing::error!("Failed to query current user from jwt: {e}"))
         .expect("Failed to query current user");
-
-    let role = if user.is_superuser {
-        Role::Admin
-    } else {
-        Role::User
-    };
+    let permissions = query_user_permissions(pool, user.id)
+        .await
+        .expect("Failed to query permissions of current user");
 
     CurrentUser {
+        role: user.role(),
         id: user.id,
         name: user.name,
         email: user.email,
-        role,
+        permissions,
+        session_id: None,
+        impersonator_id: None,
+        api_key_id: None,
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::common::db::postgres::get_postgres_pool;
+    use crate::common::db::postgres::get_test_postgres_pool;
+    use crate::user_mgmt::encryption::hash;
 
     #[tokio::test]
     async fn validate_user_from_db() {
-        let pool = get_postgres_pool().await;
+        let pool = &get_test_postgres_pool();
         let payload = AuthPayload {
             email: "admin@example.com".to_string(),
             password: "password".to_string(),
@@ -274,4 +658,110 @@ mod tests {
 
         assert!(result.is_ok())
     }
+
+    #[tokio::test]
+    async fn legacy_hash_is_rehashed_on_login() {
+        let pool = &get_test_postgres_pool();
+        let email = format!("{}@example.com", uuid::Uuid::new_v4());
+        let bcrypt_hash = bcrypt::hash("password", 4).unwrap();
+        sqlx::query!(
+            "INSERT INTO users (email, hashed_password, name) VALUES ($1, $2, 'Legacy')",
+            email,
+            bcrypt_hash,
+        )
+        .execute(pool)
+        .await
+        .unwrap();
+
+        let payload = AuthPayload {
+            email: email.clone(),
+            password: "password".to_string(),
+        };
+        assert!(validate_user(pool, payload).await.is_ok());
+
+        let hashed_password =
+            sqlx::query_scalar!("SELECT hashed_password FROM users WHERE email = $1", email)
+                .fetch_one(pool)
+                .await
+                .unwrap();
+        assert_ne!(hashed_password, bcrypt_hash);
+        assert!(!needs_rehash(&hashed_password));
+    }
+
+    #[tokio::test]
+    async fn inactive_user_cannot_log_in() {
+        let pool = &get_test_postgres_pool();
+        let email = format!("{}@example.com", uuid::Uuid::new_v4());
+        let hashed_password = hash("password".to_string()).await.unwrap();
+        sqlx::query!(
+            "INSERT INTO users (email, hashed_password, name, is_active)
+            VALUES ($1, $2, 'Inactive', false)",
+            email,
+            hashed_password,
+        )
+        .execute(pool)
+        .await
+        .unwrap();
+
+        let payload = AuthPayload {
+            email,
+            password: "password".to_string(),
+        };
+        let result = validate_user(pool, payload).await;
+        assert!(matches!(result, Err(AuthError::InactiveUser)));
+    }
+
+    #[tokio::test]
+    async fn expired_page_session_is_renewed_through_refresh() {
+        use axum::body::Body;
+        use axum::http::header::{COOKIE, LOCATION};
+        use axum::middleware::from_fn;
+        use axum::routing::get;
+        use tower::ServiceExt;
+
+        let router = axum::Router::new()
+            .route("/me", get(|| async { "page" }))
+            .layer(from_fn(renew_expired_session));
+        let page = |cookie: &'static str| {
+            let request = Request::builder()
+                .uri("/me?tab=sessions")
+                .header(COOKIE, cookie)
+                .body(Body::empty())
+                .unwrap();
+            router.clone().oneshot(request)
+        };
+        let response = page("is_logged_in=1").await.unwrap();
+        assert_eq!(
+            response.headers().get(LOCATION).unwrap(),
+            "/api/v1/auth/refresh?next=%2Fme%3Ftab%3Dsessions"
+        );
+        let response = page("is_logged_in=0").await.unwrap();
+        assert!(response.headers().get(LOCATION).is_none());
+
+        // the GET only renders a form that posts itself, with the CSRF token
+        let query = RefreshQuery {
+            next: Some("/me?tab=sessions".to_string()),
+        };
+        let html = refresh_page(CsrfToken("token".to_string()), Query(query))
+            .await
+            .render()
+            .unwrap();
+        assert!(html.contains(r#"method="post" action="/api/v1/auth/refresh/redirect""#));
+        assert!(html.contains(r#"name="csrf_token" value="token""#));
+        assert!(html.contains(r#"name="next" value="/me?tab=sessions""#));
+
+        // the session cannot be renewed, the user is logged out instead of sent back
+        let pool = get_test_postgres_pool();
+        let jar = CookieJar::new()
+            .add(Cookie::new(REFRESH_TOKEN_COOKIE, "revoked"))
+            .add(Cookie::new(IS_LOGGED_IN_COOKIE, "1"));
+        let query = RefreshQuery {
+            next: Some("//evil.example.com".to_string()),
+        };
+        let (jar, redirect) =
+            refresh_and_redirect(jar, State(pool), ClientInfo::default(), Form(query)).await;
+        let response = redirect.into_response();
+        assert_eq!(response.headers().get(LOCATION).unwrap(), "/login");
+        assert_eq!(jar.get(IS_LOGGED_IN_COOKIE).unwrap().value(), "0");
+    }
 }
@@ -0,0 +1,248 @@
+use super::error::AuthError;
+use super::token::generate_token;
+use crate::configuration::{get_environment, Environment};
+use async_trait::async_trait;
+use axum::body::{to_bytes, Body};
+use axum::extract::{FromRequest, FromRequestParts, Request};
+use axum::http::header::{AUTHORIZATION, CONTENT_TYPE, SET_COOKIE};
+use axum::http::request::Parts;
+use axum::http::{HeaderValue, Method};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Form;
+use axum_extra::extract::cookie::{Cookie, CookieJar, SameSite};
+use serde::Deserialize;
+
+// the cookie is readable by the pages, so that htmx can send it back in the header
+pub const CSRF_COOKIE: &str = "csrf_token";
+pub const CSRF_HEADER: &str = "x-csrf-token";
+// the hidden field of a plain form, which cannot set a header
+pub const CSRF_FIELD: &str = "csrf_token";
+
+// the body of a form is read to find the field, a larger body is rejected
+const MAX_FORM_SIZE: usize = 64 * 1024;
+
+/// The token of the browser, for the pages to send it back with their requests.
+/// It is the one that is about to be set in the cookie if the browser has none yet
+#[derive(Clone, Default)]
+pub struct CsrfToken(pub String);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for CsrfToken
+where
+    S: Send + Sync,
+{
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(parts
+            .extensions
+            .get::<CsrfToken>()
+            .cloned()
+            .unwrap_or_default())
+    }
+}
+
+#[derive(Deserialize)]
+struct CsrfForm {
+    csrf_token: Option<String>,
+}
+
+/// The token of the hidden field of an urlencoded form, the body is put back for the handler
+async fn form_token(request: Request) -> (Option<String>, Request) {
+    let is_form = request
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/x-www-form-urlencoded"));
+    if !is_form {
+        return (None, request);
+    }
+    let (parts, body) = request.into_parts();
+    let Ok(bytes) = to_bytes(body, MAX_FORM_SIZE).await else {
+        return (None, Request::from_parts(parts, Body::empty()));
+    };
+    let form = Request::from_parts(parts.clone(), Body::from(bytes.clone()));
+    let token = Form::<CsrfForm>::from_request(form, &())
+        .await
+        .ok()
+        .and_then(|Form(form)| form.csrf_token);
+    (token, Request::from_parts(parts, Body::from(bytes)))
+}
+
+fn is_safe(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+    )
+}
+
+/// A bearer token is not sent by the browser on its own, so it cannot be forged cross-site
+fn is_bearer(request: &Request) -> bool {
+    request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("Bearer "))
+}
+
+// compares every byte, so that the time taken does not tell how much of the token is right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Double submit cookie: a state-changing request that is not authenticated by a bearer token
+/// has to send the token of the cookie in the X-CSRF-Token header or in the csrf_token field
+/// of its form as well, another site can make the browser send the cookie but it cannot read it
+pub async fn csrf_protection(jar: CookieJar, mut request: Request, next: Next) -> Response {
+    let token = jar
+        .get(CSRF_COOKIE)
+        .map(|cookie| cookie.value().to_string());
+
+    if !is_safe(request.method()) && !is_bearer(&request) {
+        let header = request
+            .headers()
+            .get(CSRF_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        let submitted = match header {
+            Some(header) => Some(header),
+            None => {
+                let (field, form) = form_token(request).await;
+                request = form;
+                field
+            }
+        };
+        let is_valid = match (&token, submitted) {
+            (Some(token), Some(submitted)) => {
+                constant_time_eq(token.as_bytes(), submitted.as_bytes())
+            }
+            _ => false,
+        };
+        if !is_valid {
+            tracing::debug!(
+                "Rejected {} {} without a valid CSRF token",
+                request.method(),
+                request.uri()
+            );
+            return AuthError::InvalidCsrfToken.into_response();
+        }
+    }
+
+    // the token is issued once per browser session, with the first response
+    let issued = token.is_none().then(generate_token);
+    let current = token.or_else(|| issued.clone()).unwrap_or_default();
+    request.extensions_mut().insert(CsrfToken(current));
+
+    let mut response = next.run(request).await;
+    if let Some(issued) = issued {
+        let cookie = Cookie::build((CSRF_COOKIE, issued))
+            .secure(get_environment() != Environment::Local)
+            .same_site(SameSite::Strict)
+            .path("/")
+            .build();
+        if let Ok(value) = HeaderValue::from_str(&cookie.to_string()) {
+            response.headers_mut().append(SET_COOKIE, value);
+        }
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::get_configuration;
+    use axum::body::Body;
+    use axum::http::header::COOKIE;
+    use axum::http::StatusCode;
+    use axum::middleware::from_fn;
+    use axum::routing::get;
+    use axum::Router;
+    use tower::ServiceExt;
+
+    fn router() -> Router {
+        Router::new()
+            .route(
+                "/",
+                get(|CsrfToken(token): CsrfToken| async move { token })
+                    .post(|body: String| async move { body }),
+            )
+            .layer(from_fn(csrf_protection))
+    }
+
+    async fn post(headers: &[(&str, &str)]) -> StatusCode {
+        let mut request = Request::builder().method("POST").uri("/");
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let request = request.body(Body::empty()).unwrap();
+        router().oneshot(request).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn csrf_token_is_issued_and_verified() {
+        // the cookie depends on APP_ENVIRONMENT, which is set along with the configuration
+        get_configuration().expect("Failed to read configuration.");
+        let request = Request::builder().uri("/").body(Body::empty()).unwrap();
+        let response = router().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let cookie = response
+            .headers()
+            .get(SET_COOKIE)
+            .unwrap()
+            .to_str()
+            .unwrap();
+        let token = Cookie::parse(cookie.to_string())
+            .unwrap()
+            .value()
+            .to_string();
+        // the page gets the token that is set in the cookie
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, token.as_bytes());
+
+        let cookie = format!("{CSRF_COOKIE}={token}");
+        assert_eq!(post(&[]).await, StatusCode::FORBIDDEN);
+        assert_eq!(
+            post(&[(COOKIE.as_str(), &cookie)]).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            post(&[(COOKIE.as_str(), &cookie), (CSRF_HEADER, "forged")]).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            post(&[(COOKIE.as_str(), &cookie), (CSRF_HEADER, &token)]).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            post(&[(AUTHORIZATION.as_str(), "Bearer token")]).await,
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn plain_form_sends_the_token_in_a_field() {
+        let post_form = |body: String| {
+            let request = Request::builder()
+                .method("POST")
+                .uri("/")
+                .header(COOKIE, format!("{CSRF_COOKIE}=token"))
+                .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+                .body(Body::from(body))
+                .unwrap();
+            router().oneshot(request)
+        };
+
+        let forged = post_form("name=a&csrf_token=forged".to_string())
+            .await
+            .unwrap();
+        assert_eq!(forged.status(), StatusCode::FORBIDDEN);
+
+        // the handler still gets the whole form
+        let form = "name=a&csrf_token=token";
+        let response = post_form(form.to_string()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, form.as_bytes());
+    }
+}
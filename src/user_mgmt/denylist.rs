@@ -0,0 +1,135 @@
+use super::error::AuthError;
+use super::jwt::Claims;
+use super::refresh::revoke_user_refresh_tokens;
+use chrono::Utc;
+use sqlx::{PgConnection, PgPool};
+
+/// Deny the access token until it expires by itself
+pub async fn revoke_access_token(pool: &PgPool, claims: &Claims) -> Result<(), AuthError> {
+    sqlx::query!(
+        "INSERT INTO revoked_tokens (jti, expires_at) VALUES ($1, $2)
+        ON CONFLICT (jti) DO NOTHING",
+        claims.jti,
+        claims.expires_at(),
+    )
+    .execute(pool)
+    .await
+    .inspect_err(|e| tracing::error!("Failed to revoke access token: {e}"))?;
+    Ok(())
+}
+
+/// Log the user out everywhere, every token issued until now is rejected.
+/// The API keys of integrations are kept, see `revoke_user_api_keys`
+pub async fn revoke_user_tokens(
+    conn: &mut PgConnection,
+    user_id: uuid::Uuid,
+) -> Result<(), AuthError> {
+    // the clock of the app, the one that sets iat, so that the two can be compared
+    sqlx::query!(
+        "UPDATE users SET tokens_valid_after = $2 WHERE id = $1",
+        user_id,
+        Utc::now(),
+    )
+    .execute(&mut *conn)
+    .await?;
+    revoke_user_refresh_tokens(conn, user_id).await
+}
+
+/// A token is revoked if its id is denied, if its session is revoked
+/// or if all the tokens of its user are revoked
+pub async fn is_revoked(pool: &PgPool, claims: &Claims) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"SELECT EXISTS(SELECT 1 FROM revoked_tokens WHERE jti = $1)
+            OR EXISTS(SELECT 1 FROM users WHERE id = $2 AND tokens_valid_after >= $3)
+            OR EXISTS(SELECT 1 FROM sessions WHERE id = $4 AND revoked_at IS NOT NULL)
+            AS "revoked!""#,
+        claims.jti,
+        claims.sub,
+        claims.issued_at(),
+        claims.sid,
+    )
+    .fetch_one(pool)
+    .await
+}
+
+/// Expired tokens are rejected by the jwt validation anyway, so they can be forgotten
+pub async fn purge_expired(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    sqlx::query!("DELETE FROM revoked_tokens WHERE expires_at < current_timestamp")
+        .execute(pool)
+        .await
+        .map(|result| result.rows_affected())
+}
+
+/// Run the purge in the background for the lifetime of the app
+pub fn spawn_purge_task(pool: PgPool, period: std::time::Duration) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            match purge_expired(&pool).await {
+                Ok(count) => tracing::debug!("Purged {count} expired revoked tokens"),
+                Err(e) => tracing::error!("Failed to purge expired revoked tokens: {e}"),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::db::postgres::get_test_postgres_pool;
+    use crate::user_mgmt::entity::CreateUser;
+    use crate::user_mgmt::handler::insert_user;
+    use crate::user_mgmt::jwt::Role;
+
+    #[tokio::test]
+    async fn revoked_token_is_denied() {
+        let pool = &get_test_postgres_pool();
+        let claims = Claims::new(uuid::Uuid::new_v4(), Role::User);
+
+        assert!(!is_revoked(pool, &claims).await.unwrap());
+        revoke_access_token(pool, &claims).await.unwrap();
+        assert!(is_revoked(pool, &claims).await.unwrap());
+
+        // not expired yet, so it is kept
+        purge_expired(pool).await.unwrap();
+        assert!(is_revoked(pool, &claims).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn user_revocation_covers_the_same_second_but_not_api_keys() {
+        let pool = &get_test_postgres_pool();
+        let mut conn = pool.acquire().await.unwrap();
+        let payload = CreateUser {
+            email: format!("{}@example.com", uuid::Uuid::new_v4()),
+            password: "password".to_string(),
+            name: "Revoked".to_string(),
+        };
+        let user_id = insert_user(&mut conn, payload).await.unwrap();
+        sqlx::query!(
+            "INSERT INTO api_keys (user_id, name, key_prefix, key_hash, scopes)
+            VALUES ($1, 'key', 'pak_test', $2, '{}')",
+            user_id,
+            uuid::Uuid::new_v4().to_string(),
+        )
+        .execute(pool)
+        .await
+        .unwrap();
+
+        // issued a moment before, most likely in the same second
+        let claims = Claims::new(user_id, Role::User);
+        revoke_user_tokens(&mut conn, user_id).await.unwrap();
+        assert!(is_revoked(pool, &claims).await.unwrap());
+        let claims = Claims::new(user_id, Role::User);
+        assert!(!is_revoked(pool, &claims).await.unwrap());
+
+        let active_keys = sqlx::query_scalar!(
+            r#"SELECT COUNT(*) AS "count!" FROM api_keys WHERE user_id = $1 AND revoked_at IS NULL"#,
+            user_id
+        )
+        .fetch_one(pool)
+        .await
+        .unwrap();
+        assert_eq!(active_keys, 1);
+    }
+}
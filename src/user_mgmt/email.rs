@@ -0,0 +1,50 @@
+use crate::common::mail::Email;
+use crate::configuration::get_configuration;
+use once_cell::sync::Lazy;
+
+static BASE_URL: Lazy<String> = Lazy::new(|| {
+    let configuration = get_configuration().expect("Failed to load configuration.");
+    configuration
+        .application
+        .base_url
+        .trim_end_matches('/')
+        .to_string()
+});
+
+pub fn verification_email(to: String, token: &str) -> Email {
+    let link = format!("{}/verify-email?token={}", *BASE_URL, token);
+    Email {
+        to,
+        subject: "Verify your email".to_string(),
+        body: format!(
+            "Welcome! Please verify your email by opening the link below:\n\n{link}\n\n\
+            The link expires in 24 hours."
+        ),
+    }
+}
+
+pub fn magic_link_email(to: String, token: &str) -> Email {
+    let link = format!("{}/login/magic-link?token={}", *BASE_URL, token);
+    Email {
+        to,
+        subject: "Your sign-in link".to_string(),
+        body: format!(
+            "Open the link below to sign in:\n\n{link}\n\n\
+            The link expires in 15 minutes, can be used once and only works in the browser \
+            you asked for it. If it was not you, you can ignore this email."
+        ),
+    }
+}
+
+pub fn password_reset_email(to: String, token: &str) -> Email {
+    let link = format!("{}/reset-password?token={}", *BASE_URL, token);
+    Email {
+        to,
+        subject: "Reset your password".to_string(),
+        body: format!(
+            "Someone asked to reset the password of your account. \
+            If it was you, open the link below to choose a new password:\n\n{link}\n\n\
+            The link expires in 1 hour. If it was not you, you can ignore this email."
+        ),
+    }
+}
@@ -1,24 +1,119 @@
-use bcrypt::DEFAULT_COST;
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+use once_cell::sync::Lazy;
 
 use super::error::AuthError;
+use crate::configuration::{get_configuration, PasswordAlgorithm, PasswordSettings};
+
+static SCHEME: Lazy<HashScheme> = Lazy::new(|| {
+    let configuration = get_configuration().expect("Failed to load configuration.");
+    HashScheme::from_settings(&configuration.password)
+});
+
+/// How a password is hashed, new hashes always use the configured scheme
+/// while the scheme of a stored hash is read from the hash itself
+#[derive(Debug, Clone, PartialEq)]
+pub enum HashScheme {
+    Bcrypt {
+        cost: u32,
+    },
+    Argon2id {
+        memory_kib: u32,
+        iterations: u32,
+        parallelism: u32,
+    },
+}
+
+impl HashScheme {
+    pub fn from_settings(settings: &PasswordSettings) -> Self {
+        match settings.algorithm {
+            PasswordAlgorithm::Bcrypt => HashScheme::Bcrypt {
+                cost: settings.bcrypt_cost,
+            },
+            PasswordAlgorithm::Argon2id => HashScheme::Argon2id {
+                memory_kib: settings.argon2_memory_kib,
+                iterations: settings.argon2_iterations,
+                parallelism: settings.argon2_parallelism,
+            },
+        }
+    }
+
+    /// Detect the scheme of a stored hash, e.g. `$2b$12$...` or `$argon2id$v=19$m=19456,t=2,p=1$...`
+    pub fn detect(hash: &str) -> Option<Self> {
+        if hash.starts_with("$argon2") {
+            let hash = PasswordHash::new(hash).ok()?;
+            if hash.algorithm != Algorithm::Argon2id.ident() {
+                return None;
+            }
+            let params = Params::try_from(&hash).ok()?;
+            Some(HashScheme::Argon2id {
+                memory_kib: params.m_cost(),
+                iterations: params.t_cost(),
+                parallelism: params.p_cost(),
+            })
+        } else {
+            let cost = hash.parse::<bcrypt::HashParts>().ok()?.get_cost();
+            Some(HashScheme::Bcrypt { cost })
+        }
+    }
+
+    fn hash(&self, password: &str) -> Result<String, AuthError> {
+        match *self {
+            HashScheme::Bcrypt { cost } => Ok(bcrypt::hash(password, cost)?),
+            HashScheme::Argon2id {
+                memory_kib,
+                iterations,
+                parallelism,
+            } => {
+                let params = Params::new(memory_kib, iterations, parallelism, None)
+                    .map_err(argon2::password_hash::Error::from)?;
+                let salt = SaltString::generate(&mut OsRng);
+                let hash = Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+                    .hash_password(password.as_bytes(), &salt)?;
+                Ok(hash.to_string())
+            }
+        }
+    }
+}
+
+fn verify_hash(password: &str, hash: &str) -> Result<bool, AuthError> {
+    if hash.starts_with("$argon2") {
+        // the parameters are read from the hash, not from the configuration
+        let hash = PasswordHash::new(hash)?;
+        match Argon2::default().verify_password(password.as_bytes(), &hash) {
+            Ok(()) => Ok(true),
+            Err(argon2::password_hash::Error::Password) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    } else {
+        Ok(bcrypt::verify(password, hash)?)
+    }
+}
+
+/// Whether a stored hash should be replaced by a hash with the configured scheme,
+/// which can only be done when the password is known, i.e. on login
+pub fn needs_rehash(hash: &str) -> bool {
+    HashScheme::detect(hash).as_ref() != Some(&*SCHEME)
+}
 
 // consume password value to make it unusable
 pub async fn hash(password: String) -> Result<String, AuthError> {
     let (send, recv) = tokio::sync::oneshot::channel();
     rayon::spawn(move || {
-        let result = bcrypt::hash(password, DEFAULT_COST);
+        let result = SCHEME.hash(&password);
         let _ = send.send(result);
     });
-    Ok(recv.await??)
+    recv.await?
 }
 
 pub async fn verify(password: String, hash: String) -> Result<bool, AuthError> {
     let (send, recv) = tokio::sync::oneshot::channel();
     rayon::spawn(move || {
-        let result = bcrypt::verify(password, &hash);
+        let result = verify_hash(&password, &hash);
         let _ = send.send(result);
     });
-    Ok(recv.await??)
+    recv.await?
 }
 
 #[cfg(test)]
@@ -31,4 +126,30 @@ mod tests {
         let valid = verify("hunter2".to_string(), hashed).await.unwrap();
         assert!(valid)
     }
+
+    #[test]
+    fn check_scheme_detection() {
+        let argon2 = HashScheme::Argon2id {
+            memory_kib: 8192,
+            iterations: 1,
+            parallelism: 1,
+        };
+        let argon2_hash = argon2.hash("hunter2").unwrap();
+        assert_eq!(HashScheme::detect(&argon2_hash), Some(argon2));
+        assert!(verify_hash("hunter2", &argon2_hash).unwrap());
+        assert!(!verify_hash("hunter3", &argon2_hash).unwrap());
+
+        // the hash of the seeded accounts
+        let bcrypt_hash = "$2b$12$4vHMK6tEnvz5YfNQBmFPFeJqdn6gnkbB7sOhDin.eKN.4C2FzTvAC";
+        assert_eq!(
+            HashScheme::detect(bcrypt_hash),
+            Some(HashScheme::Bcrypt { cost: 12 })
+        );
+        assert!(verify_hash("password", bcrypt_hash).unwrap());
+        assert_eq!(
+            needs_rehash(bcrypt_hash),
+            *SCHEME != HashScheme::Bcrypt { cost: 12 }
+        );
+        assert_eq!(HashScheme::detect("not a hash"), None);
+    }
 }
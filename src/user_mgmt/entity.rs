@@ -1,4 +1,6 @@
-use serde::{Deserialize, Serialize};
+use super::jwt::Role;
+use serde::de::IntoDeserializer;
+use serde::{Deserialize, Deserializer, Serialize};
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct User {
@@ -10,9 +12,95 @@ pub struct User {
     pub is_superuser: bool,
 }
 
+impl User {
+    pub fn role(&self) -> Role {
+        if self.is_superuser {
+            Role::Admin
+        } else {
+            Role::User
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum UserStatus {
+    Active,
+    Inactive,
+}
+
+/// The search of the admin console, every field is optional
+#[derive(Debug, Default, Deserialize)]
+pub struct UserFilter {
+    // matched against the email and the name
+    pub q: Option<String>,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub status: Option<UserStatus>,
+}
+
+/// The empty fields of a search form are sent as empty strings, e.g. the "all" option of a select
+pub fn empty_as_none<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    let value: Option<String> = Option::deserialize(deserializer)?;
+    match value.as_deref().map(str::trim) {
+        None | Some("") => Ok(None),
+        Some(value) => T::deserialize(value.to_string().into_deserializer()).map(Some),
+    }
+}
+
+/// Like `empty_as_none`, for the numbers of a form, which are sent as strings
+pub fn parse_empty_as_none<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    let value: Option<String> = Option::deserialize(deserializer)?;
+    match value.as_deref().map(str::trim) {
+        None | Some("") => Ok(None),
+        Some(value) => value.parse().map(Some).map_err(serde::de::Error::custom),
+    }
+}
+
+impl UserFilter {
+    /// The LIKE pattern of the search, its wildcards are escaped so that they match literally
+    pub fn pattern(&self) -> Option<String> {
+        let q = self.q.as_deref().map(str::trim).filter(|q| !q.is_empty())?;
+        let escaped = q
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_");
+        Some(format!("%{escaped}%"))
+    }
+
+    pub fn is_active(&self) -> Option<bool> {
+        self.status.map(|status| status == UserStatus::Active)
+    }
+}
+
 #[derive(Deserialize)]
 pub struct CreateUser {
     pub email: String,
     pub password: String,
     pub name: String,
 }
+
+/// The registration form, the invite code is required if the registration is invite-only
+#[derive(Deserialize)]
+pub struct RegisterUser {
+    #[serde(flatten)]
+    pub user: CreateUser,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub invite_code: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct NamedRole {
+    pub id: i32,
+    pub name: String,
+    pub description: String,
+    pub permissions: Vec<String>,
+}
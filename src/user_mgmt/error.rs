@@ -1,28 +1,55 @@
 use axum::{
-    http::StatusCode,
+    http::{
+        header::{RETRY_AFTER, WWW_AUTHENTICATE},
+        StatusCode,
+    },
     response::{Html, IntoResponse, Response},
 };
 use thiserror::Error;
 
+use crate::common::error::CommonError;
+
 #[derive(Debug, Error)]
 pub enum AuthError {
     #[error("Wrong credentials")]
     WrongCredentials,
     #[error("Please <a href='/login'>log in</a> first")]
     MissingCredentials,
-    #[error("Unverified user")]
+    #[error("Please <a href='/verify-email'>verify your email</a> first")]
     UnverifiedUser,
+    #[error("Your account is deactivated")]
+    InactiveUser,
+    #[error("Too many failed attempts, please try again in {retry_after} seconds")]
+    TooManyAttempts { retry_after: u64 },
+    #[error("You are not allowed to do this")]
+    Forbidden,
+    #[error("This is not allowed while impersonating")]
+    Impersonating,
+    #[error("The registration is closed")]
+    RegistrationClosed,
+    #[error("Invalid code")]
+    InvalidCode,
     #[error("Invalid token")]
     InvalidToken,
+    #[error("Your session has expired, please reload the page")]
+    InvalidCsrfToken,
     #[error("Token creation error")]
     TokenCreation,
     #[error("Email already exists")]
     EmailExists,
+    #[error("No account is linked to this identity")]
+    UnknownIdentity,
+    #[error("An account with this email exists, log in to it first to link this identity")]
+    IdentityNotLinked,
+    #[error("The identity provider failed: {0}")]
+    IdentityProvider(String),
     #[error(transparent)]
-    CommonError(#[from] crate::common::error::CommonError),
+    CommonError(#[from] CommonError),
     #[error(transparent)]
     BcryptError(#[from] bcrypt::BcryptError),
     #[error(transparent)]
+    Argon2Error(#[from] argon2::password_hash::Error),
+    #[error(transparent)]
     JwtError(#[from] jsonwebtoken::errors::Error),
     #[error(transparent)]
     TokioRecvError(#[from] tokio::sync::oneshot::error::RecvError),
@@ -37,16 +64,48 @@ impl IntoResponse for AuthError {
         let status = match self {
             AuthError::WrongCredentials
             | AuthError::InvalidToken
+            | AuthError::InvalidCode
             | AuthError::MissingCredentials
-            | AuthError::UnverifiedUser => StatusCode::UNAUTHORIZED,
+            | AuthError::UnverifiedUser
+            | AuthError::UnknownIdentity => StatusCode::UNAUTHORIZED,
             AuthError::JwtError(ref e) => match e.kind() {
-                jsonwebtoken::errors::ErrorKind::ExpiredSignature => StatusCode::UNAUTHORIZED,
-                _ => StatusCode::INTERNAL_SERVER_ERROR,
+                // a broken key is our fault, anything else is a token that is not valid
+                jsonwebtoken::errors::ErrorKind::InvalidKeyFormat
+                | jsonwebtoken::errors::ErrorKind::InvalidRsaKey(_)
+                | jsonwebtoken::errors::ErrorKind::InvalidEcdsaKey
+                | jsonwebtoken::errors::ErrorKind::RsaFailedSigning
+                | jsonwebtoken::errors::ErrorKind::Crypto(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                _ => StatusCode::UNAUTHORIZED,
             },
-            AuthError::EmailExists => StatusCode::CONFLICT,
+            AuthError::Forbidden
+            | AuthError::InactiveUser
+            | AuthError::InvalidCsrfToken
+            | AuthError::Impersonating
+            | AuthError::RegistrationClosed => StatusCode::FORBIDDEN,
+            AuthError::TooManyAttempts { retry_after } => {
+                let body = Html(format!("<span>{}</span>", self));
+                return (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    [(RETRY_AFTER, retry_after.to_string())],
+                    body,
+                )
+                    .into_response();
+            }
+            AuthError::EmailExists | AuthError::IdentityNotLinked => StatusCode::CONFLICT,
+            AuthError::IdentityProvider(_) => StatusCode::BAD_GATEWAY,
+            AuthError::CommonError(CommonError::NotFound) => StatusCode::NOT_FOUND,
+            AuthError::CommonError(CommonError::ValidationError(_)) => StatusCode::BAD_REQUEST,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         };
+        // the access token is missing or expired, the pages renew it when they see the challenge
+        let is_token_error = matches!(
+            self,
+            AuthError::MissingCredentials | AuthError::InvalidToken | AuthError::JwtError(_)
+        );
         let body = Html(format!("<span>{}</span>", self));
+        if is_token_error && status == StatusCode::UNAUTHORIZED {
+            return (status, [(WWW_AUTHENTICATE, "Bearer")], body).into_response();
+        }
         (status, body).into_response()
     }
 }
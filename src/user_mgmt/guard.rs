@@ -0,0 +1,179 @@
+use super::auth::{CurrentUser, Role};
+use super::error::AuthError;
+use crate::common::entity::AppState;
+use axum::async_trait;
+use axum::extract::{FromRef, FromRequestParts};
+use axum::http::request::Parts;
+use axum::middleware::{from_extractor_with_state, FromExtractorLayer};
+use sqlx::PgPool;
+use std::marker::PhantomData;
+
+/// A type level role, so that the required role can be written as `RequireRole<Admin>`
+pub trait RequiredRole: Send + Sync + 'static {
+    const ROLE: Role;
+}
+
+pub struct Admin;
+
+impl RequiredRole for Admin {
+    const ROLE: Role = Role::Admin;
+}
+
+/// Extract the current user only if it has the required role,
+/// it rejects with 401 if nobody is logged in and with 403 if the role is insufficient
+pub struct RequireRole<R: RequiredRole> {
+    pub user: CurrentUser,
+    _role: PhantomData<R>,
+}
+
+#[async_trait]
+impl<S, R> FromRequestParts<S> for RequireRole<R>
+where
+    S: Send + Sync,
+    PgPool: FromRef<S>,
+    R: RequiredRole,
+{
+    type Rejection = AuthError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let user = CurrentUser::from_request_parts(parts, state).await?;
+        if !user.role.satisfies(&R::ROLE) {
+            tracing::debug!("{} is not allowed to access {}", user.email, parts.uri);
+            return Err(AuthError::Forbidden);
+        }
+        Ok(Self {
+            user,
+            _role: PhantomData,
+        })
+    }
+}
+
+/// Guard the routes of a router, use it with `Router::route_layer`
+/// so that it only applies to the routes added before it
+pub fn require_role<R: RequiredRole>(
+    state: AppState,
+) -> FromExtractorLayer<RequireRole<R>, AppState> {
+    from_extractor_with_state::<RequireRole<R>, _>(state)
+}
+
+/// A type level permission, so that the required permission can be written as
+/// `RequirePermission<UsersRead>`
+pub trait RequiredPermission: Send + Sync + 'static {
+    const PERMISSION: &'static str;
+}
+
+pub struct UsersRead;
+pub struct UsersWrite;
+pub struct RolesRead;
+pub struct RolesWrite;
+pub struct AuditRead;
+
+impl RequiredPermission for UsersRead {
+    const PERMISSION: &'static str = "users:read";
+}
+
+impl RequiredPermission for UsersWrite {
+    const PERMISSION: &'static str = "users:write";
+}
+
+impl RequiredPermission for RolesRead {
+    const PERMISSION: &'static str = "roles:read";
+}
+
+impl RequiredPermission for RolesWrite {
+    const PERMISSION: &'static str = "roles:write";
+}
+
+impl RequiredPermission for AuditRead {
+    const PERMISSION: &'static str = "audit:read";
+}
+
+/// Extract the current user only if it has the required permission
+pub struct RequirePermission<P: RequiredPermission> {
+    pub user: CurrentUser,
+    _permission: PhantomData<P>,
+}
+
+#[async_trait]
+impl<S, P> FromRequestParts<S> for RequirePermission<P>
+where
+    S: Send + Sync,
+    PgPool: FromRef<S>,
+    P: RequiredPermission,
+{
+    type Rejection = AuthError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let user = CurrentUser::from_request_parts(parts, state).await?;
+        if !user.has_permission(P::PERMISSION) {
+            tracing::debug!("{} has no permission {}", user.email, P::PERMISSION);
+            return Err(AuthError::Forbidden);
+        }
+        Ok(Self {
+            user,
+            _permission: PhantomData,
+        })
+    }
+}
+
+/// Same as `require_role` but for a permission
+pub fn require_permission<P: RequiredPermission>(
+    state: AppState,
+) -> FromExtractorLayer<RequirePermission<P>, AppState> {
+    from_extractor_with_state::<RequirePermission<P>, _>(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::db::postgres::get_test_postgres_pool;
+    use crate::common::mail::LogMailer;
+    use crate::user_mgmt::jwt::{encode, Claims};
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use axum::routing::{get, post};
+    use axum::Router;
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    async fn get_status(router: &Router, method: &str, token: Option<String>) -> StatusCode {
+        let mut request = Request::builder().method(method).uri("/");
+        if let Some(token) = token {
+            request = request.header("Authorization", format!("Bearer {token}"));
+        }
+        let request = request.body(Body::empty()).unwrap();
+        router.clone().oneshot(request).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn admin_routes_are_guarded() {
+        let state = AppState {
+            pool: get_test_postgres_pool(),
+            mailer: Arc::new(LogMailer),
+        };
+        let router = Router::new()
+            .route("/", get(|| async { "admin" }))
+            .route_layer(require_role::<Admin>(state.clone()))
+            .route("/", post(|| async { "anyone" }))
+            .with_state(state);
+
+        let admin_id = uuid::Uuid::parse_str("93cb6fd1-0bef-473a-87f7-655386777578").unwrap();
+        let user_id = uuid::Uuid::parse_str("00571729-af3e-4cb7-a693-ca0c82efed77").unwrap();
+        let admin_token = encode(&Claims::new(admin_id, Role::Admin)).unwrap();
+        let user_token = encode(&Claims::new(user_id, Role::User)).unwrap();
+
+        assert_eq!(
+            get_status(&router, "GET", None).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            get_status(&router, "GET", Some(user_token)).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            get_status(&router, "GET", Some(admin_token)).await,
+            StatusCode::OK
+        );
+        assert_eq!(get_status(&router, "POST", None).await, StatusCode::OK);
+    }
+}
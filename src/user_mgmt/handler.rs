@@ -1,7 +1,12 @@
+use super::audit::{emit, AuditAction, AuditEvent};
 use super::encryption::hash;
-use super::entity::{CreateUser, User};
+use super::entity::{CreateUser, RegisterUser, User, UserFilter};
 use super::error::AuthError;
-use axum::extract::{Json, State};
+use super::invite::{register_user, REGISTRATION_POLICY};
+use super::verification::{issue_verification_token, send_verification_email};
+use crate::common::entity::{ClientInfo, Pagination};
+use crate::common::mail::SharedMailer;
+use axum::extract::{Json, Query, State};
 use axum::Form;
 use axum_htmx::HxRedirect;
 use sqlx::PgConnection;
@@ -9,27 +14,67 @@ use sqlx::PgPool;
 
 pub async fn create_user(
     State(pool): State<PgPool>,
-    Form(user): Form<CreateUser>,
+    State(mailer): State<SharedMailer>,
+    client: ClientInfo,
+    Form(payload): Form<RegisterUser>,
 ) -> Result<(HxRedirect, ()), AuthError> {
     let mut tx = pool.begin().await?;
 
-    insert_user(&mut tx, user)
-        .await
-        .inspect_err(|e| tracing::error!("Failed to create user: {e}"))?;
+    let email = payload.user.email.clone();
+    let (user_id, invite_id) = register_user(&mut tx, *REGISTRATION_POLICY, payload).await?;
+    let token = issue_verification_token(&mut tx, user_id).await?;
     tx.commit().await?;
 
+    // the users register themselves
+    let event = AuditEvent::new(AuditAction::UserCreated)
+        .actor(user_id)
+        .target(user_id)
+        .metadata(serde_json::json!({ "email": email, "invite_id": invite_id }));
+    emit(&pool, &client, event).await;
+    send_verification_email(&mailer, email, &token).await;
+
     Ok((HxRedirect("/login".parse().unwrap()), ()))
 }
 
-pub async fn query_users(pool: &PgPool) -> Result<Vec<User>, sqlx::Error> {
-    sqlx::query_as!(
+// a page of the admin console cannot be used to dump every user at once
+pub const MAX_USERS_PAGE_SIZE: usize = 100;
+
+pub async fn query_users(
+    pool: &PgPool,
+    filter: &UserFilter,
+    pagination: &Pagination,
+) -> Result<Vec<User>, AuthError> {
+    let pagination = pagination.clamped(MAX_USERS_PAGE_SIZE)?;
+    let users = sqlx::query_as!(
         User,
         r#"SELECT users.id, users.name, users.email, 
             users.is_active, users.is_verified, users.is_superuser
-            FROM users"#
+            FROM users
+            WHERE ($1::text IS NULL OR users.email ILIKE $1 OR users.name ILIKE $1)
+                AND ($2::boolean IS NULL OR users.is_active = $2)
+            ORDER BY users.email
+            LIMIT $3 OFFSET $4"#,
+        filter.pattern(),
+        filter.is_active(),
+        pagination.limit() as i64,
+        pagination.sql_offset(),
     )
     .fetch_all(pool)
-    .await
+    .await?;
+    Ok(users)
+}
+
+pub async fn query_users_count(pool: &PgPool, filter: &UserFilter) -> Result<usize, sqlx::Error> {
+    let count = sqlx::query_scalar!(
+        r#"SELECT COUNT(*) AS "count!" FROM users
+            WHERE ($1::text IS NULL OR users.email ILIKE $1 OR users.name ILIKE $1)
+                AND ($2::boolean IS NULL OR users.is_active = $2)"#,
+        filter.pattern(),
+        filter.is_active(),
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(count as usize)
 }
 
 pub async fn query_user(pool: &PgPool, user_id: uuid::Uuid) -> Result<User, sqlx::Error> {
@@ -45,10 +90,20 @@ pub async fn query_user(pool: &PgPool, user_id: uuid::Uuid) -> Result<User, sqlx
     .await
 }
 
-pub async fn show_users(State(pool): State<PgPool>) -> Result<Json<Vec<User>>, AuthError> {
-    let users = query_users(&pool).await?;
+/// The total number of matching users is sent in the X-Total-Count header,
+/// so that the body stays a list
+pub async fn show_users(
+    State(pool): State<PgPool>,
+    Query(filter): Query<UserFilter>,
+    pagination: Option<Query<Pagination>>,
+) -> Result<([(&'static str, String); 1], Json<Vec<User>>), AuthError> {
+    let pagination = pagination
+        .unwrap_or_default()
+        .clamped(MAX_USERS_PAGE_SIZE)?;
+    let users = query_users(&pool, &filter, &pagination).await?;
+    let count = query_users_count(&pool, &filter).await?;
 
-    Ok(Json(users))
+    Ok(([("x-total-count", count.to_string())], Json(users)))
 }
 
 pub async fn insert_user(
@@ -66,12 +121,83 @@ pub async fn insert_user(
     .fetch_one(tx)
     .await
     .inspect_err(|e| tracing::error!("Failed to insert user: {e}"))
-    .map_err(|e| match e {
+    .map_err(map_email_exists)?;
+
+    Ok(row.id)
+}
+
+/// The unique constraint tells whether the email is taken, without a race between check and write
+pub fn map_email_exists(e: sqlx::Error) -> AuthError {
+    match e {
         sqlx::Error::Database(ref e) if e.constraint() == Some("users_email_key") => {
             AuthError::EmailExists
         }
         _ => AuthError::DatabaseError(e),
-    })?;
+    }
+}
 
-    Ok(row.id)
+pub async fn update_password(
+    conn: &mut PgConnection,
+    user_id: uuid::Uuid,
+    password: String,
+) -> Result<(), AuthError> {
+    let hashed_password = hash(password).await?;
+
+    sqlx::query!(
+        "UPDATE users SET hashed_password = $1 WHERE id = $2",
+        hashed_password,
+        user_id,
+    )
+    .execute(conn)
+    .await
+    .inspect_err(|e| tracing::error!("Failed to update password: {e}"))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::db::postgres::get_test_postgres_pool;
+    use crate::user_mgmt::entity::UserStatus;
+
+    #[tokio::test]
+    async fn users_are_searched_and_paginated() {
+        let pool = get_test_postgres_pool();
+        let mut conn = pool.acquire().await.unwrap();
+        // a unique name so that only the users of this test match
+        let name = format!("Search_{}", uuid::Uuid::new_v4().simple());
+        for _ in 0..3 {
+            let user = CreateUser {
+                email: format!("{}@example.com", uuid::Uuid::new_v4()),
+                password: "password".to_string(),
+                name: name.clone(),
+            };
+            insert_user(&mut conn, user).await.unwrap();
+        }
+
+        let filter = UserFilter {
+            q: Some(name.to_lowercase()),
+            status: Some(UserStatus::Active),
+        };
+        assert_eq!(query_users_count(&pool, &filter).await.unwrap(), 3);
+        let pagination = Pagination {
+            page: 1,
+            page_size: 2,
+        };
+        let users = query_users(&pool, &filter, &pagination).await.unwrap();
+        assert_eq!(users.len(), 1);
+
+        let filter = UserFilter {
+            q: Some(name),
+            status: Some(UserStatus::Inactive),
+        };
+        assert_eq!(query_users_count(&pool, &filter).await.unwrap(), 0);
+        // the wildcards of LIKE are matched literally
+        let filter = UserFilter {
+            q: Some("%".to_string()),
+            status: None,
+        };
+        assert_eq!(query_users_count(&pool, &filter).await.unwrap(), 0);
+    }
 }
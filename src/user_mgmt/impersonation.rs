@@ -0,0 +1,193 @@
+use super::audit::{emit, AuditAction, AuditEvent};
+use super::auth::{add_impersonation_cookies, remove_auth_cookies, renew_tokens, CurrentUser};
+use super::denylist::revoke_access_token;
+use super::error::AuthError;
+use super::handler::query_user;
+use super::jwt::{encode, Claims, Role};
+use crate::common::entity::ClientInfo;
+use crate::common::error::CommonError;
+use axum::extract::{Path, State};
+use axum_extra::extract::cookie::CookieJar;
+use axum_htmx::HxRedirect;
+use sqlx::PgPool;
+
+/// An access token of the user that carries the admin as its actor,
+/// it is bound to the session of the admin so that it dies with it
+pub async fn issue_impersonation_token(
+    pool: &PgPool,
+    admin: &CurrentUser,
+    user_id: uuid::Uuid,
+) -> Result<(String, String), AuthError> {
+    // an impersonation is started by a logged-in admin, not by a script or another impersonation
+    if admin.api_key_id.is_some() || admin.impersonator_id.is_some() {
+        return Err(AuthError::Forbidden);
+    }
+    if admin.id == user_id {
+        return Err(CommonError::ValidationError("You cannot impersonate yourself".into()).into());
+    }
+    let user = query_user(pool, user_id).await?;
+    if !user.is_active {
+        return Err(CommonError::ValidationError(
+            "A deactivated user cannot be impersonated".into(),
+        )
+        .into());
+    }
+    // an admin cannot borrow the permissions of another admin
+    if user.is_superuser {
+        return Err(AuthError::Forbidden);
+    }
+
+    let mut claims = Claims::new(user.id, Role::User).impersonated_by(admin.id);
+    if let Some(session_id) = admin.session_id {
+        claims = claims.with_session(session_id);
+    }
+    let token = encode(&claims).map_err(|_| AuthError::TokenCreation)?;
+    Ok((token, user.email))
+}
+
+/// Act as another user until the impersonation is stopped or the access token expires,
+/// the refresh token of the admin is kept, so a refresh ends the impersonation as well
+#[tracing::instrument(name = "Impersonating user", skip(jar, pool, admin, client), fields(email = %admin.email))]
+pub async fn impersonate_user(
+    jar: CookieJar,
+    State(pool): State<PgPool>,
+    admin: CurrentUser,
+    client: ClientInfo,
+    Path(user_id): Path<uuid::Uuid>,
+) -> Result<(CookieJar, HxRedirect, ()), AuthError> {
+    let (token, email) = issue_impersonation_token(&pool, &admin, user_id).await?;
+    let event = AuditEvent::new(AuditAction::UserImpersonated)
+        .actor(admin.id)
+        .target(user_id);
+    emit(&pool, &client, event).await;
+
+    Ok((
+        add_impersonation_cookies(jar, token, &email),
+        HxRedirect("/me".parse().unwrap()),
+        (),
+    ))
+}
+
+/// Give the admin their own access token back
+#[tracing::instrument(name = "Stopping impersonation", skip_all)]
+pub async fn stop_impersonating(
+    jar: CookieJar,
+    State(pool): State<PgPool>,
+    client: ClientInfo,
+    claims: Claims,
+) -> Result<(CookieJar, HxRedirect, ()), AuthError> {
+    let admin_id =
+        claims.act.as_ref().map(|actor| actor.sub).ok_or_else(|| {
+            CommonError::ValidationError("You are not impersonating anyone".into())
+        })?;
+    revoke_access_token(&pool, &claims).await?;
+    let event = AuditEvent::new(AuditAction::UserImpersonationStopped)
+        .actor(admin_id)
+        .target(claims.sub);
+    emit(&pool, &client, event).await;
+
+    // the admin has to log in again if their own session ended in the meantime
+    match renew_tokens(jar.clone(), &pool, &client).await {
+        Ok((jar, _)) => Ok((jar, HxRedirect("/admin/users".parse().unwrap()), ())),
+        Err(_) => Ok((
+            remove_auth_cookies(jar),
+            HxRedirect("/login".parse().unwrap()),
+            (),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::db::postgres::get_test_postgres_pool;
+    use crate::common::entity::AppState;
+    use crate::common::mail::LogMailer;
+    use crate::user_mgmt::auth::get_current_user_from_id;
+    use crate::user_mgmt::entity::CreateUser;
+    use crate::user_mgmt::handler::insert_user;
+    use crate::user_mgmt::jwt::decode;
+    use crate::user_mgmt::profile::{change_password, ChangePassword};
+    use axum::body::Body;
+    use axum::http::header::AUTHORIZATION;
+    use axum::http::{Request, StatusCode};
+    use axum::response::IntoResponse;
+    use axum::routing::get;
+    use axum::{Form, Json, Router};
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    const ADMIN_ID: &str = "93cb6fd1-0bef-473a-87f7-655386777578";
+
+    #[tokio::test]
+    async fn impersonated_user_knows_the_admin_and_cannot_change_credentials() {
+        let pool = get_test_postgres_pool();
+        let mut conn = pool.acquire().await.unwrap();
+        let payload = CreateUser {
+            email: format!("{}@example.com", uuid::Uuid::new_v4()),
+            password: "password".to_string(),
+            name: "Impersonated".to_string(),
+        };
+        let user_id = insert_user(&mut conn, payload).await.unwrap();
+        let admin_id = uuid::Uuid::parse_str(ADMIN_ID).unwrap();
+        let admin = get_current_user_from_id(&pool, &admin_id).await;
+
+        // an admin cannot impersonate themselves
+        let result = issue_impersonation_token(&pool, &admin, admin_id).await;
+        assert!(matches!(
+            result,
+            Err(AuthError::CommonError(CommonError::ValidationError(_)))
+        ));
+
+        let (token, _) = issue_impersonation_token(&pool, &admin, user_id)
+            .await
+            .unwrap();
+        let claims = decode(&token).unwrap().claims;
+        assert_eq!(claims.sub, user_id);
+        assert_eq!(claims.actor_id(), admin_id);
+
+        let state = AppState {
+            pool: pool.clone(),
+            mailer: Arc::new(LogMailer),
+        };
+        let app = Router::new()
+            .route(
+                "/",
+                get(|user: CurrentUser| async move {
+                    Json((user.id, user.actor_id(), user.impersonator_id))
+                }),
+            )
+            .with_state(state);
+        let request = Request::builder()
+            .uri("/")
+            .header(AUTHORIZATION, format!("Bearer {token}"))
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let (id, actor_id, impersonator_id): (uuid::Uuid, uuid::Uuid, Option<uuid::Uuid>) =
+            serde_json::from_slice(&body).unwrap();
+        assert_eq!(id, user_id);
+        assert_eq!(actor_id, admin_id);
+        assert_eq!(impersonator_id, Some(admin_id));
+
+        let mut user = get_current_user_from_id(&pool, &user_id).await;
+        user.impersonator_id = Some(admin_id);
+        let payload = ChangePassword {
+            current_password: "password".to_string(),
+            new_password: "impersonated".to_string(),
+        };
+        let result = change_password(
+            CookieJar::new(),
+            State(pool.clone()),
+            user,
+            ClientInfo::default(),
+            Form(payload),
+        )
+        .await;
+        assert_eq!(result.into_response().status(), StatusCode::FORBIDDEN);
+    }
+}
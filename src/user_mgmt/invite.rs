@@ -0,0 +1,335 @@
+use super::admin::ADMIN_ROLE;
+use super::audit::{emit, AuditAction, AuditEvent};
+use super::auth::CurrentUser;
+use super::entity::{empty_as_none, parse_empty_as_none, RegisterUser};
+use super::error::AuthError;
+use super::handler::insert_user;
+use super::token::{generate_token, hash_token};
+use crate::common::entity::ClientInfo;
+use crate::common::error::CommonError;
+use crate::configuration::{get_configuration, RegistrationPolicy};
+use axum::extract::{Form, Path, State};
+use axum::http::StatusCode;
+use axum::Json;
+use chrono::{DateTime, Duration, Utc};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use sqlx::{PgConnection, PgPool};
+
+pub static REGISTRATION_POLICY: Lazy<RegistrationPolicy> = Lazy::new(|| {
+    let configuration = get_configuration().expect("Failed to load configuration.");
+    configuration.security.registration
+});
+
+const MAX_USES: i32 = 1000;
+const MAX_EXPIRES_IN_DAYS: i64 = 90;
+
+#[derive(Debug, Serialize)]
+pub struct Invite {
+    pub id: uuid::Uuid,
+    pub role: Option<String>,
+    pub max_uses: i32,
+    pub use_count: i32,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub created_by: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+impl Invite {
+    pub fn is_usable(&self) -> bool {
+        self.revoked_at.is_none()
+            && self.use_count < self.max_uses
+            && self
+                .expires_at
+                .is_none_or(|expires_at| expires_at > Utc::now())
+    }
+}
+
+fn one() -> i32 {
+    1
+}
+
+#[derive(Deserialize)]
+pub struct CreateInvite {
+    // the name of a role assigned to the users who register with the code
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub role: Option<String>,
+    #[serde(default = "one")]
+    pub max_uses: i32,
+    // the code never expires if it is not set
+    #[serde(default, deserialize_with = "parse_empty_as_none")]
+    pub expires_in_days: Option<i64>,
+}
+
+/// The code itself is only returned once, when it is created
+#[derive(Debug, Serialize)]
+pub struct CreatedInvite {
+    pub code: String,
+    #[serde(flatten)]
+    pub invite: Invite,
+}
+
+pub async fn query_invites(pool: &PgPool) -> Result<Vec<Invite>, sqlx::Error> {
+    sqlx::query_as!(
+        Invite,
+        r#"SELECT invites.id, roles.name AS "role?", invites.max_uses, invites.use_count,
+            invites.expires_at, users.email AS "created_by?", invites.created_at, invites.revoked_at
+        FROM invites
+        LEFT JOIN roles ON roles.id = invites.role_id
+        LEFT JOIN users ON users.id = invites.created_by
+        ORDER BY invites.created_at DESC"#
+    )
+    .fetch_all(pool)
+    .await
+}
+
+async fn query_invite(pool: &PgPool, invite_id: uuid::Uuid) -> Result<Invite, sqlx::Error> {
+    sqlx::query_as!(
+        Invite,
+        r#"SELECT invites.id, roles.name AS "role?", invites.max_uses, invites.use_count,
+            invites.expires_at, users.email AS "created_by?", invites.created_at, invites.revoked_at
+        FROM invites
+        LEFT JOIN roles ON roles.id = invites.role_id
+        LEFT JOIN users ON users.id = invites.created_by
+        WHERE invites.id = $1"#,
+        invite_id
+    )
+    .fetch_one(pool)
+    .await
+}
+
+pub async fn show_invites(State(pool): State<PgPool>) -> Result<Json<Vec<Invite>>, AuthError> {
+    Ok(Json(query_invites(&pool).await?))
+}
+
+/// The admin role cannot be handed out by a code, a user is promoted by an admin instead
+#[tracing::instrument(name = "Creating invite", skip(pool, admin, client, payload), fields(email = %admin.email))]
+pub async fn create_invite(
+    State(pool): State<PgPool>,
+    admin: CurrentUser,
+    client: ClientInfo,
+    Form(payload): Form<CreateInvite>,
+) -> Result<(StatusCode, Json<CreatedInvite>), AuthError> {
+    if !(1..=MAX_USES).contains(&payload.max_uses) {
+        return Err(CommonError::ValidationError(format!(
+            "An invite can be used 1 to {MAX_USES} times"
+        ))
+        .into());
+    }
+    let expires_at = match payload.expires_in_days {
+        Some(days) if (1..=MAX_EXPIRES_IN_DAYS).contains(&days) => {
+            Some(Utc::now() + Duration::days(days))
+        }
+        Some(_) => {
+            return Err(CommonError::ValidationError(format!(
+                "An invite expires in 1 to {MAX_EXPIRES_IN_DAYS} days"
+            ))
+            .into())
+        }
+        None => None,
+    };
+    let role_id = match payload.role.as_deref() {
+        Some(ADMIN_ROLE) => {
+            return Err(CommonError::ValidationError(
+                "An invite cannot grant the admin role".to_string(),
+            )
+            .into())
+        }
+        Some(role) => Some(
+            sqlx::query_scalar!("SELECT id FROM roles WHERE name = $1", role)
+                .fetch_optional(&pool)
+                .await?
+                .ok_or_else(|| CommonError::ValidationError(format!("Unknown role {role}")))?,
+        ),
+        None => None,
+    };
+
+    let code = generate_token();
+    let invite_id = sqlx::query_scalar!(
+        "INSERT INTO invites (code_hash, role_id, max_uses, expires_at, created_by)
+        VALUES ($1, $2, $3, $4, $5)
+        RETURNING id",
+        hash_token(&code),
+        role_id,
+        payload.max_uses,
+        expires_at,
+        admin.id,
+    )
+    .fetch_one(&pool)
+    .await?;
+    let invite = query_invite(&pool, invite_id).await?;
+
+    let event = AuditEvent::new(AuditAction::InviteCreated)
+        .actor(admin.id)
+        .metadata(serde_json::json!({
+            "invite_id": invite.id,
+            "role": invite.role,
+            "max_uses": invite.max_uses,
+        }));
+    emit(&pool, &client, event).await;
+
+    Ok((StatusCode::CREATED, Json(CreatedInvite { code, invite })))
+}
+
+/// The users who already registered with the code are kept
+#[tracing::instrument(name = "Revoking invite", skip(pool, admin, client), fields(email = %admin.email))]
+pub async fn revoke_invite(
+    State(pool): State<PgPool>,
+    admin: CurrentUser,
+    client: ClientInfo,
+    Path(invite_id): Path<uuid::Uuid>,
+) -> Result<StatusCode, AuthError> {
+    let result = sqlx::query!(
+        "UPDATE invites SET revoked_at = current_timestamp
+        WHERE id = $1 AND revoked_at IS NULL",
+        invite_id,
+    )
+    .execute(&pool)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Err(CommonError::NotFound.into());
+    }
+
+    let event = AuditEvent::new(AuditAction::InviteRevoked)
+        .actor(admin.id)
+        .metadata(serde_json::json!({ "invite_id": invite_id }));
+    emit(&pool, &client, event).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub struct RedeemedInvite {
+    pub id: uuid::Uuid,
+    pub role_id: Option<i32>,
+}
+
+/// Use the code once, it fails if the code is unknown, revoked, expired or used up
+pub async fn redeem_invite(
+    conn: &mut PgConnection,
+    code: &str,
+) -> Result<RedeemedInvite, AuthError> {
+    sqlx::query_as!(
+        RedeemedInvite,
+        "UPDATE invites SET use_count = use_count + 1
+        WHERE code_hash = $1 AND revoked_at IS NULL AND use_count < max_uses
+            AND (expires_at IS NULL OR expires_at > current_timestamp)
+        RETURNING id, role_id",
+        hash_token(code),
+    )
+    .fetch_optional(conn)
+    .await?
+    .ok_or_else(|| {
+        CommonError::ValidationError("The invite code is invalid or has expired".to_string()).into()
+    })
+}
+
+/// Create the user according to the registration policy, it returns the id of the user
+/// and the one of the invite it registered with.
+/// An invite code is optional if the registration is open, its role is still assigned
+pub async fn register_user(
+    conn: &mut PgConnection,
+    policy: RegistrationPolicy,
+    payload: RegisterUser,
+) -> Result<(uuid::Uuid, Option<uuid::Uuid>), AuthError> {
+    let invite = match (policy, payload.invite_code) {
+        (RegistrationPolicy::Closed, _) => return Err(AuthError::RegistrationClosed),
+        (RegistrationPolicy::InviteOnly, None) => {
+            return Err(CommonError::ValidationError(
+                "An invite code is required to register".to_string(),
+            )
+            .into())
+        }
+        (_, Some(code)) => Some(redeem_invite(conn, &code).await?),
+        (RegistrationPolicy::Open, None) => None,
+    };
+
+    // the use of the code is rolled back with the transaction if the user cannot be created
+    let user_id = insert_user(conn, payload.user)
+        .await
+        .inspect_err(|e| tracing::error!("Failed to create user: {e}"))?;
+    if let Some(role_id) = invite.as_ref().and_then(|invite| invite.role_id) {
+        sqlx::query!(
+            "INSERT INTO user_roles (user_id, role_id) VALUES ($1, $2)",
+            user_id,
+            role_id
+        )
+        .execute(conn)
+        .await?;
+    }
+    Ok((user_id, invite.map(|invite| invite.id)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::db::postgres::get_test_postgres_pool;
+    use crate::user_mgmt::entity::CreateUser;
+    use crate::user_mgmt::permission::query_user_roles;
+
+    fn registration(invite_code: Option<&str>) -> RegisterUser {
+        RegisterUser {
+            user: CreateUser {
+                email: format!("{}@example.com", uuid::Uuid::new_v4()),
+                password: "password".to_string(),
+                name: "Invited".to_string(),
+            },
+            invite_code: invite_code.map(str::to_string),
+        }
+    }
+
+    #[tokio::test]
+    async fn registration_follows_the_policy_and_the_invite() {
+        let pool = get_test_postgres_pool();
+        let mut conn = pool.acquire().await.unwrap();
+
+        let result = register_user(&mut conn, RegistrationPolicy::Closed, registration(None)).await;
+        assert!(matches!(result, Err(AuthError::RegistrationClosed)));
+        let result = register_user(
+            &mut conn,
+            RegistrationPolicy::InviteOnly,
+            registration(None),
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(AuthError::CommonError(CommonError::ValidationError(_)))
+        ));
+
+        let code = generate_token();
+        sqlx::query!(
+            "INSERT INTO invites (code_hash, role_id, max_uses)
+            SELECT $1, roles.id, 1 FROM roles WHERE roles.name = 'support'",
+            hash_token(&code),
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let (user_id, invite_id) = register_user(
+            &mut conn,
+            RegistrationPolicy::InviteOnly,
+            registration(Some(&code)),
+        )
+        .await
+        .unwrap();
+        assert!(invite_id.is_some());
+        let roles = query_user_roles(&pool, user_id).await.unwrap();
+        assert_eq!(roles, vec!["support".to_string()]);
+
+        // the code is used up
+        let result = register_user(
+            &mut conn,
+            RegistrationPolicy::Open,
+            registration(Some(&code)),
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(AuthError::CommonError(CommonError::ValidationError(_)))
+        ));
+        let (_, invite_id) = register_user(&mut conn, RegistrationPolicy::Open, registration(None))
+            .await
+            .unwrap();
+        assert!(invite_id.is_none());
+    }
+}
@@ -0,0 +1,231 @@
+use super::auth::{add_login_cookies, record_login};
+use super::email::magic_link_email;
+use super::error::AuthError;
+use super::handler::query_user;
+use super::throttle::throttle_email;
+use super::token::{
+    consume_browser_bound_token, generate_token, issue_browser_bound_token, TokenPurpose,
+};
+use super::totp::{add_mfa_challenge_cookie, is_totp_enabled};
+use crate::common::entity::ClientInfo;
+use crate::common::mail::SharedMailer;
+use crate::configuration::{get_environment, Environment};
+use axum::extract::State;
+use axum::response::Html;
+use axum::Form;
+use axum_extra::extract::cookie::{Cookie, CookieJar, SameSite};
+use axum_htmx::HxRedirect;
+use chrono::Duration;
+use serde::Deserialize;
+use sqlx::PgPool;
+
+const MAGIC_LINK_TTL_MINUTES: i64 = 15;
+// the secret of the browser that asked for the link, the link only works together with it
+const MAGIC_LINK_COOKIE: &str = "magic_link";
+const MAGIC_LINK_PATH: &str = "/api/v1/auth/magic-link";
+
+#[derive(Deserialize)]
+pub struct RequestMagicLink {
+    pub email: String,
+}
+
+#[derive(Deserialize)]
+pub struct VerifyMagicLink {
+    pub token: String,
+}
+
+fn browser_cookie(secret: String, max_age: Duration) -> Cookie<'static> {
+    Cookie::build((MAGIC_LINK_COOKIE, secret))
+        .http_only(true)
+        .secure(get_environment() != Environment::Local)
+        .same_site(SameSite::Strict)
+        .max_age(cookie::time::Duration::seconds(max_age.num_seconds()))
+        .path(MAGIC_LINK_PATH)
+        .build()
+}
+
+/// The response is the same whether the email exists or not,
+/// so that it cannot be used to find out who has an account
+#[tracing::instrument(name = "Requesting magic link", skip(jar, pool, mailer, client, payload), fields(email = %payload.email))]
+pub async fn request_magic_link(
+    jar: CookieJar,
+    State(pool): State<PgPool>,
+    State(mailer): State<SharedMailer>,
+    client: ClientInfo,
+    Form(payload): Form<RequestMagicLink>,
+) -> Result<(CookieJar, Html<&'static str>), AuthError> {
+    throttle_email(&pool, &payload.email, &client).await?;
+    // a new link invalidates the previous one, so the browser keeps the secret of the latest
+    let secret = generate_token();
+    let user_id = sqlx::query_scalar!(
+        "SELECT id FROM users WHERE email = $1 AND is_active",
+        payload.email
+    )
+    .fetch_optional(&pool)
+    .await?;
+
+    if let Some(user_id) = user_id {
+        let mut conn = pool.acquire().await?;
+        let token = issue_browser_bound_token(
+            &mut conn,
+            user_id,
+            TokenPurpose::MagicLink,
+            Duration::minutes(MAGIC_LINK_TTL_MINUTES),
+            &secret,
+        )
+        .await?;
+        let _ = mailer
+            .send(magic_link_email(payload.email, &token))
+            .await
+            .inspect_err(|e| tracing::error!("Failed to send magic link email: {e}"));
+    }
+
+    let cookie = browser_cookie(secret, Duration::minutes(MAGIC_LINK_TTL_MINUTES));
+    Ok((
+        jar.add(cookie),
+        Html(
+            "<span>If an account exists for this email, a sign-in link has been sent to it.</span>",
+        ),
+    ))
+}
+
+/// Log in with the token of the link, like `login` does with the password.
+/// The link is opened with a GET, it is only used by this POST so that the scanners of the
+/// mailboxes that open the links cannot use it up
+#[tracing::instrument(name = "Logging in with magic link", skip_all)]
+pub async fn verify_magic_link(
+    jar: CookieJar,
+    State(pool): State<PgPool>,
+    client: ClientInfo,
+    Form(payload): Form<VerifyMagicLink>,
+) -> Result<(CookieJar, HxRedirect, ()), AuthError> {
+    let secret = jar
+        .get(MAGIC_LINK_COOKIE)
+        .map(|cookie| cookie.value().to_string())
+        .ok_or(AuthError::InvalidToken)?;
+
+    let mut tx = pool.begin().await?;
+    let user_id =
+        consume_browser_bound_token(&mut tx, &payload.token, TokenPurpose::MagicLink, &secret)
+            .await?;
+    // the link proves that the user can read the emails of the address
+    sqlx::query!("UPDATE users SET is_verified = true WHERE id = $1", user_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    let user = query_user(&pool, user_id).await?;
+    if !user.is_active {
+        return Err(AuthError::InactiveUser);
+    }
+    let jar = jar.add(browser_cookie(String::new(), Duration::zero()));
+    // the link replaces the password, not the second factor
+    if is_totp_enabled(&pool, user_id).await? {
+        return Ok((
+            add_mfa_challenge_cookie(jar, &pool, user_id).await?,
+            HxRedirect("/login/2fa".parse().unwrap()),
+            (),
+        ));
+    }
+    record_login(&pool, &client, user_id, false).await?;
+
+    Ok((
+        add_login_cookies(jar, &pool, user_id, user.role(), &client).await?,
+        HxRedirect("/me".parse().unwrap()),
+        (),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::db::postgres::get_test_postgres_pool;
+    use crate::common::mail::{Email, Mailer};
+    use crate::user_mgmt::entity::CreateUser;
+    use crate::user_mgmt::handler::insert_user;
+    use async_trait::async_trait;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Default)]
+    struct RecordingMailer {
+        emails: Mutex<Vec<Email>>,
+    }
+
+    #[async_trait]
+    impl Mailer for RecordingMailer {
+        async fn send(&self, email: Email) -> anyhow::Result<()> {
+            self.emails.lock().unwrap().push(email);
+            Ok(())
+        }
+    }
+
+    async fn verify(pool: &PgPool, secret: &str, token: &str) -> Result<HxRedirect, AuthError> {
+        let jar = CookieJar::new().add(Cookie::new(MAGIC_LINK_COOKIE, secret.to_string()));
+        let payload = VerifyMagicLink {
+            token: token.to_string(),
+        };
+        verify_magic_link(
+            jar,
+            State(pool.clone()),
+            ClientInfo::default(),
+            Form(payload),
+        )
+        .await
+        .map(|(_, redirect, _)| redirect)
+    }
+
+    #[tokio::test]
+    async fn magic_link_is_single_use_and_bound_to_the_browser() {
+        // the pool loads the configuration, and APP_ENVIRONMENT along with it for the cookie
+        let pool = get_test_postgres_pool();
+        let mut conn = pool.acquire().await.unwrap();
+        let email = format!("{}@example.com", uuid::Uuid::new_v4());
+        let payload = CreateUser {
+            email: email.clone(),
+            password: "password".to_string(),
+            name: "Magic".to_string(),
+        };
+        insert_user(&mut conn, payload).await.unwrap();
+
+        let mailer = Arc::new(RecordingMailer::default());
+        let (jar, _) = request_magic_link(
+            CookieJar::new(),
+            State(pool.clone()),
+            State(mailer.clone() as SharedMailer),
+            ClientInfo::default(),
+            Form(RequestMagicLink {
+                email: email.clone(),
+            }),
+        )
+        .await
+        .unwrap();
+        let secret = jar.get(MAGIC_LINK_COOKIE).unwrap().value().to_string();
+        let body = mailer.emails.lock().unwrap()[0].body.clone();
+        let token = body
+            .split("token=")
+            .nth(1)
+            .and_then(|rest| rest.split_whitespace().next())
+            .unwrap()
+            .to_string();
+
+        // the mailbox is not flooded with links, nor is the first one replaced
+        let result = request_magic_link(
+            CookieJar::new(),
+            State(pool.clone()),
+            State(mailer.clone() as SharedMailer),
+            ClientInfo::default(),
+            Form(RequestMagicLink { email }),
+        )
+        .await;
+        assert!(matches!(result, Err(AuthError::TooManyAttempts { .. })));
+        assert_eq!(mailer.emails.lock().unwrap().len(), 1);
+
+        // another browser cannot use the link, and does not use it up
+        let result = verify(&pool, &generate_token(), &token).await;
+        assert!(matches!(result, Err(AuthError::InvalidToken)));
+        let redirect = verify(&pool, &secret, &token).await.unwrap();
+        assert_eq!(redirect.0, "/me");
+        let result = verify(&pool, &secret, &token).await;
+        assert!(matches!(result, Err(AuthError::InvalidToken)));
+    }
+}
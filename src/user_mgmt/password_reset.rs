@@ -0,0 +1,160 @@
+use super::api_key::revoke_user_api_keys;
+use super::denylist::revoke_user_tokens;
+use super::email::password_reset_email;
+use super::error::AuthError;
+use super::handler::update_password;
+use super::throttle::throttle_email;
+use super::token::{consume_user_token, issue_user_token, TokenPurpose};
+use crate::common::entity::ClientInfo;
+use crate::common::mail::SharedMailer;
+use axum::extract::State;
+use axum::response::Html;
+use axum::Form;
+use axum_htmx::HxRedirect;
+use chrono::Duration;
+use serde::Deserialize;
+use sqlx::{PgConnection, PgPool};
+
+const PASSWORD_RESET_TOKEN_TTL_HOURS: i64 = 1;
+
+#[derive(Deserialize)]
+pub struct ForgotPassword {
+    pub email: String,
+}
+
+#[derive(Deserialize)]
+pub struct ResetPassword {
+    pub token: String,
+    pub password: String,
+}
+
+pub async fn issue_password_reset_token(
+    conn: &mut PgConnection,
+    user_id: uuid::Uuid,
+) -> Result<String, AuthError> {
+    issue_user_token(
+        conn,
+        user_id,
+        TokenPurpose::PasswordReset,
+        Duration::hours(PASSWORD_RESET_TOKEN_TTL_HOURS),
+    )
+    .await
+}
+
+pub async fn send_password_reset_email(mailer: &SharedMailer, email: String, token: &str) {
+    let _ = mailer
+        .send(password_reset_email(email, token))
+        .await
+        .inspect_err(|e| tracing::error!("Failed to send password reset email: {e}"));
+}
+
+/// The response is the same whether the email exists or not,
+/// so that it cannot be used to find out who has an account
+#[tracing::instrument(name = "Requesting password reset", skip(pool, mailer, client, payload), fields(email = %payload.email))]
+pub async fn forgot_password(
+    State(pool): State<PgPool>,
+    State(mailer): State<SharedMailer>,
+    client: ClientInfo,
+    Form(payload): Form<ForgotPassword>,
+) -> Result<Html<&'static str>, AuthError> {
+    throttle_email(&pool, &payload.email, &client).await?;
+    let user_id = sqlx::query_scalar!("SELECT id FROM users WHERE email = $1", payload.email)
+        .fetch_optional(&pool)
+        .await?;
+
+    if let Some(user_id) = user_id {
+        let mut conn = pool.acquire().await?;
+        let token = issue_password_reset_token(&mut conn, user_id).await?;
+        send_password_reset_email(&mailer, payload.email, &token).await;
+    }
+
+    Ok(Html(
+        "<span>If an account exists for this email, a link to reset the password has been sent to it.</span>",
+    ))
+}
+
+#[tracing::instrument(name = "Resetting password", skip(pool, payload))]
+pub async fn reset_password(
+    State(pool): State<PgPool>,
+    Form(payload): Form<ResetPassword>,
+) -> Result<(HxRedirect, ()), AuthError> {
+    if payload.password.is_empty() {
+        return Err(AuthError::MissingCredentials);
+    }
+
+    let mut tx = pool.begin().await?;
+    let user_id = consume_user_token(&mut tx, &payload.token, TokenPurpose::PasswordReset).await?;
+    update_password(&mut tx, user_id, payload.password).await?;
+    // whoever knew the old password is logged out, and loses the keys they may have created
+    revoke_user_tokens(&mut tx, user_id).await?;
+    revoke_user_api_keys(&mut tx, user_id).await?;
+    // the link was received by email, so the email is verified as well
+    sqlx::query!("UPDATE users SET is_verified = true WHERE id = $1", user_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    Ok((HxRedirect("/login".parse().unwrap()), ()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::db::postgres::get_test_postgres_pool;
+    use crate::user_mgmt::denylist::is_revoked;
+    use crate::user_mgmt::entity::CreateUser;
+    use crate::user_mgmt::handler::insert_user;
+    use crate::user_mgmt::jwt::{Claims, Role};
+
+    #[tokio::test]
+    async fn reset_password_revokes_issued_tokens() {
+        let pool = get_test_postgres_pool();
+        let mut conn = pool.acquire().await.unwrap();
+        let user = CreateUser {
+            email: format!("{}@example.com", uuid::Uuid::new_v4()),
+            password: "password".to_string(),
+            name: "Reset".to_string(),
+        };
+        let user_id = insert_user(&mut conn, user).await.unwrap();
+        sqlx::query!(
+            "INSERT INTO api_keys (user_id, name, key_prefix, key_hash, scopes)
+            VALUES ($1, 'key', 'pak_test', $2, '{}')",
+            user_id,
+            uuid::Uuid::new_v4().to_string(),
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let claims = Claims::new(user_id, Role::User);
+        assert!(!is_revoked(&pool, &claims).await.unwrap());
+        // iat has a precision of seconds
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+
+        let token = issue_user_token(
+            &mut conn,
+            user_id,
+            TokenPurpose::PasswordReset,
+            Duration::hours(1),
+        )
+        .await
+        .unwrap();
+        let payload = ResetPassword {
+            token,
+            password: "new password".to_string(),
+        };
+        reset_password(State(pool.clone()), Form(payload))
+            .await
+            .unwrap();
+
+        assert!(is_revoked(&pool, &claims).await.unwrap());
+        // unlike a logout everywhere, the reset revokes the API keys as well
+        let active_keys = sqlx::query_scalar!(
+            r#"SELECT COUNT(*) AS "count!" FROM api_keys WHERE user_id = $1 AND revoked_at IS NULL"#,
+            user_id
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(active_keys, 0);
+    }
+}
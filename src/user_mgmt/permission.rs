@@ -0,0 +1,292 @@
+use super::admin::ADMIN_ROLE;
+use super::audit::{emit, AuditAction, AuditEvent};
+use super::auth::CurrentUser;
+use super::entity::NamedRole;
+use super::error::AuthError;
+use super::jwt::Role;
+use crate::common::entity::ClientInfo;
+use crate::common::error::CommonError;
+use axum::extract::{Json, Path, State};
+use axum::http::StatusCode;
+use sqlx::PgPool;
+
+pub async fn query_user_permissions(
+    pool: &PgPool,
+    user_id: uuid::Uuid,
+) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query_scalar!(
+        "SELECT DISTINCT permissions.name FROM user_roles
+        JOIN role_permissions ON role_permissions.role_id = user_roles.role_id
+        JOIN permissions ON permissions.id = role_permissions.permission_id
+        WHERE user_roles.user_id = $1
+        ORDER BY permissions.name",
+        user_id
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn query_roles(pool: &PgPool) -> Result<Vec<NamedRole>, sqlx::Error> {
+    sqlx::query_as!(
+        NamedRole,
+        r#"SELECT roles.id, roles.name, roles.description,
+            ARRAY_REMOVE(ARRAY_AGG(permissions.name ORDER BY permissions.name), NULL) AS "permissions!"
+        FROM roles
+        LEFT JOIN role_permissions ON role_permissions.role_id = roles.id
+        LEFT JOIN permissions ON permissions.id = role_permissions.permission_id
+        GROUP BY roles.id
+        ORDER BY roles.id"#
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn query_user_roles(
+    pool: &PgPool,
+    user_id: uuid::Uuid,
+) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query_scalar!(
+        "SELECT roles.name FROM user_roles
+        JOIN roles ON roles.id = user_roles.role_id
+        WHERE user_roles.user_id = $1
+        ORDER BY roles.name",
+        user_id
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn assign_role(
+    pool: &PgPool,
+    user_id: uuid::Uuid,
+    role_name: &str,
+) -> Result<(), AuthError> {
+    let result = sqlx::query!(
+        "INSERT INTO user_roles (user_id, role_id)
+        SELECT users.id, roles.id FROM users, roles
+        WHERE users.id = $1 AND roles.name = $2
+        ON CONFLICT DO NOTHING",
+        user_id,
+        role_name
+    )
+    .execute(pool)
+    .await?;
+
+    // nothing is inserted if the user or the role does not exist, or if it is already assigned
+    if result.rows_affected() == 0 && !has_role(pool, user_id, role_name).await? {
+        return Err(CommonError::NotFound.into());
+    }
+    Ok(())
+}
+
+pub async fn unassign_role(
+    pool: &PgPool,
+    user_id: uuid::Uuid,
+    role_name: &str,
+) -> Result<(), AuthError> {
+    sqlx::query!(
+        "DELETE FROM user_roles
+        USING roles
+        WHERE user_roles.role_id = roles.id AND user_roles.user_id = $1 AND roles.name = $2",
+        user_id,
+        role_name
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+async fn has_role(
+    pool: &PgPool,
+    user_id: uuid::Uuid,
+    role_name: &str,
+) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"SELECT EXISTS(
+            SELECT 1 FROM user_roles JOIN roles ON roles.id = user_roles.role_id
+            WHERE user_roles.user_id = $1 AND roles.name = $2
+        ) AS "exists!""#,
+        user_id,
+        role_name
+    )
+    .fetch_one(pool)
+    .await
+}
+
+async fn query_role_permissions(
+    pool: &PgPool,
+    role_name: &str,
+) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query_scalar!(
+        "SELECT permissions.name FROM roles
+        JOIN role_permissions ON role_permissions.role_id = roles.id
+        JOIN permissions ON permissions.id = role_permissions.permission_id
+        WHERE roles.name = $1",
+        role_name
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// roles:write must not be a way up: nobody assigns a role to themselves,
+/// only an admin grants the admin role, and nobody grants a permission they do not hold
+async fn forbid_escalation(
+    pool: &PgPool,
+    user: &CurrentUser,
+    user_id: uuid::Uuid,
+    role_name: &str,
+) -> Result<(), AuthError> {
+    if user.id == user_id || (role_name == ADMIN_ROLE && user.role != Role::Admin) {
+        return Err(AuthError::Forbidden);
+    }
+    let permissions = query_role_permissions(pool, role_name).await?;
+    if !permissions
+        .iter()
+        .all(|permission| user.permissions.contains(permission))
+    {
+        return Err(AuthError::Forbidden);
+    }
+    Ok(())
+}
+
+pub async fn show_roles(State(pool): State<PgPool>) -> Result<Json<Vec<NamedRole>>, AuthError> {
+    let roles = query_roles(&pool).await?;
+    Ok(Json(roles))
+}
+
+pub async fn show_user_roles(
+    State(pool): State<PgPool>,
+    Path(user_id): Path<uuid::Uuid>,
+) -> Result<Json<Vec<String>>, AuthError> {
+    let roles = query_user_roles(&pool, user_id).await?;
+    Ok(Json(roles))
+}
+
+#[tracing::instrument(name = "Assigning role", skip(pool, user, client), fields(admin = %user.email))]
+pub async fn add_user_role(
+    State(pool): State<PgPool>,
+    user: CurrentUser,
+    client: ClientInfo,
+    Path((user_id, role_name)): Path<(uuid::Uuid, String)>,
+) -> Result<StatusCode, AuthError> {
+    forbid_escalation(&pool, &user, user_id, &role_name).await?;
+    assign_role(&pool, user_id, &role_name).await?;
+    let event = AuditEvent::new(AuditAction::RoleAssigned)
+        .actor(user.id)
+        .target(user_id)
+        .metadata(serde_json::json!({ "role": role_name }));
+    emit(&pool, &client, event).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[tracing::instrument(name = "Unassigning role", skip(pool, user, client), fields(admin = %user.email))]
+pub async fn remove_user_role(
+    State(pool): State<PgPool>,
+    user: CurrentUser,
+    client: ClientInfo,
+    Path((user_id, role_name)): Path<(uuid::Uuid, String)>,
+) -> Result<StatusCode, AuthError> {
+    unassign_role(&pool, user_id, &role_name).await?;
+    let event = AuditEvent::new(AuditAction::RoleUnassigned)
+        .actor(user.id)
+        .target(user_id)
+        .metadata(serde_json::json!({ "role": role_name }));
+    emit(&pool, &client, event).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::db::postgres::get_test_postgres_pool;
+    use crate::user_mgmt::auth::get_current_user_from_id;
+
+    #[tokio::test]
+    async fn assigned_role_grants_permissions() {
+        let pool = &get_test_postgres_pool();
+        let mut conn = pool.acquire().await.unwrap();
+        let user = crate::user_mgmt::entity::CreateUser {
+            email: format!("{}@example.com", uuid::Uuid::new_v4()),
+            password: "password".to_string(),
+            name: "Support".to_string(),
+        };
+        let user_id = crate::user_mgmt::handler::insert_user(&mut conn, user)
+            .await
+            .unwrap();
+        assert!(query_user_permissions(pool, user_id)
+            .await
+            .unwrap()
+            .is_empty());
+
+        assign_role(pool, user_id, "support").await.unwrap();
+        // assigning twice is fine
+        assign_role(pool, user_id, "support").await.unwrap();
+        assert_eq!(
+            query_user_permissions(pool, user_id).await.unwrap(),
+            vec!["users:read".to_string()]
+        );
+        assert!(assign_role(pool, user_id, "unknown").await.is_err());
+
+        unassign_role(pool, user_id, "support").await.unwrap();
+        assert!(query_user_permissions(pool, user_id)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn roles_writer_cannot_escalate() {
+        let pool = get_test_postgres_pool();
+        let mut conn = pool.acquire().await.unwrap();
+        let mut user_ids = Vec::new();
+        for name in ["Roles writer", "Colleague"] {
+            let user = crate::user_mgmt::entity::CreateUser {
+                email: format!("{}@example.com", uuid::Uuid::new_v4()),
+                password: "password".to_string(),
+                name: name.to_string(),
+            };
+            let user_id = crate::user_mgmt::handler::insert_user(&mut conn, user)
+                .await
+                .unwrap();
+            user_ids.push(user_id);
+        }
+        let (writer_id, colleague_id) = (user_ids[0], user_ids[1]);
+
+        let add = |user_id: uuid::Uuid, role_name: &str| {
+            let pool = pool.clone();
+            let role_name = role_name.to_string();
+            async move {
+                let mut writer = get_current_user_from_id(&pool, &writer_id).await;
+                writer.permissions = vec!["roles:write".to_string(), "users:read".to_string()];
+                add_user_role(
+                    State(pool),
+                    writer,
+                    ClientInfo::default(),
+                    Path((user_id, role_name)),
+                )
+                .await
+            }
+        };
+
+        assert!(matches!(
+            add(writer_id, "support").await,
+            Err(AuthError::Forbidden)
+        ));
+        assert!(matches!(
+            add(colleague_id, ADMIN_ROLE).await,
+            Err(AuthError::Forbidden)
+        ));
+        assert!(query_user_roles(&pool, writer_id).await.unwrap().is_empty());
+        assert!(query_user_roles(&pool, colleague_id)
+            .await
+            .unwrap()
+            .is_empty());
+
+        // support only grants users:read, which the writer holds
+        add(colleague_id, "support").await.unwrap();
+        assert_eq!(
+            query_user_roles(&pool, colleague_id).await.unwrap(),
+            vec!["support".to_string()]
+        );
+    }
+}
@@ -0,0 +1,249 @@
+use super::auth::{add_login_cookies, remove_auth_cookies, CurrentUser};
+use super::denylist::revoke_user_tokens;
+use super::encryption::verify;
+use super::error::AuthError;
+use super::handler::{map_email_exists, update_password};
+use super::throttle::{clear_throttle, reserve_attempt, ThrottleKey};
+use super::verification::{issue_verification_token, send_verification_email};
+use crate::common::entity::ClientInfo;
+use crate::common::error::CommonError;
+use crate::common::mail::SharedMailer;
+use axum::extract::State;
+use axum::response::Html;
+use axum::Form;
+use axum_extra::extract::cookie::CookieJar;
+use axum_htmx::HxRedirect;
+use serde::Deserialize;
+use sqlx::PgPool;
+
+#[derive(Deserialize)]
+pub struct UpdateProfile {
+    pub name: String,
+    pub email: String,
+}
+
+#[derive(Deserialize)]
+pub struct ChangePassword {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+#[derive(Deserialize)]
+pub struct DeleteAccount {
+    pub password: String,
+}
+
+/// The credentials are changed by the user, not by whoever holds the session,
+/// which must not be a way around the login throttle to guess the password either
+async fn check_password(
+    pool: &PgPool,
+    user: &CurrentUser,
+    password: String,
+) -> Result<(), AuthError> {
+    let account = ThrottleKey::Account(user.email.clone());
+    let attempt = reserve_attempt(pool, std::slice::from_ref(&account)).await?;
+    let hashed_password =
+        sqlx::query_scalar!("SELECT hashed_password FROM users WHERE id = $1", user.id)
+            .fetch_one(pool)
+            .await?;
+    if !verify(password, hashed_password).await? {
+        return Err(AuthError::WrongCredentials);
+    }
+    attempt.release(pool).await?;
+    clear_throttle(pool, &account).await
+}
+
+/// A new email has to be verified again, a verification link is sent to it
+#[tracing::instrument(name = "Updating profile", skip(pool, mailer, user, payload), fields(email = %user.email))]
+pub async fn update_profile(
+    State(pool): State<PgPool>,
+    State(mailer): State<SharedMailer>,
+    user: CurrentUser,
+    Form(payload): Form<UpdateProfile>,
+) -> Result<Html<&'static str>, AuthError> {
+    user.ensure_not_impersonated()?;
+    let name = payload.name.trim();
+    let email = payload.email.trim();
+    if name.is_empty() || email.is_empty() {
+        return Err(CommonError::ValidationError("Name and email are required".to_string()).into());
+    }
+
+    let email_changed = email != user.email;
+    let mut tx = pool.begin().await?;
+    sqlx::query!(
+        "UPDATE users SET name = $2, email = $3, is_verified = is_verified AND NOT $4
+        WHERE id = $1",
+        user.id,
+        name,
+        email,
+        email_changed,
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(map_email_exists)?;
+
+    if !email_changed {
+        tx.commit().await?;
+        return Ok(Html("<span>Your profile is updated.</span>"));
+    }
+    let token = issue_verification_token(&mut tx, user.id).await?;
+    tx.commit().await?;
+    send_verification_email(&mailer, email.to_string(), &token).await;
+
+    Ok(Html(
+        "<span>Your profile is updated, please verify your new email with the link sent to it.</span>",
+    ))
+}
+
+/// Every other session is logged out, the current one gets new tokens
+#[tracing::instrument(name = "Changing password", skip(jar, pool, user, client, payload), fields(email = %user.email))]
+pub async fn change_password(
+    jar: CookieJar,
+    State(pool): State<PgPool>,
+    user: CurrentUser,
+    client: ClientInfo,
+    Form(payload): Form<ChangePassword>,
+) -> Result<(CookieJar, Html<&'static str>), AuthError> {
+    user.ensure_not_impersonated()?;
+    if payload.new_password.is_empty() {
+        return Err(AuthError::MissingCredentials);
+    }
+    check_password(&pool, &user, payload.current_password).await?;
+
+    let mut tx = pool.begin().await?;
+    update_password(&mut tx, user.id, payload.new_password).await?;
+    revoke_user_tokens(&mut tx, user.id).await?;
+    tx.commit().await?;
+
+    let jar = add_login_cookies(jar, &pool, user.id, user.role, &client).await?;
+    Ok((jar, Html("<span>Your password is changed.</span>")))
+}
+
+/// Delete the account and everything that belongs to it, the tokens are deleted with it
+#[tracing::instrument(name = "Deleting account", skip(jar, pool, user, payload), fields(email = %user.email))]
+pub async fn delete_account(
+    jar: CookieJar,
+    State(pool): State<PgPool>,
+    user: CurrentUser,
+    Form(payload): Form<DeleteAccount>,
+) -> Result<(CookieJar, HxRedirect, ()), AuthError> {
+    user.ensure_not_impersonated()?;
+    check_password(&pool, &user, payload.password).await?;
+    sqlx::query!("DELETE FROM users WHERE id = $1", user.id)
+        .execute(&pool)
+        .await?;
+    Ok((
+        remove_auth_cookies(jar),
+        HxRedirect("/".parse().unwrap()),
+        (),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::db::postgres::get_test_postgres_pool;
+    use crate::common::mail::LogMailer;
+    use crate::user_mgmt::auth::get_current_user_from_id;
+    use crate::user_mgmt::entity::CreateUser;
+    use crate::user_mgmt::handler::insert_user;
+    use crate::user_mgmt::throttle::query_failures;
+    use std::sync::Arc;
+
+    async fn create_user(pool: &PgPool) -> CurrentUser {
+        let mut conn = pool.acquire().await.unwrap();
+        let user = CreateUser {
+            email: format!("{}@example.com", uuid::Uuid::new_v4()),
+            password: "password".to_string(),
+            name: "Profile".to_string(),
+        };
+        let user_id = insert_user(&mut conn, user).await.unwrap();
+        get_current_user_from_id(pool, &user_id).await
+    }
+
+    #[tokio::test]
+    async fn profile_update_keeps_emails_unique() {
+        let pool = get_test_postgres_pool();
+        let mailer: SharedMailer = Arc::new(LogMailer);
+        let user = create_user(&pool).await;
+        let user_id = user.id;
+
+        let payload = UpdateProfile {
+            name: "Renamed".to_string(),
+            email: "admin@example.com".to_string(),
+        };
+        let result = update_profile(
+            State(pool.clone()),
+            State(mailer.clone()),
+            user,
+            Form(payload),
+        )
+        .await;
+        assert!(matches!(result, Err(AuthError::EmailExists)));
+
+        let user = get_current_user_from_id(&pool, &user_id).await;
+        let updated_at_before =
+            sqlx::query_scalar!("SELECT updated_at FROM users WHERE id = $1", user_id)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        let payload = UpdateProfile {
+            name: "Renamed".to_string(),
+            email: user.email.clone(),
+        };
+        let result = update_profile(State(pool.clone()), State(mailer), user, Form(payload)).await;
+        assert!(result.is_ok());
+
+        let row = sqlx::query!("SELECT name, updated_at FROM users WHERE id = $1", user_id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(row.name, "Renamed");
+        assert!(row.updated_at > updated_at_before);
+    }
+
+    #[tokio::test]
+    async fn password_change_requires_current_password() {
+        let pool = get_test_postgres_pool();
+        let user = create_user(&pool).await;
+        let user_id = user.id;
+
+        let payload = ChangePassword {
+            current_password: "wrong".to_string(),
+            new_password: "new password".to_string(),
+        };
+        let result = change_password(
+            CookieJar::new(),
+            State(pool.clone()),
+            user,
+            ClientInfo::default(),
+            Form(payload),
+        )
+        .await;
+        assert!(matches!(result, Err(AuthError::WrongCredentials)));
+
+        // the wrong password is throttled like a login
+        let user = get_current_user_from_id(&pool, &user_id).await;
+        let key = ThrottleKey::Account(user.email.clone());
+        assert_eq!(query_failures(&pool, &key).await, 1);
+        clear_throttle(&pool, &key).await.unwrap();
+
+        let payload = ChangePassword {
+            current_password: "password".to_string(),
+            new_password: "new password".to_string(),
+        };
+        let result = change_password(
+            CookieJar::new(),
+            State(pool.clone()),
+            user,
+            ClientInfo::default(),
+            Form(payload),
+        )
+        .await;
+        assert!(result.is_ok());
+        let user = get_current_user_from_id(&pool, &user_id).await;
+        assert!(check_password(&pool, &user, "new password".to_string())
+            .await
+            .is_ok());
+    }
+}
@@ -0,0 +1,256 @@
+use super::error::AuthError;
+use super::token::{generate_token, hash_token};
+use crate::common::entity::ClientInfo;
+use crate::configuration::get_configuration;
+use chrono::{Duration, Utc};
+use once_cell::sync::Lazy;
+use sqlx::{PgConnection, PgPool};
+
+pub static REFRESH_TOKEN_TTL: Lazy<Duration> = Lazy::new(|| {
+    let configuration = get_configuration().expect("Failed to load configuration.");
+    Duration::days(configuration.security.refresh_token_ttl_days)
+});
+
+/// Two tabs that refresh at the same time both present the token, the slower one
+/// gets a token of its own instead of ending the session as a replay would
+const REUSE_GRACE_PERIOD: Duration = Duration::seconds(30);
+
+/// A refresh token and the session it belongs to, the session id is the family of the token
+pub struct SessionToken {
+    pub user_id: uuid::Uuid,
+    pub session_id: uuid::Uuid,
+    pub token: String,
+}
+
+/// Issue a refresh token for a fresh login, which starts a new session
+pub async fn issue_refresh_token(
+    pool: &PgPool,
+    user_id: uuid::Uuid,
+    client: &ClientInfo,
+) -> Result<SessionToken, AuthError> {
+    let mut tx = pool.begin().await?;
+    let session_id = sqlx::query_scalar!(
+        "INSERT INTO sessions (user_id, user_agent, ip) VALUES ($1, $2, $3) RETURNING id",
+        user_id,
+        client.user_agent,
+        client.ip,
+    )
+    .fetch_one(&mut *tx)
+    .await
+    .inspect_err(|e| tracing::error!("Failed to insert session: {e}"))?;
+    let token = insert_refresh_token(&mut tx, user_id, session_id).await?;
+    tx.commit().await?;
+
+    Ok(SessionToken {
+        user_id,
+        session_id,
+        token,
+    })
+}
+
+async fn insert_refresh_token(
+    conn: &mut PgConnection,
+    user_id: uuid::Uuid,
+    family_id: uuid::Uuid,
+) -> Result<String, AuthError> {
+    let token = generate_token();
+    let expires_at = Utc::now() + *REFRESH_TOKEN_TTL;
+
+    sqlx::query!(
+        "INSERT INTO refresh_tokens (user_id, family_id, token_hash, expires_at)
+        VALUES ($1, $2, $3, $4)",
+        user_id,
+        family_id,
+        hash_token(&token),
+        expires_at,
+    )
+    .execute(conn)
+    .await
+    .inspect_err(|e| tracing::error!("Failed to insert refresh token: {e}"))?;
+
+    Ok(token)
+}
+
+/// Exchange a refresh token for a new one of the same family.
+/// A refresh token can only be used once, presenting a revoked token means it was stolen
+/// or replayed, so the whole family is revoked and the user has to log in again.
+/// A token rotated moments ago is the exception, see `REUSE_GRACE_PERIOD`
+pub async fn rotate_refresh_token(
+    pool: &PgPool,
+    token: &str,
+    client: &ClientInfo,
+) -> Result<SessionToken, AuthError> {
+    let mut tx = pool.begin().await?;
+
+    let current = sqlx::query!(
+        r#"SELECT refresh_tokens.id, refresh_tokens.user_id, refresh_tokens.family_id,
+            refresh_tokens.expires_at, refresh_tokens.revoked_at,
+            sessions.revoked_at AS "session_revoked_at"
+        FROM refresh_tokens
+        JOIN sessions ON sessions.id = refresh_tokens.family_id
+        WHERE refresh_tokens.token_hash = $1
+        FOR UPDATE OF refresh_tokens"#,
+        hash_token(token)
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(AuthError::InvalidToken)?;
+
+    match current.revoked_at {
+        // only a rotation revokes a token without its session
+        Some(revoked_at)
+            if current.session_revoked_at.is_none()
+                && revoked_at > Utc::now() - REUSE_GRACE_PERIOD =>
+        {
+            tracing::debug!(
+                "Refresh token of family {} reused within the grace period",
+                current.family_id
+            );
+        }
+        Some(_) => {
+            tracing::warn!(
+                "Refresh token reuse detected, revoking token family {}",
+                current.family_id
+            );
+            revoke_family(&mut tx, current.family_id).await?;
+            tx.commit().await?;
+            return Err(AuthError::InvalidToken);
+        }
+        None => {
+            sqlx::query!(
+                "UPDATE refresh_tokens SET revoked_at = current_timestamp WHERE id = $1",
+                current.id
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+    }
+
+    if current.expires_at < Utc::now() {
+        return Err(AuthError::InvalidToken);
+    }
+
+    // a refresh is the only time the session is seen without a query on every request
+    sqlx::query!(
+        "UPDATE sessions SET last_seen_at = current_timestamp, ip = COALESCE($2, ip)
+        WHERE id = $1",
+        current.family_id,
+        client.ip,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let token = insert_refresh_token(&mut tx, current.user_id, current.family_id).await?;
+    tx.commit().await?;
+
+    Ok(SessionToken {
+        user_id: current.user_id,
+        session_id: current.family_id,
+        token,
+    })
+}
+
+/// Revoke every token rotated from the same login as the given token
+pub async fn revoke_refresh_token(pool: &PgPool, token: &str) -> Result<(), AuthError> {
+    let mut conn = pool.acquire().await?;
+    let family_id = sqlx::query_scalar!(
+        "SELECT family_id FROM refresh_tokens WHERE token_hash = $1",
+        hash_token(token)
+    )
+    .fetch_optional(&mut *conn)
+    .await?;
+
+    if let Some(family_id) = family_id {
+        revoke_family(&mut conn, family_id).await?;
+    }
+    Ok(())
+}
+
+pub async fn revoke_user_refresh_tokens(
+    conn: &mut PgConnection,
+    user_id: uuid::Uuid,
+) -> Result<(), AuthError> {
+    sqlx::query!(
+        "UPDATE refresh_tokens SET revoked_at = current_timestamp
+        WHERE user_id = $1 AND revoked_at IS NULL",
+        user_id
+    )
+    .execute(&mut *conn)
+    .await?;
+    sqlx::query!(
+        "UPDATE sessions SET revoked_at = current_timestamp
+        WHERE user_id = $1 AND revoked_at IS NULL",
+        user_id
+    )
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+/// Revoking the family ends its session, the access tokens of the session are rejected as well
+pub async fn revoke_family(
+    conn: &mut PgConnection,
+    family_id: uuid::Uuid,
+) -> Result<(), AuthError> {
+    sqlx::query!(
+        "UPDATE refresh_tokens SET revoked_at = current_timestamp
+        WHERE family_id = $1 AND revoked_at IS NULL",
+        family_id
+    )
+    .execute(&mut *conn)
+    .await?;
+    sqlx::query!(
+        "UPDATE sessions SET revoked_at = current_timestamp
+        WHERE id = $1 AND revoked_at IS NULL",
+        family_id
+    )
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::db::postgres::get_test_postgres_pool;
+
+    #[tokio::test]
+    async fn reused_refresh_token_revokes_family() {
+        let pool = &get_test_postgres_pool();
+        let user_id = uuid::Uuid::parse_str("00571729-af3e-4cb7-a693-ca0c82efed77").unwrap();
+
+        let client = ClientInfo::default();
+
+        let issued = issue_refresh_token(pool, user_id, &client).await.unwrap();
+        let rotated = rotate_refresh_token(pool, &issued.token, &client)
+            .await
+            .unwrap();
+        assert_eq!(rotated.user_id, user_id);
+        assert_eq!(rotated.session_id, issued.session_id);
+
+        // a second tab refreshing at the same time gets a token of the same session
+        let concurrent = rotate_refresh_token(pool, &issued.token, &client)
+            .await
+            .unwrap();
+        assert_eq!(concurrent.session_id, issued.session_id);
+
+        // replaying the first token later kills the rotated ones as well
+        sqlx::query!(
+            "UPDATE refresh_tokens SET revoked_at = current_timestamp - interval '1 minute'
+            WHERE token_hash = $1",
+            hash_token(&issued.token)
+        )
+        .execute(pool)
+        .await
+        .unwrap();
+        assert!(rotate_refresh_token(pool, &issued.token, &client)
+            .await
+            .is_err());
+        assert!(rotate_refresh_token(pool, &rotated.token, &client)
+            .await
+            .is_err());
+        assert!(rotate_refresh_token(pool, &concurrent.token, &client)
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,211 @@
+use super::auth::{remove_auth_cookies, CurrentUser};
+use super::denylist::revoke_user_tokens;
+use super::error::AuthError;
+use super::refresh::revoke_family;
+use crate::common::error::CommonError;
+use axum::extract::{Json, Path, State};
+use axum::http::StatusCode;
+use axum_extra::extract::cookie::CookieJar;
+use axum_htmx::HxRedirect;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::PgPool;
+
+/// A login on a device that can still be refreshed
+#[derive(Debug, Serialize)]
+pub struct Session {
+    pub id: uuid::Uuid,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: DateTime<Utc>,
+    // updated whenever the access token is refreshed
+    pub last_seen_at: DateTime<Utc>,
+    // the session of the request that lists them
+    pub is_current: bool,
+}
+
+impl Session {
+    /// A short name of the device, e.g. "Firefox on Linux"
+    pub fn device(&self) -> String {
+        self.user_agent
+            .as_deref()
+            .map(describe_user_agent)
+            .unwrap_or_else(|| "Unknown device".to_string())
+    }
+}
+
+// the order matters, e.g. Edge and Chrome mention Safari as well
+fn describe_user_agent(user_agent: &str) -> String {
+    let browser = [
+        ("Edg/", "Edge"),
+        ("Firefox/", "Firefox"),
+        ("Chrome/", "Chrome"),
+        ("Safari/", "Safari"),
+    ]
+    .iter()
+    .find(|(token, _)| user_agent.contains(token))
+    .map_or("Unknown browser", |(_, name)| *name);
+    let os = [
+        ("Android", "Android"),
+        ("iPhone", "iOS"),
+        ("iPad", "iOS"),
+        ("Windows", "Windows"),
+        ("Mac OS", "macOS"),
+        ("Linux", "Linux"),
+    ]
+    .iter()
+    .find(|(token, _)| user_agent.contains(token))
+    .map(|(_, name)| *name);
+    match os {
+        Some(os) => format!("{browser} on {os}"),
+        None => browser.to_string(),
+    }
+}
+
+/// The sessions that are neither revoked nor expired, the most recently seen first
+pub async fn query_sessions(
+    pool: &PgPool,
+    user_id: uuid::Uuid,
+    current_session_id: Option<uuid::Uuid>,
+) -> Result<Vec<Session>, sqlx::Error> {
+    sqlx::query_as!(
+        Session,
+        r#"SELECT sessions.id, sessions.user_agent, sessions.ip,
+            sessions.created_at, sessions.last_seen_at,
+            COALESCE(sessions.id = $2, false) AS "is_current!"
+        FROM sessions
+        WHERE sessions.user_id = $1 AND sessions.revoked_at IS NULL
+            AND EXISTS(
+                SELECT 1 FROM refresh_tokens
+                WHERE refresh_tokens.family_id = sessions.id
+                    AND refresh_tokens.revoked_at IS NULL
+                    AND refresh_tokens.expires_at > current_timestamp
+            )
+        ORDER BY sessions.last_seen_at DESC"#,
+        user_id,
+        current_session_id,
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn show_sessions(
+    State(pool): State<PgPool>,
+    user: CurrentUser,
+) -> Result<Json<Vec<Session>>, AuthError> {
+    let sessions = query_sessions(&pool, user.id, user.session_id).await?;
+    Ok(Json(sessions))
+}
+
+/// Log a single device out, its access token is rejected from the next request on
+#[tracing::instrument(name = "Revoking session", skip(pool, user), fields(email = %user.email))]
+pub async fn revoke_session(
+    State(pool): State<PgPool>,
+    user: CurrentUser,
+    Path(session_id): Path<uuid::Uuid>,
+) -> Result<StatusCode, AuthError> {
+    user.ensure_not_impersonated()?;
+    let mut tx = pool.begin().await?;
+    // the session of another user is not found either
+    let session = sqlx::query_scalar!(
+        "SELECT id FROM sessions WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL",
+        session_id,
+        user.id,
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(CommonError::NotFound)?;
+    revoke_family(&mut tx, session).await?;
+    tx.commit().await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Revoke every session including the current one
+#[tracing::instrument(name = "Logging out everywhere", skip(jar, pool, user), fields(email = %user.email))]
+pub async fn logout_everywhere(
+    jar: CookieJar,
+    State(pool): State<PgPool>,
+    user: CurrentUser,
+) -> Result<(CookieJar, HxRedirect, ()), AuthError> {
+    user.ensure_not_impersonated()?;
+    let mut tx = pool.begin().await?;
+    revoke_user_tokens(&mut tx, user.id).await?;
+    tx.commit().await?;
+    Ok((
+        remove_auth_cookies(jar),
+        HxRedirect("/login".parse().unwrap()),
+        (),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::db::postgres::get_test_postgres_pool;
+    use crate::common::entity::ClientInfo;
+    use crate::user_mgmt::auth::get_current_user_from_id;
+    use crate::user_mgmt::denylist::is_revoked;
+    use crate::user_mgmt::entity::CreateUser;
+    use crate::user_mgmt::handler::insert_user;
+    use crate::user_mgmt::jwt::{Claims, Role};
+    use crate::user_mgmt::refresh::{issue_refresh_token, rotate_refresh_token};
+
+    #[test]
+    fn check_device_description() {
+        let firefox = "Mozilla/5.0 (X11; Linux x86_64; rv:131.0) Gecko/20100101 Firefox/131.0";
+        assert_eq!(describe_user_agent(firefox), "Firefox on Linux");
+        let edge = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 \
+            (KHTML, like Gecko) Chrome/129.0.0.0 Safari/537.36 Edg/129.0.0.0";
+        assert_eq!(describe_user_agent(edge), "Edge on Windows");
+        assert_eq!(describe_user_agent("curl/8.5.0"), "Unknown browser");
+    }
+
+    #[tokio::test]
+    async fn revoked_session_is_rejected() {
+        let pool = get_test_postgres_pool();
+        let mut conn = pool.acquire().await.unwrap();
+        let payload = CreateUser {
+            email: format!("{}@example.com", uuid::Uuid::new_v4()),
+            password: "password".to_string(),
+            name: "Sessions".to_string(),
+        };
+        let user_id = insert_user(&mut conn, payload).await.unwrap();
+        let client = ClientInfo {
+            ip: Some("203.0.113.7".to_string()),
+            user_agent: Some("curl/8.5.0".to_string()),
+        };
+        let laptop = issue_refresh_token(&pool, user_id, &client).await.unwrap();
+        let phone = issue_refresh_token(&pool, user_id, &client).await.unwrap();
+
+        let sessions = query_sessions(&pool, user_id, Some(laptop.session_id))
+            .await
+            .unwrap();
+        assert_eq!(sessions.len(), 2);
+        assert!(sessions
+            .iter()
+            .any(|session| session.id == laptop.session_id && session.is_current));
+
+        let user = get_current_user_from_id(&pool, &user_id).await;
+        revoke_session(State(pool.clone()), user, Path(phone.session_id))
+            .await
+            .unwrap();
+        let claims = Claims::new(user_id, Role::User).with_session(phone.session_id);
+        assert!(is_revoked(&pool, &claims).await.unwrap());
+        assert!(rotate_refresh_token(&pool, &phone.token, &client)
+            .await
+            .is_err());
+        let claims = Claims::new(user_id, Role::User).with_session(laptop.session_id);
+        assert!(!is_revoked(&pool, &claims).await.unwrap());
+
+        // the session of another user cannot be revoked
+        let other_id = uuid::Uuid::parse_str("00571729-af3e-4cb7-a693-ca0c82efed77").unwrap();
+        let other = get_current_user_from_id(&pool, &other_id).await;
+        let result = revoke_session(State(pool.clone()), other, Path(laptop.session_id)).await;
+        assert!(matches!(
+            result,
+            Err(AuthError::CommonError(CommonError::NotFound))
+        ));
+        let sessions = query_sessions(&pool, user_id, None).await.unwrap();
+        assert_eq!(sessions.len(), 1);
+    }
+}
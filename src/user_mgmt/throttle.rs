@@ -0,0 +1,355 @@
+use super::audit::{emit, AuditAction, AuditEvent};
+use super::auth::CurrentUser;
+use super::error::AuthError;
+use super::handler::query_user;
+use crate::common::entity::ClientInfo;
+use crate::configuration::get_configuration;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use chrono::{DateTime, Duration, Utc};
+use once_cell::sync::Lazy;
+use sqlx::PgPool;
+
+struct ThrottleSettings {
+    max_login_failures: i32,
+    max_login_failures_per_ip: i32,
+    lockout: Duration,
+    window: Duration,
+}
+
+static SETTINGS: Lazy<ThrottleSettings> = Lazy::new(|| {
+    let configuration = get_configuration().expect("Failed to load configuration.");
+    ThrottleSettings {
+        max_login_failures: configuration.security.max_login_failures,
+        max_login_failures_per_ip: configuration.security.max_login_failures_per_ip,
+        lockout: Duration::minutes(configuration.security.lockout_minutes),
+        window: Duration::minutes(configuration.security.failure_window_minutes),
+    }
+});
+
+// the backoff stops doubling at 2^6 = 64 seconds, the lockout takes over from there
+const MAX_BACKOFF_EXPONENT: i32 = 6;
+
+/// What a login attempt is throttled by, a login is checked against all of them.
+/// The emails sent on the request of anybody are counted apart, so that asking for
+/// links does not lock the account nor the ip out of the login
+#[derive(Clone, Debug)]
+pub enum ThrottleKey {
+    Account(String),
+    Ip(String),
+    MailTo(String),
+    MailFromIp(String),
+}
+
+impl ThrottleKey {
+    fn as_key(&self) -> String {
+        match self {
+            ThrottleKey::Account(email) => format!("account:{}", email.to_lowercase()),
+            ThrottleKey::Ip(ip) => format!("ip:{}", ip),
+            ThrottleKey::MailTo(email) => format!("mail_to:{}", email.to_lowercase()),
+            ThrottleKey::MailFromIp(ip) => format!("mail_from_ip:{}", ip),
+        }
+    }
+
+    fn max_failures(&self) -> i32 {
+        match self {
+            ThrottleKey::Account(_) | ThrottleKey::MailTo(_) => SETTINGS.max_login_failures,
+            ThrottleKey::Ip(_) | ThrottleKey::MailFromIp(_) => SETTINGS.max_login_failures_per_ip,
+        }
+    }
+}
+
+pub struct LoginThrottle {
+    pub failures: i32,
+    pub last_failure_at: Option<DateTime<Utc>>,
+    pub locked_until: Option<DateTime<Utc>>,
+}
+
+impl LoginThrottle {
+    /// The failures are forgotten once the last one is older than the window,
+    /// e.g. the ip of a NAT is not locked forever by a few typos a day, but not during a lockout
+    fn decayed(self, now: DateTime<Utc>, window: Duration) -> Self {
+        let is_stale = self.last_failure_at.is_none_or(|last| last + window <= now);
+        let is_locked = self.locked_until.is_some_and(|until| until > now);
+        if is_stale && !is_locked {
+            return Self {
+                failures: 0,
+                last_failure_at: None,
+                locked_until: None,
+            };
+        }
+        self
+    }
+
+    /// How long to wait before the next attempt, every failure doubles the wait
+    pub fn retry_after(&self, now: DateTime<Utc>) -> Option<Duration> {
+        let backoff_until = self
+            .last_failure_at
+            .filter(|_| self.failures > 0)
+            .map(|last| {
+                let exponent = (self.failures - 1).min(MAX_BACKOFF_EXPONENT);
+                last + Duration::seconds(1 << exponent)
+            });
+        let blocked_until = backoff_until.max(self.locked_until)?;
+        (blocked_until > now).then(|| blocked_until - now)
+    }
+}
+
+struct Reservation {
+    key: ThrottleKey,
+    reserved_at: DateTime<Utc>,
+    previous: LoginThrottle,
+}
+
+/// An attempt counted as a failure before the credentials are verified,
+/// so that concurrent attempts cannot all pass the check of the same counters.
+/// Dropping it keeps the failure, a valid attempt is given back with `release`
+#[must_use]
+pub struct ThrottleAttempt {
+    reservations: Vec<Reservation>,
+}
+
+/// Reject the attempt before the password is verified,
+/// so that a brute force attack does not keep the hashing pool busy either
+pub async fn reserve_attempt(
+    pool: &PgPool,
+    keys: &[ThrottleKey],
+) -> Result<ThrottleAttempt, AuthError> {
+    // every attempt locks the rows in the same order, so that two attempts cannot deadlock
+    let mut keys = keys.to_vec();
+    keys.sort_by_key(ThrottleKey::as_key);
+
+    let mut tx = pool.begin().await?;
+    let now = Utc::now();
+    let mut throttles = Vec::with_capacity(keys.len());
+    for key in &keys {
+        sqlx::query!(
+            "INSERT INTO login_throttles (key) VALUES ($1) ON CONFLICT (key) DO NOTHING",
+            key.as_key()
+        )
+        .execute(&mut *tx)
+        .await?;
+        let throttle = sqlx::query_as!(
+            LoginThrottle,
+            "SELECT failures, last_failure_at, locked_until FROM login_throttles
+            WHERE key = $1 FOR UPDATE",
+            key.as_key()
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        throttles.push(throttle.decayed(now, SETTINGS.window));
+    }
+
+    let retry_after = throttles
+        .iter()
+        .filter_map(|throttle| throttle.retry_after(now))
+        .max();
+    if let Some(retry_after) = retry_after {
+        // the transaction is rolled back, a rejected attempt is not counted
+        return Err(AuthError::TooManyAttempts {
+            // round up so that the client does not retry a bit too early
+            retry_after: (retry_after.num_milliseconds() as u64).div_ceil(1000),
+        });
+    }
+
+    let mut reservations = Vec::with_capacity(keys.len());
+    for (key, previous) in keys.into_iter().zip(throttles) {
+        let failures = previous.failures + 1;
+        let locked_until = if failures >= key.max_failures() {
+            Some(now + SETTINGS.lockout)
+        } else {
+            previous.locked_until
+        };
+        let reserved_at = sqlx::query_scalar!(
+            r#"UPDATE login_throttles
+            SET failures = $2, last_failure_at = current_timestamp, locked_until = $3
+            WHERE key = $1
+            RETURNING last_failure_at AS "last_failure_at!""#,
+            key.as_key(),
+            failures,
+            locked_until,
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        reservations.push(Reservation {
+            key,
+            reserved_at,
+            previous,
+        });
+    }
+    tx.commit().await?;
+
+    Ok(ThrottleAttempt { reservations })
+}
+
+impl ThrottleAttempt {
+    /// Undo the failure counted by the attempt once the credentials are valid,
+    /// the failures of the other attempts are kept, e.g. of the other clients of the same ip
+    pub async fn release(self, pool: &PgPool) -> Result<(), AuthError> {
+        for reservation in self.reservations {
+            // the time and the lockout are only restored if no failure happened since
+            sqlx::query!(
+                "UPDATE login_throttles
+                SET failures = GREATEST(failures - 1, 0),
+                    last_failure_at = CASE WHEN last_failure_at = $2 THEN $3 ELSE last_failure_at END,
+                    locked_until = CASE WHEN last_failure_at = $2 THEN $4 ELSE locked_until END
+                WHERE key = $1",
+                reservation.key.as_key(),
+                reservation.reserved_at,
+                reservation.previous.last_failure_at,
+                reservation.previous.locked_until,
+            )
+            .execute(pool)
+            .await?;
+        }
+        Ok(())
+    }
+}
+
+/// Count an email that an anonymous client asks for, per recipient and per client ip,
+/// so that the endpoint cannot be used to flood a mailbox. Every email counts, even
+/// to an unknown address, otherwise the rejection would tell whether the account exists
+pub async fn throttle_email(
+    pool: &PgPool,
+    email: &str,
+    client: &ClientInfo,
+) -> Result<(), AuthError> {
+    let mut keys = vec![ThrottleKey::MailTo(email.to_string())];
+    if let Some(ip) = client.ip.clone() {
+        keys.push(ThrottleKey::MailFromIp(ip));
+    }
+    // never released, the email is sent whatever the outcome
+    reserve_attempt(pool, &keys).await.map(drop)
+}
+
+/// Forget the failures, e.g. after a successful login or when an admin unlocks the account
+pub async fn clear_throttle(pool: &PgPool, key: &ThrottleKey) -> Result<(), AuthError> {
+    sqlx::query!("DELETE FROM login_throttles WHERE key = $1", key.as_key())
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// The stored failures, whatever the backoff, so that a test does not depend on the clock
+#[cfg(test)]
+pub async fn query_failures(pool: &PgPool, key: &ThrottleKey) -> i32 {
+    sqlx::query_scalar!(
+        "SELECT failures FROM login_throttles WHERE key = $1",
+        key.as_key()
+    )
+    .fetch_optional(pool)
+    .await
+    .unwrap()
+    .unwrap_or(0)
+}
+
+/// Let an admin unlock an account before the lockout expires
+#[tracing::instrument(name = "Unlocking user", skip(pool, admin, client), fields(admin = %admin.email))]
+pub async fn unlock_user(
+    State(pool): State<PgPool>,
+    admin: CurrentUser,
+    client: ClientInfo,
+    Path(user_id): Path<uuid::Uuid>,
+) -> Result<StatusCode, AuthError> {
+    let user = query_user(&pool, user_id).await?;
+    clear_throttle(&pool, &ThrottleKey::Account(user.email)).await?;
+    let event = AuditEvent::new(AuditAction::UserUnlocked)
+        .actor(admin.id)
+        .target(user.id);
+    emit(&pool, &client, event).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::db::postgres::get_test_postgres_pool;
+
+    #[test]
+    fn check_exponential_backoff() {
+        let now = Utc::now();
+        let throttle = |failures| LoginThrottle {
+            failures,
+            last_failure_at: Some(now),
+            locked_until: None,
+        };
+        assert_eq!(throttle(0).retry_after(now), None);
+        assert_eq!(throttle(1).retry_after(now), Some(Duration::seconds(1)));
+        assert_eq!(throttle(3).retry_after(now), Some(Duration::seconds(4)));
+        assert_eq!(throttle(100).retry_after(now), Some(Duration::seconds(64)));
+        let later = now + Duration::seconds(10);
+        assert_eq!(throttle(3).retry_after(later), None);
+    }
+
+    #[test]
+    fn failures_decay_after_the_window() {
+        let now = Utc::now();
+        let window = Duration::minutes(60);
+        let throttle = |last_failure_at, locked_until| LoginThrottle {
+            failures: 3,
+            last_failure_at: Some(last_failure_at),
+            locked_until,
+        };
+        let recent = throttle(now - Duration::minutes(59), None).decayed(now, window);
+        assert_eq!(recent.failures, 3);
+        let stale = throttle(now - Duration::minutes(61), None).decayed(now, window);
+        assert_eq!(stale.failures, 0);
+        let locked = throttle(
+            now - Duration::minutes(61),
+            Some(now + Duration::minutes(1)),
+        );
+        assert_eq!(locked.decayed(now, window).failures, 3);
+    }
+
+    async fn set_failures(pool: &PgPool, key: &ThrottleKey, failures: i32) {
+        // old enough to be past the backoff, but not the window
+        sqlx::query!(
+            "INSERT INTO login_throttles (key, failures, last_failure_at)
+            VALUES ($1, $2, current_timestamp - interval '5 minutes')",
+            key.as_key(),
+            failures,
+        )
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn account_is_locked_after_too_many_failures() {
+        let pool = &get_test_postgres_pool();
+        let key = ThrottleKey::Account(format!("{}@example.com", uuid::Uuid::new_v4()));
+        let keys = [key];
+
+        set_failures(pool, &keys[0], SETTINGS.max_login_failures - 1).await;
+        let _failed = reserve_attempt(pool, &keys).await.unwrap();
+        let result = reserve_attempt(pool, &keys).await;
+        assert!(matches!(
+            result,
+            Err(AuthError::TooManyAttempts { retry_after }) if retry_after > 60
+        ));
+
+        clear_throttle(pool, &keys[0]).await.unwrap();
+        assert!(reserve_attempt(pool, &keys).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn concurrent_attempts_are_counted_before_the_verification() {
+        let pool = &get_test_postgres_pool();
+        let key = ThrottleKey::Ip(uuid::Uuid::new_v4().to_string());
+        let keys = [key];
+
+        // only one of the attempts passes, the other sees its failure and has to wait
+        let (first, second) =
+            tokio::join!(reserve_attempt(pool, &keys), reserve_attempt(pool, &keys));
+        assert!(first.is_ok() != second.is_ok());
+
+        // a valid attempt gives its failure and its lockout back
+        clear_throttle(pool, &keys[0]).await.unwrap();
+        set_failures(pool, &keys[0], SETTINGS.max_login_failures_per_ip - 1).await;
+        let attempt = reserve_attempt(pool, &keys).await.unwrap();
+        attempt.release(pool).await.unwrap();
+        let attempt = reserve_attempt(pool, &keys).await.unwrap();
+        attempt.release(pool).await.unwrap();
+        clear_throttle(pool, &keys[0]).await.unwrap();
+    }
+}
@@ -0,0 +1,197 @@
+use super::error::AuthError;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{Duration, Utc};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use sqlx::PgConnection;
+
+#[derive(Debug, Clone, Copy)]
+pub enum TokenPurpose {
+    EmailVerification,
+    PasswordReset,
+    // issued after the password is verified, exchanged for the access token with a TOTP code
+    MfaChallenge,
+    // a sign-in link sent by email instead of the password
+    MagicLink,
+}
+
+impl TokenPurpose {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TokenPurpose::EmailVerification => "email_verification",
+            TokenPurpose::PasswordReset => "password_reset",
+            TokenPurpose::MfaChallenge => "mfa_challenge",
+            TokenPurpose::MagicLink => "magic_link",
+        }
+    }
+}
+
+/// Generate a random url-safe token, it is only shown to the client once
+/// and only its hash is stored in the database
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+// sha256 instead of bcrypt because the token has enough entropy by itself
+// and we need to look it up by its hash
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// Issue a single-use token for the user, the previous unused tokens of the same purpose
+/// are invalidated so that only the latest one sent to the user works
+pub async fn issue_user_token(
+    conn: &mut PgConnection,
+    user_id: uuid::Uuid,
+    purpose: TokenPurpose,
+    ttl: Duration,
+) -> Result<String, AuthError> {
+    insert_user_token(conn, user_id, purpose, ttl, None).await
+}
+
+/// Like `issue_user_token`, the token is only accepted together with the secret of the browser
+pub async fn issue_browser_bound_token(
+    conn: &mut PgConnection,
+    user_id: uuid::Uuid,
+    purpose: TokenPurpose,
+    ttl: Duration,
+    browser_secret: &str,
+) -> Result<String, AuthError> {
+    insert_user_token(
+        conn,
+        user_id,
+        purpose,
+        ttl,
+        Some(hash_token(browser_secret)),
+    )
+    .await
+}
+
+async fn insert_user_token(
+    conn: &mut PgConnection,
+    user_id: uuid::Uuid,
+    purpose: TokenPurpose,
+    ttl: Duration,
+    browser_hash: Option<String>,
+) -> Result<String, AuthError> {
+    sqlx::query!(
+        "UPDATE user_tokens SET used_at = current_timestamp
+        WHERE user_id = $1 AND purpose = $2 AND used_at IS NULL",
+        user_id,
+        purpose.as_str(),
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    let token = generate_token();
+    sqlx::query!(
+        "INSERT INTO user_tokens (user_id, purpose, token_hash, expires_at, browser_hash)
+        VALUES ($1, $2, $3, $4, $5)",
+        user_id,
+        purpose.as_str(),
+        hash_token(&token),
+        Utc::now() + ttl,
+        browser_hash,
+    )
+    .execute(&mut *conn)
+    .await
+    .inspect_err(|e| tracing::error!("Failed to insert user token: {e}"))?;
+
+    Ok(token)
+}
+
+/// Return the user of a valid token without using it up,
+/// e.g. when the token is only used after another check succeeds
+pub async fn find_user_token(
+    conn: &mut PgConnection,
+    token: &str,
+    purpose: TokenPurpose,
+) -> Result<uuid::Uuid, AuthError> {
+    sqlx::query_scalar!(
+        "SELECT user_id FROM user_tokens
+        WHERE token_hash = $1 AND purpose = $2
+            AND used_at IS NULL AND expires_at > current_timestamp",
+        hash_token(token),
+        purpose.as_str(),
+    )
+    .fetch_optional(conn)
+    .await?
+    .ok_or(AuthError::InvalidToken)
+}
+
+/// Mark the token as used and return its user, it fails if the token is unknown,
+/// expired or already used
+pub async fn consume_user_token(
+    conn: &mut PgConnection,
+    token: &str,
+    purpose: TokenPurpose,
+) -> Result<uuid::Uuid, AuthError> {
+    sqlx::query_scalar!(
+        "UPDATE user_tokens SET used_at = current_timestamp
+        WHERE token_hash = $1 AND purpose = $2
+            AND used_at IS NULL AND expires_at > current_timestamp
+        RETURNING user_id",
+        hash_token(token),
+        purpose.as_str(),
+    )
+    .fetch_optional(conn)
+    .await?
+    .ok_or(AuthError::InvalidToken)
+}
+
+/// Like `consume_user_token`, a token opened in another browser is rejected without using it up,
+/// so that it still works in the browser that asked for it
+pub async fn consume_browser_bound_token(
+    conn: &mut PgConnection,
+    token: &str,
+    purpose: TokenPurpose,
+    browser_secret: &str,
+) -> Result<uuid::Uuid, AuthError> {
+    sqlx::query_scalar!(
+        "UPDATE user_tokens SET used_at = current_timestamp
+        WHERE token_hash = $1 AND purpose = $2 AND browser_hash = $3
+            AND used_at IS NULL AND expires_at > current_timestamp
+        RETURNING user_id",
+        hash_token(token),
+        purpose.as_str(),
+        hash_token(browser_secret),
+    )
+    .fetch_optional(conn)
+    .await?
+    .ok_or(AuthError::InvalidToken)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::db::postgres::get_test_postgres_pool;
+
+    #[test]
+    fn check_token_hashing() {
+        let token = generate_token();
+        assert_ne!(token, generate_token());
+        assert_eq!(hash_token(&token), hash_token(&token));
+        assert_eq!(hash_token(&token).len(), 64);
+    }
+
+    #[tokio::test]
+    async fn user_token_is_single_use() {
+        let mut conn = get_test_postgres_pool().acquire().await.unwrap();
+        let user_id = uuid::Uuid::parse_str("00571729-af3e-4cb7-a693-ca0c82efed77").unwrap();
+        let purpose = TokenPurpose::EmailVerification;
+
+        let token = issue_user_token(&mut conn, user_id, purpose, Duration::hours(1))
+            .await
+            .unwrap();
+        let consumed = consume_user_token(&mut conn, &token, purpose)
+            .await
+            .unwrap();
+        assert_eq!(consumed, user_id);
+        assert!(consume_user_token(&mut conn, &token, purpose)
+            .await
+            .is_err());
+    }
+}
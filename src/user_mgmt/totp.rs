@@ -0,0 +1,562 @@
+use super::audit::{emit, AuditAction, AuditEvent};
+use super::auth::{add_login_cookies, login_failed, record_login, CurrentUser};
+use super::error::AuthError;
+use super::handler::query_user;
+use super::throttle::{clear_throttle, reserve_attempt, ThrottleKey};
+use super::token::{
+    consume_user_token, find_user_token, hash_token, issue_user_token, TokenPurpose,
+};
+use crate::common::entity::ClientInfo;
+use crate::common::error::CommonError;
+use crate::configuration::{get_environment, Environment};
+use askama_axum::Template;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::Html;
+use axum::{Form, Json};
+use axum_extra::extract::cookie::{Cookie, CookieJar, SameSite};
+use axum_htmx::HxRedirect;
+use chrono::{DateTime, Utc};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sqlx::{PgConnection, PgPool};
+use totp_rs::{Algorithm, Secret, TOTP};
+
+const TOTP_DIGITS: usize = 6;
+const TOTP_STEP_SECONDS: u64 = 30;
+// accept the previous and the next code as well, for clocks that are a bit off
+const TOTP_SKEW: u8 = 1;
+const TOTP_ISSUER: &str = env!("CARGO_PKG_NAME");
+const RECOVERY_CODE_COUNT: usize = 10;
+
+pub const MFA_CHALLENGE_COOKIE: &str = "mfa_challenge";
+const MFA_CHALLENGE_TTL_MINUTES: i64 = 5;
+// the challenge is only sent to the endpoint that consumes it
+const MFA_CHALLENGE_PATH: &str = "/api/v1/auth/2fa";
+
+struct UserTotp {
+    secret: String,
+    confirmed_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+pub struct TotpCode {
+    // either a code of the authenticator app or a recovery code
+    pub code: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TotpStatus {
+    pub enabled: bool,
+    pub confirmed_at: Option<DateTime<Utc>>,
+    pub recovery_codes_left: i64,
+}
+
+#[derive(Template)]
+#[template(path = "components/totp_enrollment.html")]
+pub struct TotpEnrollmentTemplate {
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+#[derive(Template)]
+#[template(path = "components/recovery_codes.html")]
+pub struct RecoveryCodesTemplate {
+    pub codes: Vec<String>,
+}
+
+fn build_totp(secret: &str, email: String) -> Result<TOTP, AuthError> {
+    let secret = Secret::Encoded(secret.to_string())
+        .to_bytes()
+        .map_err(|e| anyhow::anyhow!("Invalid TOTP secret: {e:?}"))?;
+    TOTP::new(
+        Algorithm::SHA1,
+        TOTP_DIGITS,
+        TOTP_SKEW,
+        TOTP_STEP_SECONDS,
+        secret,
+        Some(TOTP_ISSUER.to_string()),
+        email,
+    )
+    .map_err(|e| anyhow::anyhow!("Invalid TOTP: {e}").into())
+}
+
+/// The time step of the code if it is valid at `time`, within the allowed skew
+fn matching_step(totp: &TOTP, code: &str, time: u64) -> Option<u64> {
+    let current = time / TOTP_STEP_SECONDS;
+    let skew = TOTP_SKEW as u64;
+    (current.saturating_sub(skew)..=current + skew)
+        .find(|step| totp.generate(step * TOTP_STEP_SECONDS) == code)
+}
+
+async fn query_user_totp(
+    pool: &PgPool,
+    user_id: uuid::Uuid,
+) -> Result<Option<UserTotp>, AuthError> {
+    Ok(sqlx::query_as!(
+        UserTotp,
+        "SELECT secret, confirmed_at FROM user_totp WHERE user_id = $1",
+        user_id
+    )
+    .fetch_optional(pool)
+    .await?)
+}
+
+/// Whether the user has to enter a code after the password
+pub async fn is_totp_enabled(pool: &PgPool, user_id: uuid::Uuid) -> Result<bool, AuthError> {
+    let totp = query_user_totp(pool, user_id).await?;
+    Ok(totp.is_some_and(|totp| totp.confirmed_at.is_some()))
+}
+
+/// Check a code of the authenticator app, a code is only accepted once
+async fn accept_totp_code(
+    pool: &PgPool,
+    user_id: uuid::Uuid,
+    totp: &UserTotp,
+    email: String,
+    code: &str,
+) -> Result<bool, AuthError> {
+    let totp = build_totp(&totp.secret, email)?;
+    let Some(step) = matching_step(&totp, code, Utc::now().timestamp() as u64) else {
+        return Ok(false);
+    };
+    let result = sqlx::query!(
+        "UPDATE user_totp SET last_used_step = $2
+        WHERE user_id = $1 AND (last_used_step IS NULL OR last_used_step < $2)",
+        user_id,
+        step as i64,
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() == 1)
+}
+
+fn generate_recovery_code() -> String {
+    let mut bytes = [0u8; 5];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let code: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+    format!("{}-{}", &code[..5], &code[5..])
+}
+
+// the dash and the case do not matter when the code is typed in
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .collect::<String>()
+        .to_lowercase()
+}
+
+/// Replace the recovery codes of the user, they are only shown to the user once
+async fn replace_recovery_codes(
+    conn: &mut PgConnection,
+    user_id: uuid::Uuid,
+) -> Result<Vec<String>, AuthError> {
+    sqlx::query!("DELETE FROM recovery_codes WHERE user_id = $1", user_id)
+        .execute(&mut *conn)
+        .await?;
+
+    let codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| generate_recovery_code())
+        .collect();
+    let hashes: Vec<String> = codes
+        .iter()
+        .map(|code| hash_token(&normalize_recovery_code(code)))
+        .collect();
+    sqlx::query!(
+        "INSERT INTO recovery_codes (user_id, code_hash)
+        SELECT $1, UNNEST($2::varchar[])",
+        user_id,
+        &hashes,
+    )
+    .execute(&mut *conn)
+    .await?;
+    Ok(codes)
+}
+
+async fn use_recovery_code(
+    pool: &PgPool,
+    user_id: uuid::Uuid,
+    code: &str,
+) -> Result<bool, AuthError> {
+    let result = sqlx::query!(
+        "UPDATE recovery_codes SET used_at = current_timestamp
+        WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL",
+        user_id,
+        hash_token(&normalize_recovery_code(code)),
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() == 1)
+}
+
+/// Check the second factor of a user with a confirmed TOTP,
+/// a code with the length of a TOTP code is checked as such, anything else as a recovery code
+async fn accept_second_factor(
+    pool: &PgPool,
+    user_id: uuid::Uuid,
+    email: String,
+    code: &str,
+) -> Result<bool, AuthError> {
+    let code = code.trim();
+    match query_user_totp(pool, user_id).await? {
+        Some(totp) if totp.confirmed_at.is_some() => {
+            if code.len() == TOTP_DIGITS && code.chars().all(|c| c.is_ascii_digit()) {
+                accept_totp_code(pool, user_id, &totp, email, code).await
+            } else {
+                use_recovery_code(pool, user_id, code).await
+            }
+        }
+        _ => Ok(false),
+    }
+}
+
+/// Issue the challenge that replaces the access token after the password is verified,
+/// the client is redirected to enter the code
+pub async fn add_mfa_challenge_cookie(
+    jar: CookieJar,
+    pool: &PgPool,
+    user_id: uuid::Uuid,
+) -> Result<CookieJar, AuthError> {
+    let mut conn = pool.acquire().await?;
+    let challenge = issue_user_token(
+        &mut conn,
+        user_id,
+        TokenPurpose::MfaChallenge,
+        chrono::Duration::minutes(MFA_CHALLENGE_TTL_MINUTES),
+    )
+    .await?;
+
+    let cookie = Cookie::build((MFA_CHALLENGE_COOKIE, challenge))
+        .http_only(true)
+        .secure(get_environment() != Environment::Local)
+        .same_site(SameSite::Strict)
+        .max_age(cookie::time::Duration::minutes(MFA_CHALLENGE_TTL_MINUTES))
+        .path(MFA_CHALLENGE_PATH)
+        .build();
+    Ok(jar.add(cookie))
+}
+
+/// The second login step, the access token is only issued after a valid code
+#[tracing::instrument(name = "Verifying second factor", skip(jar, pool, client, payload))]
+pub async fn verify_second_factor(
+    jar: CookieJar,
+    State(pool): State<PgPool>,
+    client: ClientInfo,
+    Form(payload): Form<TotpCode>,
+) -> Result<(CookieJar, HxRedirect, ()), AuthError> {
+    let challenge = jar
+        .get(MFA_CHALLENGE_COOKIE)
+        .ok_or(AuthError::MissingCredentials)?
+        .value()
+        .to_string();
+    let mut conn = pool.acquire().await?;
+    let user_id = find_user_token(&mut conn, &challenge, TokenPurpose::MfaChallenge).await?;
+    let user = query_user(&pool, user_id).await?;
+    if !user.is_active {
+        return Err(AuthError::InactiveUser);
+    }
+
+    // the codes are short, so they are throttled together with the password
+    let account = ThrottleKey::Account(user.email.clone());
+    let mut throttle_keys = vec![account.clone()];
+    if let Some(ip) = client.ip.clone() {
+        throttle_keys.push(ThrottleKey::Ip(ip));
+    }
+    let attempt = reserve_attempt(&pool, &throttle_keys).await?;
+
+    if !accept_second_factor(&pool, user_id, user.email.clone(), &payload.code).await? {
+        let event = login_failed(&user.email, "invalid_code").target(user.id);
+        emit(&pool, &client, event).await;
+        return Err(AuthError::InvalidCode);
+    }
+    attempt.release(&pool).await?;
+    consume_user_token(&mut conn, &challenge, TokenPurpose::MfaChallenge).await?;
+    clear_throttle(&pool, &account).await?;
+    record_login(&pool, &client, user.id, true).await?;
+
+    let jar = add_login_cookies(jar, &pool, user.id, user.role(), &client).await?;
+    let challenge_cookie = Cookie::build((MFA_CHALLENGE_COOKIE, ""))
+        .max_age(cookie::time::Duration::ZERO)
+        .path(MFA_CHALLENGE_PATH)
+        .build();
+    Ok((
+        jar.add(challenge_cookie),
+        HxRedirect("/me".parse().unwrap()),
+        (),
+    ))
+}
+
+/// Start the enrollment with a new secret, it is only required at login once it is confirmed
+#[tracing::instrument(name = "Enrolling TOTP", skip(pool, user), fields(email = %user.email))]
+pub async fn enroll_totp(
+    State(pool): State<PgPool>,
+    user: CurrentUser,
+) -> Result<TotpEnrollmentTemplate, AuthError> {
+    user.ensure_not_impersonated()?;
+    if is_totp_enabled(&pool, user.id).await? {
+        return Err(CommonError::ValidationError(
+            "Two-factor authentication is already enabled".to_string(),
+        )
+        .into());
+    }
+
+    let secret = Secret::generate_secret().to_encoded().to_string();
+    let totp = build_totp(&secret, user.email.clone())?;
+    sqlx::query!(
+        "INSERT INTO user_totp (user_id, secret) VALUES ($1, $2)
+        ON CONFLICT (user_id) DO UPDATE
+            SET secret = $2, last_used_step = NULL, created_at = current_timestamp",
+        user.id,
+        secret,
+    )
+    .execute(&pool)
+    .await?;
+
+    Ok(TotpEnrollmentTemplate {
+        secret,
+        otpauth_uri: totp.get_url(),
+    })
+}
+
+/// Confirm the enrollment with a first code, the recovery codes are shown once
+#[tracing::instrument(name = "Confirming TOTP", skip(pool, user, payload), fields(email = %user.email))]
+pub async fn confirm_totp(
+    State(pool): State<PgPool>,
+    user: CurrentUser,
+    Form(payload): Form<TotpCode>,
+) -> Result<RecoveryCodesTemplate, AuthError> {
+    user.ensure_not_impersonated()?;
+    let totp = match query_user_totp(&pool, user.id).await? {
+        Some(totp) if totp.confirmed_at.is_none() => totp,
+        _ => {
+            return Err(CommonError::ValidationError(
+                "Start the two-factor authentication setup first".to_string(),
+            )
+            .into())
+        }
+    };
+    if !accept_totp_code(
+        &pool,
+        user.id,
+        &totp,
+        user.email.clone(),
+        payload.code.trim(),
+    )
+    .await?
+    {
+        return Err(AuthError::InvalidCode);
+    }
+
+    let mut tx = pool.begin().await?;
+    sqlx::query!(
+        "UPDATE user_totp SET confirmed_at = current_timestamp WHERE user_id = $1",
+        user.id
+    )
+    .execute(&mut *tx)
+    .await?;
+    let codes = replace_recovery_codes(&mut tx, user.id).await?;
+    tx.commit().await?;
+
+    Ok(RecoveryCodesTemplate { codes })
+}
+
+async fn delete_totp(conn: &mut PgConnection, user_id: uuid::Uuid) -> Result<(), AuthError> {
+    sqlx::query!("DELETE FROM recovery_codes WHERE user_id = $1", user_id)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query!("DELETE FROM user_totp WHERE user_id = $1", user_id)
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+/// Turn the second factor off, which requires a valid code as well
+#[tracing::instrument(name = "Disabling TOTP", skip(pool, user, payload), fields(email = %user.email))]
+pub async fn disable_totp(
+    State(pool): State<PgPool>,
+    user: CurrentUser,
+    Form(payload): Form<TotpCode>,
+) -> Result<Html<&'static str>, AuthError> {
+    user.ensure_not_impersonated()?;
+    // a stolen session must not be a way around the login throttle to guess the codes
+    let account = ThrottleKey::Account(user.email.clone());
+    let attempt = reserve_attempt(&pool, std::slice::from_ref(&account)).await?;
+    if !accept_second_factor(&pool, user.id, user.email.clone(), &payload.code).await? {
+        return Err(AuthError::InvalidCode);
+    }
+    attempt.release(&pool).await?;
+    clear_throttle(&pool, &account).await?;
+    let mut tx = pool.begin().await?;
+    delete_totp(&mut tx, user.id).await?;
+    tx.commit().await?;
+    Ok(Html("<span>Two-factor authentication is disabled.</span>"))
+}
+
+pub async fn query_totp_status(
+    pool: &PgPool,
+    user_id: uuid::Uuid,
+) -> Result<TotpStatus, AuthError> {
+    let confirmed_at = query_user_totp(pool, user_id)
+        .await?
+        .and_then(|totp| totp.confirmed_at);
+    let recovery_codes_left = sqlx::query_scalar!(
+        r#"SELECT COUNT(*) as "count!" FROM recovery_codes WHERE user_id = $1 AND used_at IS NULL"#,
+        user_id
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(TotpStatus {
+        enabled: confirmed_at.is_some(),
+        confirmed_at,
+        recovery_codes_left,
+    })
+}
+
+pub async fn show_user_totp(
+    State(pool): State<PgPool>,
+    Path(user_id): Path<uuid::Uuid>,
+) -> Result<Json<TotpStatus>, AuthError> {
+    // 404 for an unknown user instead of a disabled status
+    query_user(&pool, user_id).await?;
+    Ok(Json(query_totp_status(&pool, user_id).await?))
+}
+
+/// Let an admin remove the second factor of a user who lost both the app and the recovery codes
+#[tracing::instrument(name = "Resetting TOTP", skip(pool, user, client), fields(admin = %user.email))]
+pub async fn reset_user_totp(
+    State(pool): State<PgPool>,
+    user: CurrentUser,
+    client: ClientInfo,
+    Path(user_id): Path<uuid::Uuid>,
+) -> Result<StatusCode, AuthError> {
+    query_user(&pool, user_id).await?;
+    let mut tx = pool.begin().await?;
+    delete_totp(&mut tx, user_id).await?;
+    tx.commit().await?;
+    let event = AuditEvent::new(AuditAction::UserTotpReset)
+        .actor(user.id)
+        .target(user_id);
+    emit(&pool, &client, event).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::db::postgres::get_test_postgres_pool;
+    use crate::user_mgmt::auth::get_current_user_from_id;
+    use crate::user_mgmt::entity::CreateUser;
+    use crate::user_mgmt::handler::insert_user;
+
+    #[test]
+    fn check_recovery_code_format() {
+        let code = generate_recovery_code();
+        assert_eq!(code.len(), 11);
+        assert_eq!(normalize_recovery_code(&code.to_uppercase()).len(), 10);
+        assert_eq!(
+            normalize_recovery_code(" ABCDE-12345 "),
+            normalize_recovery_code("abcde12345")
+        );
+    }
+
+    #[test]
+    fn check_code_skew() {
+        let secret = Secret::generate_secret().to_encoded().to_string();
+        let totp = build_totp(&secret, "test@example.com".to_string()).unwrap();
+        let time = 1_000_000 * TOTP_STEP_SECONDS;
+        let previous = totp.generate(time - TOTP_STEP_SECONDS);
+        assert_eq!(matching_step(&totp, &previous, time), Some(999_999));
+        let too_old = totp.generate(time - 2 * TOTP_STEP_SECONDS);
+        assert_eq!(matching_step(&totp, &too_old, time), None);
+    }
+
+    #[tokio::test]
+    async fn second_factor_codes_are_single_use() {
+        let pool = &get_test_postgres_pool();
+        let mut conn = pool.acquire().await.unwrap();
+        let email = format!("{}@example.com", uuid::Uuid::new_v4());
+        let user = CreateUser {
+            email: email.clone(),
+            password: "password".to_string(),
+            name: "Totp".to_string(),
+        };
+        let user_id = insert_user(&mut conn, user).await.unwrap();
+
+        let secret = Secret::generate_secret().to_encoded().to_string();
+        sqlx::query!(
+            "INSERT INTO user_totp (user_id, secret, confirmed_at) VALUES ($1, $2, current_timestamp)",
+            user_id,
+            secret,
+        )
+        .execute(pool)
+        .await
+        .unwrap();
+        assert!(is_totp_enabled(pool, user_id).await.unwrap());
+
+        let code = build_totp(&secret, email.clone())
+            .unwrap()
+            .generate_current()
+            .unwrap();
+        assert!(accept_second_factor(pool, user_id, email.clone(), &code)
+            .await
+            .unwrap());
+        assert!(!accept_second_factor(pool, user_id, email.clone(), &code)
+            .await
+            .unwrap());
+
+        let codes = replace_recovery_codes(&mut conn, user_id).await.unwrap();
+        assert!(
+            accept_second_factor(pool, user_id, email.clone(), &codes[0])
+                .await
+                .unwrap()
+        );
+        assert!(
+            !accept_second_factor(pool, user_id, email.clone(), &codes[0])
+                .await
+                .unwrap()
+        );
+        let status = query_totp_status(pool, user_id).await.unwrap();
+        assert_eq!(status.recovery_codes_left, RECOVERY_CODE_COUNT as i64 - 1);
+    }
+
+    #[tokio::test]
+    async fn disabling_is_throttled_like_the_login() {
+        let pool = get_test_postgres_pool();
+        let mut conn = pool.acquire().await.unwrap();
+        let user = CreateUser {
+            email: format!("{}@example.com", uuid::Uuid::new_v4()),
+            password: "password".to_string(),
+            name: "Totp".to_string(),
+        };
+        let user_id = insert_user(&mut conn, user).await.unwrap();
+        let secret = Secret::generate_secret().to_encoded().to_string();
+        sqlx::query!(
+            "INSERT INTO user_totp (user_id, secret, confirmed_at) VALUES ($1, $2, current_timestamp)",
+            user_id,
+            secret,
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let disable = |code: &str| {
+            let pool = pool.clone();
+            let code = TotpCode {
+                code: code.to_string(),
+            };
+            async move {
+                let user = get_current_user_from_id(&pool, &user_id).await;
+                disable_totp(State(pool), user, Form(code)).await
+            }
+        };
+        assert!(matches!(
+            disable("000000").await,
+            Err(AuthError::InvalidCode)
+        ));
+        assert!(matches!(
+            disable("000000").await,
+            Err(AuthError::TooManyAttempts { .. })
+        ));
+        assert!(is_totp_enabled(&pool, user_id).await.unwrap());
+    }
+}
@@ -0,0 +1,102 @@
+use super::email::verification_email;
+use super::error::AuthError;
+use super::token::{consume_user_token, issue_user_token, TokenPurpose};
+use crate::common::mail::SharedMailer;
+use axum::extract::State;
+use axum::response::Html;
+use axum::Form;
+use chrono::Duration;
+use serde::Deserialize;
+use sqlx::{PgConnection, PgPool};
+
+const VERIFICATION_TOKEN_TTL_HOURS: i64 = 24;
+
+/// Issue a verification token, the email is sent by the caller
+/// so that the token can be created in the same transaction as the user
+pub async fn issue_verification_token(
+    conn: &mut PgConnection,
+    user_id: uuid::Uuid,
+) -> Result<String, AuthError> {
+    issue_user_token(
+        conn,
+        user_id,
+        TokenPurpose::EmailVerification,
+        Duration::hours(VERIFICATION_TOKEN_TTL_HOURS),
+    )
+    .await
+}
+
+/// The account is still created if the email cannot be sent, the user can ask for a new one
+pub async fn send_verification_email(mailer: &SharedMailer, email: String, token: &str) {
+    let _ = mailer
+        .send(verification_email(email, token))
+        .await
+        .inspect_err(|e| tracing::error!("Failed to send verification email: {e}"));
+}
+
+pub async fn verify_email(pool: &PgPool, token: &str) -> Result<(), AuthError> {
+    let mut tx = pool.begin().await?;
+    let user_id = consume_user_token(&mut tx, token, TokenPurpose::EmailVerification).await?;
+    sqlx::query!("UPDATE users SET is_verified = true WHERE id = $1", user_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct ResendVerification {
+    pub email: String,
+}
+
+/// The response is the same whether the email exists or not,
+/// so that it cannot be used to find out who has an account
+#[tracing::instrument(name = "Resending verification email", skip(pool, mailer, payload), fields(email = %payload.email))]
+pub async fn resend_verification(
+    State(pool): State<PgPool>,
+    State(mailer): State<SharedMailer>,
+    Form(payload): Form<ResendVerification>,
+) -> Result<Html<&'static str>, AuthError> {
+    let user_id = sqlx::query_scalar!(
+        "SELECT id FROM users WHERE email = $1 AND is_verified = false",
+        payload.email
+    )
+    .fetch_optional(&pool)
+    .await?;
+
+    if let Some(user_id) = user_id {
+        let mut conn = pool.acquire().await?;
+        let token = issue_verification_token(&mut conn, user_id).await?;
+        send_verification_email(&mailer, payload.email, &token).await;
+    }
+
+    Ok(Html(
+        "<span>If the account exists and is not verified yet, a new link has been sent to its email.</span>",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::db::postgres::get_test_postgres_pool;
+    use crate::user_mgmt::entity::CreateUser;
+    use crate::user_mgmt::handler::{insert_user, query_user};
+
+    #[tokio::test]
+    async fn verification_token_verifies_user() {
+        let pool = &get_test_postgres_pool();
+        let mut conn = pool.acquire().await.unwrap();
+        let user = CreateUser {
+            email: format!("{}@example.com", uuid::Uuid::new_v4()),
+            password: "password".to_string(),
+            name: "Verify".to_string(),
+        };
+        let user_id = insert_user(&mut conn, user).await.unwrap();
+        let token = issue_verification_token(&mut conn, user_id).await.unwrap();
+
+        verify_email(pool, &token).await.unwrap();
+        assert!(query_user(pool, user_id).await.unwrap().is_verified);
+        // single-use
+        assert!(verify_email(pool, &token).await.is_err());
+    }
+}